@@ -0,0 +1,45 @@
+use super::{make_chunk, ChunkConfig, TextChunk};
+
+/// Split `chars[from..to]` into windows of at most `chunk_size` characters.
+/// Windows are cut at whitespace when possible so words are not torn apart.
+pub fn split(chars: &[char], from: usize, to: usize, config: &ChunkConfig) -> Vec<TextChunk> {
+    let mut chunks = vec![];
+    // How far back we are willing to look for a word boundary
+    let lookback = config.chunk_size / 5;
+
+    let mut start = from;
+    while start < to {
+        let mut end = std::cmp::min(start + config.chunk_size, to);
+        if end < to {
+            let min_cut = end - lookback;
+            let mut cut = end;
+            while cut > min_cut && !chars[cut].is_whitespace() {
+                cut -= 1;
+            }
+            if cut > min_cut {
+                end = cut;
+            }
+        }
+
+        if let Some(chunk) = make_chunk(chars, start, end) {
+            chunks.push(chunk);
+        }
+        if end >= to {
+            break;
+        }
+
+        // Step back by the overlap, then to the start of the word we landed in
+        let mut next = end.saturating_sub(config.chunk_overlap);
+        let overlap_start = next;
+        let min_next = next.saturating_sub(lookback).max(start + 1);
+        while next > min_next && !chars[next - 1].is_whitespace() {
+            next -= 1;
+        }
+        if next > 0 && !chars[next - 1].is_whitespace() {
+            next = overlap_start;
+        }
+        start = if next > start { next } else { end };
+    }
+
+    chunks
+}
@@ -0,0 +1,436 @@
+pub mod fixed_size;
+pub mod page;
+pub mod sentence;
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use crate::extractor::{CodeBlock, ExtractedText, Heading, Record, Row, PAGE_BREAK};
+use crate::vdb::error::Error;
+
+/// How a document's text is split into passages before embedding
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum ChunkStrategy {
+    /// Windows of `chunk_size` characters, overlapping by `chunk_overlap`
+    FixedSize,
+    /// Packs whole sentences and paragraphs up to `chunk_size` characters
+    Sentence,
+    /// One passage per PDF page, split further only when a page is too long
+    Page,
+}
+
+/// Per collection chunking settings, sizes are counted in characters
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChunkConfig {
+    pub strategy: ChunkStrategy,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    /// Dotted paths of the fields embedded as the text of JSON records, the
+    /// other fields are kept as metadata. Every string field when empty.
+    #[serde(default)]
+    pub text_paths: Vec<String>,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            strategy: ChunkStrategy::Sentence,
+            chunk_size: 1000,
+            chunk_overlap: 200,
+            text_paths: vec![],
+        }
+    }
+}
+
+impl ChunkConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.chunk_size == 0 || self.chunk_overlap >= self.chunk_size {
+            return Err(Error::InvalidInput);
+        }
+        if self.text_paths.iter().any(|path| path.trim().is_empty()) {
+            return Err(Error::InvalidInput);
+        }
+        Ok(())
+    }
+}
+
+/// A passage of a document together with its character range in the source text
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextChunk {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+    // 1-based page the passage starts on, `None` when the text has no page breaks
+    pub page: Option<u32>,
+    // Titles of the headings the passage starts under, outermost first
+    pub section: Vec<String>,
+    // Sheet and first and last row of a passage of tabular data
+    pub sheet: Option<String>,
+    pub rows: Option<(u32, u32)>,
+    // Fields of the structured record the passage comes from, as key and value
+    pub fields: Vec<(String, String)>,
+    // When that record was written, in milliseconds since the epoch
+    pub date: Option<u64>,
+    // Language, symbol and first and last line of a passage of source code
+    pub language: Option<String>,
+    pub symbol: Option<String>,
+    pub lines: Option<(u32, u32)>,
+}
+
+/// Split an extracted document into passages, each one labelled with the
+/// section it starts in. Tabular documents are split between rows instead,
+/// whatever the strategy, so every passage holds whole rows of one sheet,
+/// structured data gets a passage per record and source code is split
+/// between its functions and classes.
+pub fn chunk_document(document: &ExtractedText, config: &ChunkConfig) -> Result<Vec<TextChunk>, Error> {
+    if !document.rows.is_empty() {
+        config.validate()?;
+        return Ok(chunk_rows(&document.text, &document.rows, config));
+    }
+    if !document.records.is_empty() {
+        config.validate()?;
+        return Ok(chunk_records(&document.text, &document.records, config));
+    }
+    if !document.blocks.is_empty() {
+        config.validate()?;
+        return Ok(chunk_code(&document.text, &document.blocks, document.language.as_deref(), config));
+    }
+
+    let mut chunks = chunk_text(&document.text, config)?;
+    for chunk in &mut chunks {
+        chunk.section = section_path(&document.headings, chunk.start);
+    }
+    Ok(chunks)
+}
+
+// Titles of the headings enclosing character `at`, a heading closes every
+// deeper or equally deep one before it
+fn section_path(headings: &[Heading], at: usize) -> Vec<String> {
+    let mut path: Vec<&Heading> = vec![];
+    for heading in headings.iter().take_while(|heading| heading.start <= at) {
+        while path.last().is_some_and(|last| last.level >= heading.level) {
+            path.pop();
+        }
+        path.push(heading);
+    }
+    path.into_iter().map(|heading| heading.title.clone()).collect()
+}
+
+// Consecutive rows of a sheet packed up to `chunk_size` characters, a row
+// longer than that is split on its own
+fn chunk_rows(text: &str, rows: &[Row], config: &ChunkConfig) -> Vec<TextChunk> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = vec![];
+    let mut group: Vec<&Row> = vec![];
+    for row in rows {
+        let fits = group.first().is_some_and(|first| first.sheet == row.sheet && row.end - first.start <= config.chunk_size);
+        if !fits {
+            chunks.extend(chunk_group(&chars, &group, config));
+            group.clear();
+        }
+        group.push(row);
+    }
+    chunks.extend(chunk_group(&chars, &group, config));
+    chunks
+}
+
+fn chunk_group(chars: &[char], group: &[&Row], config: &ChunkConfig) -> Vec<TextChunk> {
+    let (Some(first), Some(last)) = (group.first(), group.last()) else {
+        return vec![];
+    };
+    let mut chunks = split_span(chars, first.start, last.end, config);
+    for chunk in &mut chunks {
+        chunk.sheet = first.sheet.clone();
+        chunk.rows = Some((first.number, last.number));
+    }
+    chunks
+}
+
+// One passage per record, or several for a record longer than `chunk_size`,
+// each carrying the record's number, fields and date
+fn chunk_records(text: &str, records: &[Record], config: &ChunkConfig) -> Vec<TextChunk> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = vec![];
+    for record in records {
+        for mut chunk in split_span(&chars, record.start, record.end, config) {
+            chunk.rows = Some((record.number, record.number));
+            chunk.fields = record.fields.clone();
+            chunk.date = record.date;
+            chunks.push(chunk);
+        }
+    }
+    chunks
+}
+
+// Consecutive blocks of a top-level item packed up to `chunk_size`
+// characters, so a small class stays whole and a large one is split between
+// its members. A passage is named after its block, or the item when it holds
+// several, and numbered by the lines it covers.
+fn chunk_code(text: &str, blocks: &[CodeBlock], language: Option<&str>, config: &ChunkConfig) -> Vec<TextChunk> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = vec![];
+    let mut group: Vec<&CodeBlock> = vec![];
+    for block in blocks {
+        let fits = group.first().is_some_and(|first| first.item == block.item && block.end - first.start <= config.chunk_size);
+        if !fits {
+            chunks.extend(code_group(&chars, &group, config));
+            group.clear();
+        }
+        group.push(block);
+    }
+    chunks.extend(code_group(&chars, &group, config));
+
+    let newlines: Vec<usize> = (0..chars.len()).filter(|&i| chars[i] == '\n').collect();
+    let line = |at: usize| newlines.partition_point(|&n| n < at) as u32 + 1;
+    for chunk in &mut chunks {
+        chunk.language = language.map(str::to_string);
+        chunk.lines = Some((line(chunk.start), line(chunk.end - 1)));
+    }
+    chunks
+}
+
+fn code_group(chars: &[char], group: &[&CodeBlock], config: &ChunkConfig) -> Vec<TextChunk> {
+    let (Some(first), Some(last)) = (group.first(), group.last()) else {
+        return vec![];
+    };
+    let symbol = match group.iter().all(|block| block.symbol == first.symbol) {
+        true => first.symbol.clone(),
+        false => first.item.clone(),
+    };
+    let mut chunks = split_span(chars, first.start, last.end, config);
+    for chunk in &mut chunks {
+        chunk.symbol = symbol.clone();
+    }
+    chunks
+}
+
+// `chars[start..end]` as one passage if it fits, in fixed size windows otherwise
+fn split_span(chars: &[char], start: usize, end: usize, config: &ChunkConfig) -> Vec<TextChunk> {
+    match end - start > config.chunk_size {
+        true => fixed_size::split(chars, start, end, config),
+        false => make_chunk(chars, start, end).into_iter().collect(),
+    }
+}
+
+/// Split extracted document text into passages according to the collection config
+pub fn chunk_text(text: &str, config: &ChunkConfig) -> Result<Vec<TextChunk>, Error> {
+    config.validate()?;
+
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = match config.strategy {
+        ChunkStrategy::FixedSize => fixed_size::split(&chars, 0, chars.len(), config),
+        ChunkStrategy::Sentence => sentence::split(&chars, 0, chars.len(), config),
+        ChunkStrategy::Page => page::split(&chars, config),
+    };
+
+    // Paged text, number every chunk by the page it starts on
+    let breaks: Vec<usize> = (0..chars.len()).filter(|&i| chars[i] == PAGE_BREAK).collect();
+    if !breaks.is_empty() {
+        for chunk in &mut chunks {
+            chunk.page = Some(breaks.partition_point(|&b| b < chunk.start) as u32 + 1);
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Build a chunk from `chars[start..end]`, trimming surrounding whitespace.
+/// Returns `None` when nothing but whitespace is left.
+pub(crate) fn make_chunk(chars: &[char], start: usize, end: usize) -> Option<TextChunk> {
+    let mut start = start;
+    let mut end = end;
+    while start < end && chars[start].is_whitespace() {
+        start += 1;
+    }
+    while end > start && chars[end - 1].is_whitespace() {
+        end -= 1;
+    }
+    if start == end {
+        return None;
+    }
+
+    Some(TextChunk {
+        text: chars[start..end].iter().collect(),
+        start,
+        end,
+        page: None,
+        section: vec![],
+        sheet: None,
+        rows: None,
+        language: None,
+        symbol: None,
+        lines: None,
+        fields: vec![],
+        date: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_document, chunk_text, ChunkConfig, ChunkStrategy};
+    use crate::extractor::code_file::{extract_text_from_code, Language};
+    use crate::extractor::csv_file::extract_text_from_csv;
+    use crate::extractor::json_file::extract_text_from_jsonl;
+    use crate::extractor::markdown_file::extract_text_from_markdown;
+    use crate::extractor::PAGE_BREAK;
+    use crate::vdb::error::Error;
+
+    fn config(strategy: ChunkStrategy, chunk_size: usize, chunk_overlap: usize) -> ChunkConfig {
+        ChunkConfig {
+            strategy,
+            chunk_size,
+            chunk_overlap,
+            text_paths: vec![],
+        }
+    }
+
+    #[test]
+    fn fixed_size_respects_size_and_overlap() {
+        let text = "one two three four five six seven eight nine ten eleven twelve";
+        let chunks = chunk_text(text, &config(ChunkStrategy::FixedSize, 20, 5)).unwrap();
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.text.chars().count() <= 20);
+            assert_eq!(chunk.text, text.chars().skip(chunk.start).take(chunk.end - chunk.start).collect::<String>());
+        }
+        // Consecutive chunks share some text
+        for pair in chunks.windows(2) {
+            assert!(pair[1].start < pair[0].end);
+        }
+        assert_eq!(chunks.last().unwrap().end, text.chars().count());
+    }
+
+    #[test]
+    fn sentence_keeps_sentences_whole() {
+        let text = "The first sentence is here. The second one follows! Is this the third?\n\nA new paragraph starts.";
+        let chunks = chunk_text(text, &config(ChunkStrategy::Sentence, 60, 0)).unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].text, "The first sentence is here. The second one follows!");
+        assert_eq!(chunks[1].text, "Is this the third?\n\nA new paragraph starts.");
+    }
+
+    #[test]
+    fn sentence_overlap_repeats_trailing_sentence() {
+        let text = "Alpha beta gamma. Delta epsilon zeta. Eta theta iota.";
+        let chunks = chunk_text(text, &config(ChunkStrategy::Sentence, 40, 20)).unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].text, "Alpha beta gamma. Delta epsilon zeta.");
+        assert_eq!(chunks[1].text, "Delta epsilon zeta. Eta theta iota.");
+    }
+
+    #[test]
+    fn sentence_splits_oversized_sentence() {
+        let text = "a".repeat(250);
+        let chunks = chunk_text(&text, &config(ChunkStrategy::Sentence, 100, 0)).unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|c| c.text.len() <= 100));
+    }
+
+    #[test]
+    fn page_mode_splits_on_page_breaks() {
+        let text = format!("First page text.{}Second page text.{}{}Fourth page.", PAGE_BREAK, PAGE_BREAK, PAGE_BREAK);
+        let chunks = chunk_text(&text, &config(ChunkStrategy::Page, 1000, 100)).unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].text, "First page text.");
+        assert_eq!(chunks[1].text, "Second page text.");
+        assert_eq!(chunks[2].text, "Fourth page.");
+        assert_eq!(chunks.iter().map(|c| c.page).collect::<Vec<_>>(), vec![Some(1), Some(2), Some(4)]);
+    }
+
+    #[test]
+    fn chunks_are_numbered_by_starting_page() {
+        let text = format!("Alpha beta gamma. Delta epsilon.{}Zeta eta theta.{}", PAGE_BREAK, PAGE_BREAK);
+        let chunks = chunk_text(&text, &config(ChunkStrategy::Sentence, 20, 0)).unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.iter().map(|c| c.page).collect::<Vec<_>>(), vec![Some(1), Some(1), Some(2)]);
+
+        // Plain text has no pages
+        let chunks = chunk_text("Alpha beta gamma.", &config(ChunkStrategy::Sentence, 20, 0)).unwrap();
+        assert_eq!(chunks[0].page, None);
+    }
+
+    #[test]
+    fn chunks_carry_their_section_path() {
+        let markdown = "Preface text.\n\n# Guide\n\n## Install\n\nRun the installer.\n\n### Linux\n\nUse the package.\n\n## Usage\n\nStart it.";
+        let document = extract_text_from_markdown(markdown.as_bytes()).unwrap();
+        let chunks = chunk_document(&document, &config(ChunkStrategy::Sentence, 30, 0)).unwrap();
+
+        let sections: Vec<(&str, Vec<String>)> = chunks.iter().map(|c| (c.text.as_str(), c.section.clone())).collect();
+        let path = |titles: &[&str]| titles.iter().map(|t| t.to_string()).collect::<Vec<String>>();
+        assert_eq!(
+            sections,
+            vec![
+                ("Preface text.\n\n# Guide", path(&[])),
+                ("## Install", path(&["Guide", "Install"])),
+                ("Run the installer.", path(&["Guide", "Install"])),
+                ("### Linux\n\nUse the package.", path(&["Guide", "Install", "Linux"])),
+                ("## Usage\n\nStart it.", path(&["Guide", "Usage"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn tabular_chunks_hold_whole_rows() {
+        let csv = format!("Account,Note\n4000,Rent\n4100,Travel\n\n4200,Office\n4300,{}\n", "x".repeat(60));
+        let document = extract_text_from_csv(csv.as_bytes()).unwrap();
+        let chunks = chunk_document(&document, &config(ChunkStrategy::Sentence, 60, 10)).unwrap();
+
+        let rows: Vec<(&str, Option<(u32, u32)>)> = chunks.iter().map(|c| (c.text.as_str(), c.rows)).collect();
+        assert_eq!(rows[0], ("Account: 4000\nNote: Rent\n\nAccount: 4100\nNote: Travel", Some((2, 3))));
+        assert_eq!(rows[1], ("Account: 4200\nNote: Office", Some((5, 5))));
+        // The long row is split but keeps its number
+        assert!(rows.len() > 3);
+        assert!(rows[2..].iter().all(|(text, rows)| text.chars().count() <= 60 && *rows == Some((6, 6))));
+        assert!(chunks.iter().all(|c| c.sheet.is_none() && c.section.is_empty()));
+    }
+
+    #[test]
+    fn records_are_chunked_one_by_one() {
+        let jsonl = format!("{{\"q\": \"Pay?\", \"votes\": 2}}\n{{\"q\": \"Ship?\"}}\n{{\"q\": \"{}\", \"id\": 3}}", "x".repeat(40));
+        let document = extract_text_from_jsonl(jsonl.as_bytes(), &[]).unwrap();
+        let chunks = chunk_document(&document, &config(ChunkStrategy::Sentence, 30, 5)).unwrap();
+
+        assert_eq!(chunks[0].text, "q: Pay?");
+        assert_eq!(chunks[0].fields, vec![("votes".to_string(), "2".to_string())]);
+        assert_eq!((chunks[1].text.as_str(), chunks[1].rows), ("q: Ship?", Some((2, 2))));
+        assert!(chunks[1].fields.is_empty());
+        // The long record is split, every part keeps its fields
+        assert!(chunks.len() > 3);
+        assert!(chunks[2..].iter().all(|c| c.rows == Some((3, 3)) && c.fields == vec![("id".to_string(), "3".to_string())]));
+    }
+
+    #[test]
+    fn code_is_chunked_between_items() {
+        let rust = format!(
+            "use std::io;\n\nstruct Point {{\n    x: i32,\n}}\n\nimpl Point {{\n    fn new() -> Self {{\n        Point {{ x: 0 }}\n    }}\n\n    fn long(&self) {{\n        // {}\n    }}\n}}\n",
+            "x".repeat(62)
+        );
+        let document = extract_text_from_code(rust.as_bytes(), Language::Rust).unwrap();
+        let chunks = chunk_document(&document, &config(ChunkStrategy::Sentence, 100, 10)).unwrap();
+
+        let symbols: Vec<Option<&str>> = chunks.iter().map(|c| c.symbol.as_deref()).collect();
+        assert_eq!(symbols, vec![None, Some("Point"), Some("Point"), Some("Point::long"), Some("Point")]);
+        assert_eq!((chunks[0].text.as_str(), chunks[0].lines), ("use std::io;", Some((1, 1))));
+        assert_eq!(chunks[1].lines, Some((3, 5)));
+        // The impl is too long for one passage, so it's split between its methods
+        assert_eq!(chunks[2].text, "impl Point {\n    fn new() -> Self {\n        Point { x: 0 }\n    }");
+        assert_eq!(chunks[2].lines, Some((7, 10)));
+        assert_eq!(chunks[3].lines, Some((12, 14)));
+        assert_eq!((chunks[4].text.as_str(), chunks[4].lines), ("}", Some((15, 15))));
+        assert!(chunks.iter().all(|c| c.language.as_deref() == Some("rust")));
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let result = chunk_text("text", &config(ChunkStrategy::FixedSize, 10, 10));
+        assert_eq!(result, Err(Error::InvalidInput));
+
+        let blank_path = ChunkConfig { text_paths: vec![" ".to_string()], ..Default::default() };
+        assert_eq!(blank_path.validate(), Err(Error::InvalidInput));
+    }
+}
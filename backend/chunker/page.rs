@@ -0,0 +1,23 @@
+use super::{make_chunk, sentence, ChunkConfig, TextChunk};
+use crate::extractor::PAGE_BREAK;
+
+/// One chunk per page, pages are separated by `PAGE_BREAK`.
+/// Pages longer than `chunk_size` are split on sentences.
+pub fn split(chars: &[char], config: &ChunkConfig) -> Vec<TextChunk> {
+    let mut chunks = vec![];
+
+    let mut start = 0;
+    for end in 0..=chars.len() {
+        if end < chars.len() && chars[end] != PAGE_BREAK {
+            continue;
+        }
+        if end - start <= config.chunk_size {
+            chunks.extend(make_chunk(chars, start, end));
+        } else {
+            chunks.extend(sentence::split(chars, start, end, config));
+        }
+        start = end + 1;
+    }
+
+    chunks
+}
@@ -0,0 +1,79 @@
+use super::{fixed_size, make_chunk, ChunkConfig, TextChunk};
+use crate::extractor::PAGE_BREAK;
+
+/// Split `chars[from..to]` into sentence and paragraph units, returned as ranges.
+/// Whitespace-only units are dropped.
+fn units(chars: &[char], from: usize, to: usize) -> Vec<(usize, usize)> {
+    let mut units = vec![];
+    let mut start = from;
+
+    for i in from..to {
+        let c = chars[i];
+        let boundary = match c {
+            '.' | '!' | '?' => i + 1 == to || chars[i + 1].is_whitespace(),
+            '\n' => i + 1 < to && chars[i + 1] == '\n',
+            PAGE_BREAK => true,
+            _ => false,
+        };
+        if boundary {
+            units.push((start, i + 1));
+            start = i + 1;
+        }
+    }
+    if start < to {
+        units.push((start, to));
+    }
+
+    units
+        .into_iter()
+        .filter(|(s, e)| chars[*s..*e].iter().any(|c| !c.is_whitespace()))
+        .collect()
+}
+
+/// Pack whole sentences into chunks of at most `chunk_size` characters.
+/// Trailing sentences of a chunk are repeated in the next one up to `chunk_overlap`
+/// characters, sentences longer than a chunk fall back to fixed size windows.
+pub fn split(chars: &[char], from: usize, to: usize, config: &ChunkConfig) -> Vec<TextChunk> {
+    let mut chunks = vec![];
+    let mut current: Vec<(usize, usize)> = vec![];
+
+    for unit in units(chars, from, to) {
+        if unit.1 - unit.0 > config.chunk_size {
+            flush(chars, &current, &mut chunks);
+            current.clear();
+            chunks.extend(fixed_size::split(chars, unit.0, unit.1, config));
+            continue;
+        }
+
+        if !current.is_empty() && unit.1 - current[0].0 > config.chunk_size {
+            flush(chars, &current, &mut chunks);
+
+            // Keep the trailing sentences that fit in the overlap
+            let mut keep = 0;
+            let mut overlap = 0;
+            for (s, e) in current.iter().rev() {
+                if overlap + (e - s) > config.chunk_overlap {
+                    break;
+                }
+                overlap += e - s;
+                keep += 1;
+            }
+            current.drain(..current.len() - keep);
+            while !current.is_empty() && unit.1 - current[0].0 > config.chunk_size {
+                current.remove(0);
+            }
+        }
+        current.push(unit);
+    }
+    flush(chars, &current, &mut chunks);
+
+    chunks
+}
+
+fn flush(chars: &[char], units: &[(usize, usize)], chunks: &mut Vec<TextChunk>) {
+    if let (Some(first), Some(last)) = (units.first(), units.last()) {
+        if let Some(chunk) = make_chunk(chars, first.0, last.1) {
+            chunks.push(chunk);
+        }
+    }
+}
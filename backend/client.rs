@@ -7,10 +7,20 @@ use ic_cdk::{
     },
     id,
 };
-use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use std::str;
+use crate::extractor::code_file::{extract_text_from_code, Language};
+use crate::extractor::csv_file::{extract_text_from_csv, extract_text_from_tsv};
+use crate::extractor::docx_file::extract_text_from_docx;
+use crate::extractor::email_file::{extract_text_from_eml, extract_text_from_mbox};
+use crate::extractor::html_file::extract_text_from_html;
+use crate::extractor::json_file::{extract_text_from_json, extract_text_from_jsonl};
+use crate::extractor::markdown_file::extract_text_from_markdown;
+use crate::extractor::odt_file::extract_text_from_odt;
+use crate::extractor::{extract_text_from_document, ExtractedText};
 use crate::extractor::pdf_file::extract_text_from_pdf;
+use crate::extractor::rtf_file::extract_text_from_rtf;
+use crate::extractor::xlsx_file::extract_text_from_xlsx;
 use crate::vdb::error::Error;
 
 /// Used to build a request to the Management Canister's `http_request` method.
@@ -32,7 +42,7 @@ pub(crate) fn is_ipv4_support_available() -> bool {
     support
 }
 
-async fn generate_icp_uuid() -> String {
+pub(crate) async fn generate_icp_uuid() -> String {
     let (random_bytes,): (Vec<u8>,) = raw_rand().await.expect("Failed dapetin randomness dari ICP");
     let uuid_bytes: [u8; 16] = random_bytes[..16].try_into().expect("Slice gagal");
     hex::encode(uuid_bytes)
@@ -58,6 +68,7 @@ impl CanisterHttpRequest {
     }
 
     /// A simple wrapper to assign the URL with the `GET` method.
+    #[allow(dead_code)]
     pub fn get(self, url: &str) -> Self {
         self.url(url).method(HttpMethod::POST)
     }
@@ -125,103 +136,102 @@ impl CanisterHttpRequest {
 }
 
 
-/// OpenAI API request structure for text embedding
-#[derive(Serialize)]
-struct OpenAIEmbeddingRequest {
-    model: String,
-    input: String,
-}
-
-/// OpenAI API response structure for embeddings
-#[derive(Deserialize)]
-struct OpenAIEmbeddingResponse {
-    data: Vec<EmbeddingData>,
-}
-
-#[derive(Deserialize)]
-struct EmbeddingData {
-    embedding: Vec<f32>,
-}
-
-/// Generates embeddings from text content using OpenAI's API
-pub async fn generate_embeddings(text: &str, api_key: &str) -> Result<Vec<f32>, String> {
-    is_ipv4_support_available();
-    // Prepare the request body
-    let request_body = OpenAIEmbeddingRequest {
-        model: "text-embedding-3-small".to_string(),
-        input: text.to_string(),
-    };
-
-    let body_json = match serde_json::to_vec(&request_body) {
-        Ok(json) => json,
-        Err(e) => return Err(format!("Failed to serialize request: {}", e)),
-    };
-
-    let ikey = generate_icp_uuid().await;
-    ic_cdk::println!("idempotency_key: {}", ikey.clone().to_string());
-
-    let context = ikey.clone();
-
-    // Create HTTP request
-    let response = CanisterHttpRequest::new()
-        .url("https://openai.ariwira.me/v1/embeddings")
-        .method(HttpMethod::POST)
-        .add_headers(vec![
-            ("Content-Type".to_string(), "application/json".to_string()),
-            ("Authorization".to_string(), format!("Bearer {}", api_key)),
-            ("Idempotency-Key".to_string(), ikey.to_string()),
-        ])
-        .max_response_bytes(1 * 1024 * 1024) // 1MB max response
-        .cycles(30_956_296_000)// Adjust cycles as needed
-        .payload(Some(body_json))
-        .transform_context("transform_exchange_http_response", context.as_bytes().to_vec())
-        .send()
-        .await?;
-
-    // Process the response
-    if response.status != 200_u16 {
-        return Err(format!(
-            "OpenAI API error: Status {}, {}",
-            response.status,
-            str::from_utf8(&response.body).unwrap_or("Invalid UTF-8 response")
-        ));
-    }
-
-    // Parse the response
-    let response_text = match str::from_utf8(&response.body) {
-        Ok(text) => text,
-        Err(e) => return Err(format!("Failed to decode response: {}", e)),
-    };
-
-    let embedding_response: OpenAIEmbeddingResponse = match serde_json::from_str(response_text) {
-        Ok(resp) => resp,
-        Err(e) => return Err(format!("Failed to parse API response: {}", e)),
-    };
-
-    // Extract the embedding vector from the first result
-    if embedding_response.data.is_empty() {
-        return Err("No embeddings returned from API".to_string());
-    }
-
-    Ok(embedding_response.data[0].embedding.clone())
-}
-
-/// Extract text content from ByteBuf based on file type
-pub fn extract_text_from_bytebuf(data: &ByteBuf, file_type: &str) -> Result<String, String> {
+/// Extract text content, and the outline of structured formats, from ByteBuf based on file type.
+/// `text_paths` pick the text of JSON records. Source files are typed by
+/// their extension, such as `rs` or `py`.
+pub fn extract_text_from_bytebuf(data: &ByteBuf, file_type: &str, text_paths: &[String]) -> Result<ExtractedText, Error> {
     match file_type.to_lowercase().as_str() {
         "txt" | "text" => {
             match str::from_utf8(data) {
-                Ok(text) => Ok(text.to_string()),
-                Err(_) => Err("Failed to decode text from file".to_string()),
+                Ok(text) => Ok(text.to_string().into()),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        // Encrypted and scanned PDFs are reported as such
+        "pdf" => extract_text_from_pdf(data),
+        "html" | "htm" => {
+            match extract_text_from_html(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        "markdown" | "md" => {
+            match extract_text_from_markdown(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        // Any word processing format, told apart by the contents
+        "docs" => {
+            match extract_text_from_document(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
             }
         },
-        "pdf" => {
-            match extract_text_from_pdf(data) {
-                Ok(text) => Ok(text.to_string()),
-                Err(_) => Err("Failed to decode pdf from file".to_string()),
+        "docx" => {
+            match extract_text_from_docx(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
             }
         },
-        // Add support for other file types as needed
-        _ => Err(format!("Unsupported file type for text extraction: {}", file_type)),
+        "odt" => {
+            match extract_text_from_odt(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        "rtf" => {
+            match extract_text_from_rtf(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        "csv" => {
+            match extract_text_from_csv(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        "tsv" => {
+            match extract_text_from_tsv(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        "xlsx" => {
+            match extract_text_from_xlsx(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        "json" => {
+            match extract_text_from_json(data, text_paths) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        "jsonl" => {
+            match extract_text_from_jsonl(data, text_paths) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        "eml" => {
+            match extract_text_from_eml(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        "mbox" => {
+            match extract_text_from_mbox(data) {
+                Ok(text) => Ok(text),
+                Err(_) => Err(Error::FileTypeNotSupported),
+            }
+        },
+        extension => match Language::from_extension(extension) {
+            Some(language) => extract_text_from_code(data, language),
+            // Add support for other file types as needed
+            None => Err(Error::FileTypeNotSupported),
+        },
     }
 }
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use super::{EmbeddingConfig, EmbeddingProvider, InputType};
+
+/// Cohere's `/embed` endpoint
+pub struct Cohere {
+    config: EmbeddingConfig,
+}
+
+impl Cohere {
+    pub fn new(config: EmbeddingConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[derive(Serialize)]
+struct CohereEmbeddingRequest<'a> {
+    model: &'a str,
+    texts: &'a [&'a str],
+    input_type: &'a str,
+}
+
+#[derive(Deserialize)]
+struct CohereEmbeddingResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl EmbeddingProvider for Cohere {
+    fn url(&self) -> String {
+        format!("{}/embed", self.config.base_url)
+    }
+
+    fn headers(&self) -> Vec<(String, String)> {
+        self.config.bearer_header()
+    }
+
+    // Cohere caps a request at 96 texts
+    fn max_batch_size(&self) -> usize {
+        96
+    }
+
+    fn request_body(&self, texts: &[&str], input_type: InputType) -> Result<Vec<u8>, String> {
+        // v3 models require telling documents and queries apart
+        let input_type = match input_type {
+            InputType::Document => "search_document",
+            InputType::Query => "search_query",
+        };
+        let request_body = CohereEmbeddingRequest {
+            model: &self.config.model,
+            texts,
+            input_type,
+        };
+
+        serde_json::to_vec(&request_body).map_err(|e| format!("Failed to serialize request: {}", e))
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>, String> {
+        let embedding_response: CohereEmbeddingResponse =
+            serde_json::from_slice(body).map_err(|e| format!("Failed to parse API response: {}", e))?;
+
+        // Embeddings come back in the order of `texts`
+        if embedding_response.embeddings.is_empty() {
+            return Err("No embeddings returned from API".to_string());
+        }
+        Ok(embedding_response.embeddings)
+    }
+}
@@ -0,0 +1,431 @@
+pub mod cohere;
+pub mod ollama;
+pub mod openai;
+pub mod tei;
+
+use ic_cdk::api::management_canister::http_request::HttpMethod;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::str;
+use crate::client::{generate_icp_uuid, is_ipv4_support_available, CanisterHttpRequest};
+use crate::vdb::memory::get_config_map_by_key;
+
+// Config map keys used to select and parameterise the embedding provider
+pub const OPENAI_API_KEY: &str = "OPENAI_KEY";
+/// Bearer token of the embedding API, `OPENAI_KEY` when unset
+pub const EMBEDDING_API_KEY: &str = "EMBEDDING_API_KEY";
+pub const EMBEDDING_PROVIDER: &str = "EMBEDDING_PROVIDER";
+pub const EMBEDDING_BASE_URL: &str = "EMBEDDING_BASE_URL";
+pub const EMBEDDING_MODEL: &str = "EMBEDDING_MODEL";
+pub const EMBEDDING_DIMENSIONS: &str = "EMBEDDING_DIMENSIONS";
+/// Extra request headers, as a JSON object of header name to value
+pub const EMBEDDING_HEADERS: &str = "EMBEDDING_HEADERS";
+
+/// Whether the text is stored in a collection or used to search it.
+/// Some models embed the two differently.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputType {
+    Document,
+    Query,
+}
+
+/// Settings shared by every provider, read from the config map
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmbeddingConfig {
+    pub provider: String,
+    pub base_url: String,
+    pub model: String,
+    pub dimensions: Option<usize>,
+    pub headers: Vec<(String, String)>,
+    pub api_key: Option<String>,
+}
+
+impl EmbeddingConfig {
+    pub fn from_config_map() -> Result<Self, String> {
+        Self::from_lookup(|key| get_config_map_by_key(key.to_string()))
+    }
+
+    /// Build the config from any key/value lookup, missing values fall back to
+    /// the provider's defaults
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let provider = lookup(EMBEDDING_PROVIDER).unwrap_or_else(|| "openai".to_string());
+        let (default_base_url, default_model) = match provider.as_str() {
+            "openai" => ("https://openai.ariwira.me/v1", "text-embedding-3-small"),
+            // Self hosted, outcalls only reach it at a public https address
+            "ollama" => ("", "nomic-embed-text"),
+            "cohere" => ("https://api.cohere.com/v1", "embed-english-v3.0"),
+            // TEI serves a single model picked when the server starts
+            "tei" => ("", ""),
+            _ => return Err(format!("Unknown embedding provider: {}", provider)),
+        };
+
+        let base_url = lookup(EMBEDDING_BASE_URL).unwrap_or_else(|| default_base_url.to_string());
+        if base_url.is_empty() {
+            return Err(format!("{} is required for the {} provider", EMBEDDING_BASE_URL, provider));
+        }
+        if !base_url.starts_with("https://") {
+            return Err(format!("{} must be an https URL, outcalls can't reach {}", EMBEDDING_BASE_URL, base_url));
+        }
+
+        let dimensions = match lookup(EMBEDDING_DIMENSIONS) {
+            Some(value) => Some(value.trim().parse::<usize>().map_err(|_| format!("Invalid {}: {}", EMBEDDING_DIMENSIONS, value))?),
+            None => None,
+        };
+
+        let headers = match lookup(EMBEDDING_HEADERS) {
+            Some(value) => serde_json::from_str::<BTreeMap<String, String>>(&value)
+                .map_err(|e| format!("Invalid {}: {}", EMBEDDING_HEADERS, e))?
+                .into_iter()
+                .collect(),
+            None => vec![],
+        };
+
+        Ok(Self {
+            provider,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: lookup(EMBEDDING_MODEL).unwrap_or_else(|| default_model.to_string()),
+            dimensions,
+            headers,
+            api_key: api_key(&lookup, EMBEDDING_API_KEY),
+        })
+    }
+
+    /// Length of the vectors the configured model returns, if known
+    pub fn dimension(&self) -> Option<usize> {
+        self.dimensions.or_else(|| model_dimension(&self.model))
+    }
+
+    pub fn provider(&self) -> Box<dyn EmbeddingProvider> {
+        match self.provider.as_str() {
+            "ollama" => Box::new(ollama::Ollama::new(self.clone())),
+            "cohere" => Box::new(cohere::Cohere::new(self.clone())),
+            "tei" => Box::new(tei::Tei::new(self.clone())),
+            _ => Box::new(openai::OpenAI::new(self.clone())),
+        }
+    }
+
+    fn bearer_header(&self) -> Vec<(String, String)> {
+        match &self.api_key {
+            Some(api_key) => vec![("Authorization".to_string(), format!("Bearer {}", api_key))],
+            None => vec![],
+        }
+    }
+}
+
+/// The non empty value of `key`, else of `OPENAI_KEY` every provider used to share
+pub fn api_key(lookup: impl Fn(&str) -> Option<String>, key: &str) -> Option<String> {
+    [key, OPENAI_API_KEY].into_iter().find_map(|key| lookup(key).filter(|value| !value.is_empty()))
+}
+
+/// Length of the vectors returned by a known embedding model
+pub fn model_dimension(model: &str) -> Option<usize> {
+    match model {
+        "text-embedding-3-small" | "text-embedding-ada-002" => Some(1536),
+        "text-embedding-3-large" => Some(3072),
+        "nomic-embed-text" | "BAAI/bge-base-en-v1.5" => Some(768),
+        "mxbai-embed-large" | "embed-english-v3.0" | "embed-multilingual-v3.0" | "BAAI/bge-large-en-v1.5" => Some(1024),
+        "all-minilm" | "embed-english-light-v3.0" | "embed-multilingual-light-v3.0" | "BAAI/bge-small-en-v1.5"
+        | "sentence-transformers/all-MiniLM-L6-v2" => Some(384),
+        _ => None,
+    }
+}
+
+/// Dimension every collection is created with, derived from the configured model
+pub fn embedding_dimension() -> Result<usize, String> {
+    let config = EmbeddingConfig::from_config_map()?;
+    config.dimension().ok_or(format!(
+        "Unknown dimension for embedding model '{}', set {}",
+        config.model, EMBEDDING_DIMENSIONS
+    ))
+}
+
+/// Request and response format of an embedding API
+pub trait EmbeddingProvider {
+    /// Full URL of the embedding endpoint
+    fn url(&self) -> String;
+
+    /// Provider specific headers, usually authentication
+    fn headers(&self) -> Vec<(String, String)>;
+
+    /// Most inputs the API accepts in a single request
+    fn max_batch_size(&self) -> usize;
+
+    /// JSON request body embedding every one of `texts`
+    fn request_body(&self, texts: &[&str], input_type: InputType) -> Result<Vec<u8>, String>;
+
+    /// The embeddings out of a successful response body, in input order
+    fn parse_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>, String>;
+}
+
+// Hard limits of an HTTP outcall
+const MAX_RESPONSE_BYTES: usize = 2 * 1024 * 1024;
+const MAX_REQUEST_BYTES: usize = 1024 * 1024;
+// JSON overhead of one embedding in a response, a float takes up to ~24 chars
+const RESPONSE_BYTES_PER_FLOAT: usize = 24;
+const RESPONSE_BYTES_PER_EMBEDDING: usize = 256;
+// JSON overhead of one input in a request, escaping may grow the text itself
+const REQUEST_BYTES_PER_INPUT: usize = 16;
+// Dimension assumed for the response budget when the model's one is unknown
+const FALLBACK_DIMENSION: usize = 4096;
+
+/// Upper bound of the response size for `count` embeddings of `dimension` floats
+fn response_bytes(count: usize, dimension: usize) -> usize {
+    1024 + count * (dimension * RESPONSE_BYTES_PER_FLOAT + RESPONSE_BYTES_PER_EMBEDDING)
+}
+
+/// Split `texts` into consecutive batches that fit the provider's batch size and
+/// both the request and response size limits of an outcall
+pub fn batches(texts: &[String], max_batch_size: usize, dimension: usize) -> Vec<Range<usize>> {
+    let max_per_response = (MAX_RESPONSE_BYTES - 1024) / response_bytes(1, dimension).saturating_sub(1024).max(1);
+    let max_inputs = max_batch_size.min(max_per_response).max(1);
+
+    let mut batches = vec![];
+    let mut start = 0;
+    let mut request_bytes = 0;
+    for (i, text) in texts.iter().enumerate() {
+        let size = text.len() * 2 + REQUEST_BYTES_PER_INPUT;
+        if i > start && (i - start >= max_inputs || request_bytes + size > MAX_REQUEST_BYTES) {
+            batches.push(start..i);
+            start = i;
+            request_bytes = 0;
+        }
+        request_bytes += size;
+    }
+    if start < texts.len() {
+        batches.push(start..texts.len());
+    }
+    batches
+}
+
+/// Generates embeddings for every text using the configured provider, batching
+/// as many texts per outcall as the limits allow. The result is in input order.
+pub async fn generate_embeddings(texts: &[String], input_type: InputType) -> Result<Vec<Vec<f32>>, String> {
+    is_ipv4_support_available();
+    let config = EmbeddingConfig::from_config_map()?;
+    let provider = config.provider();
+    let dimension = config.dimension();
+
+    // One source of randomness per call, every batch gets its own key derived from it
+    let ikey = generate_icp_uuid().await;
+    ic_cdk::println!("idempotency_key: {}", ikey.clone().to_string());
+
+    let mut embeddings = Vec::with_capacity(texts.len());
+    for (n, batch) in batches(texts, provider.max_batch_size(), dimension.unwrap_or(FALLBACK_DIMENSION)).into_iter().enumerate() {
+        let inputs: Vec<&str> = texts[batch.clone()].iter().map(|text| text.as_str()).collect();
+        let body_json = provider.request_body(&inputs, input_type)?;
+
+        let context = format!("{}-{}", ikey, n);
+        let mut headers = vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Idempotency-Key".to_string(), context.clone()),
+        ];
+        headers.extend(provider.headers());
+        headers.extend(config.headers.clone());
+
+        // Only reserve, and pay for, the response size this batch can need
+        let max_response_bytes = response_bytes(inputs.len(), dimension.unwrap_or(FALLBACK_DIMENSION)).min(MAX_RESPONSE_BYTES);
+
+        // Create HTTP request
+        let response = CanisterHttpRequest::new()
+            .url(&provider.url())
+            .method(HttpMethod::POST)
+            .add_headers(headers)
+            .max_response_bytes(max_response_bytes as u64)
+            .cycles(30_956_296_000)// Covers a 2MB response, the unused part is refunded
+            .payload(Some(body_json))
+            .transform_context("transform_exchange_http_response", context.as_bytes().to_vec())
+            .send()
+            .await?;
+
+        // Process the response
+        if response.status != 200_u16 {
+            return Err(format!(
+                "Embedding API error: Status {}, {}",
+                response.status,
+                str::from_utf8(&response.body).unwrap_or("Invalid UTF-8 response")
+            ));
+        }
+
+        let batch_embeddings = provider.parse_response(&response.body)?;
+        if batch_embeddings.len() != inputs.len() {
+            return Err(format!("Expected {} embeddings from the API, got {}", inputs.len(), batch_embeddings.len()));
+        }
+        for embedding in batch_embeddings {
+            if let Some(dimension) = dimension {
+                if embedding.len() != dimension {
+                    return Err(format!("Expected {} dimensions from the model, got {}", dimension, embedding.len()));
+                }
+            }
+            embeddings.push(embedding);
+        }
+    }
+
+    Ok(embeddings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batches, EmbeddingConfig, InputType, MAX_REQUEST_BYTES};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    fn config(entries: &[(&str, &str)]) -> Result<EmbeddingConfig, String> {
+        let map: HashMap<String, String> = entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        EmbeddingConfig::from_lookup(|key| map.get(key).cloned())
+    }
+
+    fn body(config: &EmbeddingConfig, texts: &[&str], input_type: InputType) -> Value {
+        serde_json::from_slice(&config.provider().request_body(texts, input_type).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn defaults_to_openai() {
+        let config = config(&[("OPENAI_KEY", "sk-test")]).unwrap();
+        let provider = config.provider();
+
+        assert_eq!(provider.url(), "https://openai.ariwira.me/v1/embeddings");
+        assert_eq!(config.dimension(), Some(1536));
+        assert!(provider.headers().contains(&("Authorization".to_string(), "Bearer sk-test".to_string())));
+        assert_eq!(
+            body(&config, &["hello", "world"], InputType::Document),
+            json!({"model": "text-embedding-3-small", "input": ["hello", "world"]})
+        );
+
+        // Results are put back in input order by their index
+        let response = br#"{"data": [{"index": 1, "embedding": [1.0, 0.0]}, {"index": 0, "embedding": [0.5, -0.5]}]}"#;
+        assert_eq!(provider.parse_response(response), Ok(vec![vec![0.5, -0.5], vec![1.0, 0.0]]));
+
+        let response = br#"{"data": [{"index": 0, "embedding": [1.0]}, {"index": 0, "embedding": [2.0]}]}"#;
+        assert!(provider.parse_response(response).is_err());
+    }
+
+    #[test]
+    fn openai_compatible_with_custom_dimensions_and_headers() {
+        let config = config(&[
+            ("EMBEDDING_BASE_URL", "https://llm.example.com/v1/"),
+            ("EMBEDDING_MODEL", "text-embedding-3-large"),
+            ("EMBEDDING_DIMENSIONS", "256"),
+            ("EMBEDDING_HEADERS", r#"{"X-Team": "finance"}"#),
+        ])
+        .unwrap();
+
+        assert_eq!(config.provider().url(), "https://llm.example.com/v1/embeddings");
+        assert_eq!(config.dimension(), Some(256));
+        assert_eq!(config.headers, vec![("X-Team".to_string(), "finance".to_string())]);
+        assert!(config.provider().headers().is_empty());
+        assert_eq!(
+            body(&config, &["hello"], InputType::Query),
+            json!({"model": "text-embedding-3-large", "input": ["hello"], "dimensions": 256})
+        );
+    }
+
+    #[test]
+    fn provider_key_falls_back_to_the_openai_key() {
+        let cohere = config(&[("EMBEDDING_PROVIDER", "cohere"), ("EMBEDDING_API_KEY", "co-key"), ("OPENAI_KEY", "sk-test")]).unwrap();
+        assert_eq!(cohere.api_key, Some("co-key".to_string()));
+
+        let openai = config(&[("EMBEDDING_API_KEY", ""), ("OPENAI_KEY", "sk-test")]).unwrap();
+        assert_eq!(openai.api_key, Some("sk-test".to_string()));
+        assert_eq!(config(&[]).unwrap().api_key, None);
+    }
+
+    #[test]
+    fn ollama_format() {
+        // No default address, localhost is out of reach of an outcall
+        assert!(config(&[("EMBEDDING_PROVIDER", "ollama")]).is_err());
+
+        let config = config(&[("EMBEDDING_PROVIDER", "ollama"), ("EMBEDDING_BASE_URL", "https://ollama.example.com")]).unwrap();
+        let provider = config.provider();
+
+        assert_eq!(provider.url(), "https://ollama.example.com/api/embed");
+        assert_eq!(config.dimension(), Some(768));
+        assert_eq!(
+            body(&config, &["hello", "world"], InputType::Document),
+            json!({"model": "nomic-embed-text", "input": ["hello", "world"]})
+        );
+        assert_eq!(
+            provider.parse_response(br#"{"model": "nomic-embed-text", "embeddings": [[1.0, 2.0], [3.0, 4.0]]}"#),
+            Ok(vec![vec![1.0, 2.0], vec![3.0, 4.0]])
+        );
+    }
+
+    #[test]
+    fn cohere_format() {
+        let config = config(&[("EMBEDDING_PROVIDER", "cohere"), ("OPENAI_KEY", "co-key")]).unwrap();
+        let provider = config.provider();
+
+        assert_eq!(provider.url(), "https://api.cohere.com/v1/embed");
+        assert!(provider.headers().contains(&("Authorization".to_string(), "Bearer co-key".to_string())));
+        assert_eq!(
+            body(&config, &["hello", "world"], InputType::Document),
+            json!({"model": "embed-english-v3.0", "texts": ["hello", "world"], "input_type": "search_document"})
+        );
+        assert_eq!(body(&config, &["hello"], InputType::Query)["input_type"], "search_query");
+        assert_eq!(
+            provider.parse_response(br#"{"id": "x", "embeddings": [[0.25, 0.75], [0.5, 0.5]]}"#),
+            Ok(vec![vec![0.25, 0.75], vec![0.5, 0.5]])
+        );
+    }
+
+    #[test]
+    fn tei_format() {
+        assert!(config(&[("EMBEDDING_PROVIDER", "tei")]).is_err());
+
+        let config = config(&[
+            ("EMBEDDING_PROVIDER", "tei"),
+            ("EMBEDDING_BASE_URL", "https://tei.example.com"),
+            ("EMBEDDING_DIMENSIONS", "384"),
+        ])
+        .unwrap();
+        let provider = config.provider();
+
+        assert_eq!(provider.url(), "https://tei.example.com/embed");
+        assert_eq!(config.dimension(), Some(384));
+        assert_eq!(body(&config, &["hello", "world"], InputType::Document), json!({"inputs": ["hello", "world"]}));
+        assert_eq!(provider.parse_response(b"[[0.1, 0.2], [0.3, 0.4]]"), Ok(vec![vec![0.1, 0.2], vec![0.3, 0.4]]));
+    }
+
+    #[test]
+    fn batches_respect_batch_size() {
+        let texts: Vec<String> = (0..100).map(|i| format!("chunk {}", i)).collect();
+
+        assert_eq!(batches(&texts, 96, 384), vec![0..96, 96..100]);
+        assert_eq!(batches(&texts, 32, 384), vec![0..32, 32..64, 64..96, 96..100]);
+        assert_eq!(batches(&texts[..1], 32, 384), vec![0..1]);
+        assert!(batches(&[], 32, 384).is_empty());
+    }
+
+    #[test]
+    fn batches_fit_the_response_budget() {
+        let texts: Vec<String> = (0..200).map(|i| format!("chunk {}", i)).collect();
+
+        // ~37KB per 1536 float embedding, a 2MB response holds a few dozen
+        let ranges = batches(&texts, 2048, 1536);
+        assert!(ranges.len() > 1 && ranges.len() < 10);
+        assert!(ranges.iter().all(|range| range.len() * 1536 * 24 < 2 * 1024 * 1024));
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, 200);
+        assert!(ranges.windows(2).all(|pair| pair[0].end == pair[1].start));
+    }
+
+    #[test]
+    fn batches_fit_the_request_budget() {
+        let texts: Vec<String> = (0..8).map(|_| "a".repeat(MAX_REQUEST_BYTES / 5)).collect();
+
+        let ranges = batches(&texts, 2048, 384);
+        assert_eq!(ranges, vec![0..2, 2..4, 4..6, 6..8]);
+
+        // An input over the budget on its own still gets a batch
+        let huge = vec!["a".repeat(MAX_REQUEST_BYTES)];
+        assert_eq!(batches(&huge, 2048, 384), vec![0..1]);
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        assert!(config(&[("EMBEDDING_PROVIDER", "unknown")]).is_err());
+        assert!(config(&[("EMBEDDING_DIMENSIONS", "many")]).is_err());
+        assert!(config(&[("EMBEDDING_HEADERS", "X-Team: finance")]).is_err());
+        assert!(config(&[("EMBEDDING_PROVIDER", "ollama"), ("EMBEDDING_BASE_URL", "http://localhost:11434")]).is_err());
+        assert!(config(&[("EMBEDDING_BASE_URL", "llm.example.com/v1")]).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+use super::{EmbeddingConfig, EmbeddingProvider, InputType};
+
+/// Ollama's native `/api/embed` endpoint, the older `/api/embeddings` one takes a single prompt
+pub struct Ollama {
+    config: EmbeddingConfig,
+}
+
+impl Ollama {
+    pub fn new(config: EmbeddingConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[derive(Serialize)]
+struct OllamaEmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbeddingResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl EmbeddingProvider for Ollama {
+    fn url(&self) -> String {
+        format!("{}/api/embed", self.config.base_url)
+    }
+
+    // Ollama has no authentication of its own, a proxy in front of it may
+    fn headers(&self) -> Vec<(String, String)> {
+        self.config.bearer_header()
+    }
+
+    // No limit of its own, keep each request a reasonable amount of work for one server
+    fn max_batch_size(&self) -> usize {
+        64
+    }
+
+    fn request_body(&self, texts: &[&str], _input_type: InputType) -> Result<Vec<u8>, String> {
+        let request_body = OllamaEmbeddingRequest {
+            model: &self.config.model,
+            input: texts,
+        };
+
+        serde_json::to_vec(&request_body).map_err(|e| format!("Failed to serialize request: {}", e))
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>, String> {
+        let embedding_response: OllamaEmbeddingResponse =
+            serde_json::from_slice(body).map_err(|e| format!("Failed to parse API response: {}", e))?;
+
+        if embedding_response.embeddings.is_empty() {
+            return Err("No embeddings returned from API".to_string());
+        }
+        Ok(embedding_response.embeddings)
+    }
+}
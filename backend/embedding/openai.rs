@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use super::{EmbeddingConfig, EmbeddingProvider, InputType};
+
+/// OpenAI and every server exposing the same `/embeddings` API
+pub struct OpenAI {
+    config: EmbeddingConfig,
+}
+
+impl OpenAI {
+    pub fn new(config: EmbeddingConfig) -> Self {
+        Self { config }
+    }
+}
+
+/// OpenAI API request structure for text embedding
+#[derive(Serialize)]
+struct OpenAIEmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<usize>,
+}
+
+/// OpenAI API response structure for embeddings
+#[derive(Deserialize)]
+struct OpenAIEmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl EmbeddingProvider for OpenAI {
+    fn url(&self) -> String {
+        format!("{}/embeddings", self.config.base_url)
+    }
+
+    fn headers(&self) -> Vec<(String, String)> {
+        self.config.bearer_header()
+    }
+
+    // OpenAI caps a request at 2048 inputs
+    fn max_batch_size(&self) -> usize {
+        2048
+    }
+
+    fn request_body(&self, texts: &[&str], _input_type: InputType) -> Result<Vec<u8>, String> {
+        let request_body = OpenAIEmbeddingRequest {
+            model: &self.config.model,
+            input: texts,
+            dimensions: self.config.dimensions,
+        };
+
+        serde_json::to_vec(&request_body).map_err(|e| format!("Failed to serialize request: {}", e))
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>, String> {
+        let embedding_response: OpenAIEmbeddingResponse =
+            serde_json::from_slice(body).map_err(|e| format!("Failed to parse API response: {}", e))?;
+
+        if embedding_response.data.is_empty() {
+            return Err("No embeddings returned from API".to_string());
+        }
+
+        // The results are not guaranteed to be in input order, put each one back at its index
+        let mut embeddings = vec![None; embedding_response.data.len()];
+        for data in embedding_response.data {
+            match embeddings.get_mut(data.index) {
+                Some(slot) if slot.is_none() => *slot = Some(data.embedding),
+                _ => return Err(format!("Unexpected embedding index {} in API response", data.index)),
+            }
+        }
+        Ok(embeddings.into_iter().flatten().collect())
+    }
+}
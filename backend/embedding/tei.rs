@@ -0,0 +1,49 @@
+use serde::Serialize;
+use super::{EmbeddingConfig, EmbeddingProvider, InputType};
+
+/// Hugging Face Text Embeddings Inference, `/embed` returns a bare list of vectors
+pub struct Tei {
+    config: EmbeddingConfig,
+}
+
+impl Tei {
+    pub fn new(config: EmbeddingConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[derive(Serialize)]
+struct TeiEmbeddingRequest<'a> {
+    inputs: &'a [&'a str],
+}
+
+impl EmbeddingProvider for Tei {
+    fn url(&self) -> String {
+        format!("{}/embed", self.config.base_url)
+    }
+
+    // Inference Endpoints take a HF token, a self hosted server usually nothing
+    fn headers(&self) -> Vec<(String, String)> {
+        self.config.bearer_header()
+    }
+
+    // Default `--max-client-batch-size` of the server
+    fn max_batch_size(&self) -> usize {
+        32
+    }
+
+    fn request_body(&self, texts: &[&str], _input_type: InputType) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(&TeiEmbeddingRequest { inputs: texts })
+            .map_err(|e| format!("Failed to serialize request: {}", e))
+    }
+
+    fn parse_response(&self, body: &[u8]) -> Result<Vec<Vec<f32>>, String> {
+        let embeddings: Vec<Vec<f32>> =
+            serde_json::from_slice(body).map_err(|e| format!("Failed to parse API response: {}", e))?;
+
+        if embeddings.is_empty() {
+            return Err("No embeddings returned from API".to_string());
+        }
+        Ok(embeddings)
+    }
+}
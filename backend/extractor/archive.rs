@@ -0,0 +1,229 @@
+use crate::vdb::error::Error;
+use flate2::read::DeflateDecoder;
+use std::io::Read;
+
+/// Largest entry inflated, office documents are far smaller and anything
+/// bigger is more likely a zip bomb than text
+const MAX_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const CENTRAL_DIRECTORY_ENTRY: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+
+struct Entry {
+    name: String,
+    method: u16,
+    compressed_size: u64,
+    size: u64,
+    offset: u64,
+}
+
+/// Read only view of a zip archive, enough for the OOXML and ODF containers:
+/// stored and deflated entries, no encryption, no zip64.
+pub struct Archive<'a> {
+    bytes: &'a [u8],
+    entries: Vec<Entry>,
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        // The end of central directory record sits after the optional comment
+        let end = (0..bytes.len().saturating_sub(21))
+            .rev()
+            .take(22 + u16::MAX as usize)
+            .find(|&at| read_u32(bytes, at, 0) == Some(END_OF_CENTRAL_DIRECTORY))
+            .ok_or(Error::FileTypeNotSupported)?;
+        let count = read_u16(bytes, end, 10).ok_or(Error::FileTypeNotSupported)?;
+        let mut at = read_u32(bytes, end, 16).ok_or(Error::FileTypeNotSupported)? as usize;
+
+        let mut entries = vec![];
+        for _ in 0..count {
+            if read_u32(bytes, at, 0) != Some(CENTRAL_DIRECTORY_ENTRY) {
+                return Err(Error::FileTypeNotSupported);
+            }
+            let field = |offset: usize| read_u16(bytes, at, offset).ok_or(Error::FileTypeNotSupported);
+            let wide = |offset: usize| read_u32(bytes, at, offset).ok_or(Error::FileTypeNotSupported);
+            let name_len = field(28)? as usize;
+            let name = span(bytes, &[at, 46], name_len).ok_or(Error::FileTypeNotSupported)?;
+            entries.push(Entry {
+                name: String::from_utf8_lossy(name).into_owned(),
+                method: field(10)?,
+                compressed_size: wide(20)? as u64,
+                size: wide(24)? as u64,
+                offset: wide(42)? as u64,
+            });
+            at = end_of(&[at, 46, name_len, field(30)? as usize, field(32)? as usize])
+                .ok_or(Error::FileTypeNotSupported)?;
+        }
+
+        Ok(Self { bytes, entries })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.iter().any(|entry| entry.name == name)
+    }
+
+    /// Contents of the entry called `name`
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let entry = self.entries.iter().find(|entry| entry.name == name).ok_or(Error::FileTypeNotSupported)?;
+        if entry.size > MAX_ENTRY_SIZE {
+            return Err(Error::FileTypeNotSupported);
+        }
+
+        // The local header repeats the name and may carry a different extra field
+        let at = usize::try_from(entry.offset).map_err(|_| Error::FileTypeNotSupported)?;
+        if read_u32(self.bytes, at, 0) != Some(LOCAL_FILE_HEADER) {
+            return Err(Error::FileTypeNotSupported);
+        }
+        let name_len = read_u16(self.bytes, at, 26).ok_or(Error::FileTypeNotSupported)? as usize;
+        let extra_len = read_u16(self.bytes, at, 28).ok_or(Error::FileTypeNotSupported)? as usize;
+        let compressed_size = usize::try_from(entry.compressed_size).map_err(|_| Error::FileTypeNotSupported)?;
+        let data = span(self.bytes, &[at, 30, name_len, extra_len], compressed_size)
+            .ok_or(Error::FileTypeNotSupported)?;
+
+        match entry.method {
+            0 => Ok(data.to_vec()),
+            8 => {
+                let mut out = Vec::with_capacity(entry.size as usize);
+                DeflateDecoder::new(data)
+                    .take(MAX_ENTRY_SIZE)
+                    .read_to_end(&mut out)
+                    .map_err(|_| Error::FileTypeNotSupported)?;
+                Ok(out)
+            }
+            _ => Err(Error::FileTypeNotSupported),
+        }
+    }
+
+    /// Contents of the entry called `name` as text
+    pub fn read_string(&self, name: &str) -> Result<String, Error> {
+        String::from_utf8(self.read(name)?).map_err(|_| Error::FileTypeNotSupported)
+    }
+}
+
+// Offsets and lengths come from the file, summing them must not wrap around on wasm32
+fn end_of(parts: &[usize]) -> Option<usize> {
+    parts.iter().try_fold(0usize, |sum, &part| sum.checked_add(part))
+}
+
+// The `len` bytes starting at the sum of `start`, None when they're not all in `bytes`
+fn span<'b>(bytes: &'b [u8], start: &[usize], len: usize) -> Option<&'b [u8]> {
+    let start = end_of(start)?;
+    bytes.get(start..start.checked_add(len)?)
+}
+
+fn read_u16(bytes: &[u8], at: usize, offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(span(bytes, &[at, offset], 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], at: usize, offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(span(bytes, &[at, offset], 4)?.try_into().ok()?))
+}
+
+/// Zip archive of `files`, deflated when `deflate` is set, for the extractor tests
+#[cfg(test)]
+pub(crate) fn zip(files: &[(&str, &str)], deflate: bool) -> Vec<u8> {
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let mut out = vec![];
+    let mut directory = vec![];
+    for (name, contents) in files {
+        let data = match deflate {
+            true => {
+                let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+                encoder.write_all(contents.as_bytes()).unwrap();
+                encoder.finish().unwrap()
+            }
+            false => contents.as_bytes().to_vec(),
+        };
+        let method: u16 = if deflate { 8 } else { 0 };
+        let offset = out.len() as u32;
+
+        // Local header: signature, version, flags, method, time, date, crc, sizes, name and extra lengths
+        out.extend(LOCAL_FILE_HEADER.to_le_bytes());
+        out.extend([20, 0, 0, 0]);
+        out.extend(method.to_le_bytes());
+        out.extend([0; 8]);
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend((contents.len() as u32).to_le_bytes());
+        out.extend((name.len() as u16).to_le_bytes());
+        out.extend([0; 2]);
+        out.extend(name.as_bytes());
+        out.extend(&data);
+
+        directory.extend(CENTRAL_DIRECTORY_ENTRY.to_le_bytes());
+        directory.extend([20, 0, 20, 0, 0, 0]);
+        directory.extend(method.to_le_bytes());
+        directory.extend([0; 8]);
+        directory.extend((data.len() as u32).to_le_bytes());
+        directory.extend((contents.len() as u32).to_le_bytes());
+        directory.extend((name.len() as u16).to_le_bytes());
+        directory.extend([0; 12]);
+        directory.extend(offset.to_le_bytes());
+        directory.extend(name.as_bytes());
+    }
+
+    let directory_offset = out.len() as u32;
+    out.extend(&directory);
+    out.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    out.extend([0; 4]);
+    out.extend((files.len() as u16).to_le_bytes());
+    out.extend((files.len() as u16).to_le_bytes());
+    out.extend((directory.len() as u32).to_le_bytes());
+    out.extend(directory_offset.to_le_bytes());
+    out.extend([0; 2]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{zip, Archive};
+    use crate::vdb::error::Error;
+
+    #[test]
+    fn reads_stored_and_deflated_entries() {
+        let files = [("mimetype", "application/test"), ("dir/content.xml", "<a>hello hello hello hello</a>")];
+        for deflate in [false, true] {
+            let bytes = zip(&files, deflate);
+            let archive = Archive::new(&bytes).unwrap();
+            assert!(archive.contains("dir/content.xml"));
+            assert!(!archive.contains("content.xml"));
+            assert_eq!(archive.read_string("mimetype").unwrap(), "application/test");
+            assert_eq!(archive.read_string("dir/content.xml").unwrap(), "<a>hello hello hello hello</a>");
+            assert!(archive.read("missing").is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_zip() {
+        assert_eq!(Archive::new(b"{\\rtf1 hello}").err(), Some(Error::FileTypeNotSupported));
+        assert!(Archive::new(&[]).is_err());
+
+        // Cut short, the directory points past the end
+        let bytes = zip(&[("a.xml", "<a/>")], true);
+        assert!(Archive::new(&bytes[10..]).is_err());
+    }
+
+    #[test]
+    fn rejects_entries_outside_the_archive() {
+        let bytes = zip(&[("a.xml", "<a/>")], false);
+        let directory = u32::from_le_bytes(bytes[bytes.len() - 6..bytes.len() - 2].try_into().unwrap()) as usize;
+
+        // Compressed size, then local header offset, pointing far past the end
+        for field in [20, 42] {
+            for value in [u32::MAX, u32::MAX - 30] {
+                let mut crafted = bytes.clone();
+                crafted[directory + field..directory + field + 4].copy_from_slice(&value.to_le_bytes());
+                let archive = Archive::new(&crafted).unwrap();
+                assert_eq!(archive.read("a.xml").err(), Some(Error::FileTypeNotSupported));
+            }
+        }
+
+        // A name length running past the end of the directory
+        let mut crafted = bytes.clone();
+        crafted[directory + 28..directory + 30].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(Archive::new(&crafted).is_err());
+    }
+}
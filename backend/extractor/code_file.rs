@@ -0,0 +1,791 @@
+use super::{CodeBlock, ExtractedText};
+use crate::vdb::error::Error;
+
+/// Programming languages of the source files that can be ingested
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    Rust,
+    TypeScript,
+    JavaScript,
+    Python,
+    Go,
+    Java,
+    Kotlin,
+    CSharp,
+    C,
+    Cpp,
+}
+
+impl Language {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        let language = match extension.to_lowercase().as_str() {
+            "rs" => Self::Rust,
+            "ts" | "tsx" | "mts" | "cts" => Self::TypeScript,
+            "js" | "jsx" | "mjs" | "cjs" => Self::JavaScript,
+            "py" | "pyi" => Self::Python,
+            "go" => Self::Go,
+            "java" => Self::Java,
+            "kt" | "kts" => Self::Kotlin,
+            "cs" => Self::CSharp,
+            "c" | "h" => Self::C,
+            "cpp" | "cc" | "cxx" | "hpp" | "hh" | "hxx" => Self::Cpp,
+            _ => return None,
+        };
+        Some(language)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Rust => "rust",
+            Self::TypeScript => "typescript",
+            Self::JavaScript => "javascript",
+            Self::Python => "python",
+            Self::Go => "go",
+            Self::Java => "java",
+            Self::Kotlin => "kotlin",
+            Self::CSharp => "csharp",
+            Self::C => "c",
+            Self::Cpp => "cpp",
+        }
+    }
+
+    // Joins a member's name to its container's
+    fn separator(self) -> &'static str {
+        match self {
+            Self::Rust | Self::Cpp => "::",
+            _ => ".",
+        }
+    }
+}
+
+/// Words that open a statement rather than declare something
+const CONTROL: [&str; 27] = [
+    "if", "else", "for", "foreach", "while", "do", "switch", "match", "when", "case", "try", "catch", "except", "with",
+    "return", "throw", "yield", "await", "new", "delete", "sizeof", "typeof", "using", "lock", "assert", "elif",
+    "synchronized",
+];
+
+/// Source text of a file split into blocks on the boundaries of its
+/// functions, classes and other top-level items, containers such as classes
+/// and impls down to their members. The split is heuristic: declarations are
+/// recognised by their leading keywords and their extent by brace depth, or
+/// indentation for Python, with comments and strings masked out.
+pub fn extract_text_from_code(bytes: &[u8], language: Language) -> Result<ExtractedText, Error> {
+    let text = std::str::from_utf8(bytes).map_err(|_| Error::FileTypeNotSupported)?;
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+
+    let source = Source::new(text, language);
+    let items = match language {
+        Language::Python => source.indented_items(0, source.lines.len(), 0),
+        _ => source.braced_items(0, source.lines.len(), 0),
+    };
+    let mut spans = vec![];
+    source.spans(&items, 0, source.lines.len(), None, None, &mut spans);
+
+    let blocks = spans
+        .into_iter()
+        .map(|span| CodeBlock {
+            symbol: span.symbol,
+            item: span.item,
+            start: source.offsets[span.first],
+            end: source.offsets[span.last] + source.lines[span.last].chars().count(),
+        })
+        .collect();
+    Ok(ExtractedText {
+        text: text.to_string(),
+        language: Some(language.name().to_string()),
+        blocks,
+        ..Default::default()
+    })
+}
+
+// A declaration found on a line: the item's name, and whether its body
+// holds members worth splitting on
+struct Declaration {
+    name: String,
+    container: bool,
+}
+
+// A declared item and its line range, leading comments included
+struct Item {
+    name: String,
+    first: usize,
+    // Line its body starts after
+    header: usize,
+    last: usize,
+    members: Vec<Item>,
+}
+
+// Lines of a block with what it's called and the top-level item it's part of
+struct Span {
+    first: usize,
+    last: usize,
+    symbol: Option<String>,
+    item: Option<String>,
+}
+
+struct Source<'a> {
+    language: Language,
+    lines: Vec<&'a str>,
+    // Character offset of every line
+    offsets: Vec<usize>,
+    // Lines with comments and the contents of strings blanked out
+    code: Vec<String>,
+    // Nesting at the start of every line, and after the last one: braces, or
+    // any bracket for Python
+    depths: Vec<usize>,
+    // Whether a line starts inside a multi-line string
+    in_string: Vec<bool>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Code,
+    BlockComment,
+    Quoted(char),
+    Triple(char),
+    // A Rust raw string closed by a quote and this many `#`s
+    Raw(usize),
+}
+
+impl<'a> Source<'a> {
+    fn new(text: &'a str, language: Language) -> Self {
+        let lines: Vec<&str> = text.split('\n').collect();
+        let mut offsets = Vec::with_capacity(lines.len());
+        let mut offset = 0;
+        for line in &lines {
+            offsets.push(offset);
+            offset += line.chars().count() + 1;
+        }
+
+        let mut state = State::Code;
+        let mut code = Vec::with_capacity(lines.len());
+        let mut in_string = Vec::with_capacity(lines.len());
+        for line in &lines {
+            in_string.push(matches!(state, State::Quoted(_) | State::Triple(_) | State::Raw(_)));
+            code.push(mask(line, language, &mut state));
+        }
+
+        let mut depths = Vec::with_capacity(lines.len() + 1);
+        let mut depth: i64 = 0;
+        for line in &code {
+            depths.push(depth.max(0) as usize);
+            for c in line.chars() {
+                match (c, language) {
+                    ('{', _) | ('(' | '[', Language::Python) => depth += 1,
+                    ('}', _) | (')' | ']', Language::Python) => depth -= 1,
+                    _ => {}
+                }
+            }
+        }
+        depths.push(depth.max(0) as usize);
+
+        Self { language, lines, offsets, code, depths, in_string }
+    }
+
+    // Items declared at brace depth `depth` within lines `from..to`
+    fn braced_items(&self, from: usize, to: usize, depth: usize) -> Vec<Item> {
+        let mut items = vec![];
+        let mut i = from;
+        while i < to {
+            let declaration = match self.depths[i] == depth {
+                true => declaration(self.language, self.code[i].trim(), depth > 0),
+                false => None,
+            };
+            let Some(declaration) = declaration else {
+                i += 1;
+                continue;
+            };
+
+            // The body opens on the first line with a brace, a declaration
+            // without one ends with a `;` or where the next one starts
+            let mut header = None;
+            let mut last = to - 1;
+            for k in i..to {
+                let code = self.code[k].trim_end();
+                if header.is_none() && code.contains('{') {
+                    header = Some(k);
+                }
+                if header.is_some() && self.depths[k + 1] <= depth {
+                    last = k;
+                    break;
+                }
+                let next_starts = k + 1 == to
+                    || self.code[k + 1].trim().is_empty()
+                    || declaration_at(self, k + 1, depth);
+                if header.is_none() && (code.ends_with(';') || next_starts) {
+                    last = k;
+                    break;
+                }
+            }
+
+            let members = match (declaration.container, header) {
+                (true, Some(header)) if last > header => self.braced_items(header + 1, last, depth + 1),
+                _ => vec![],
+            };
+            items.push(Item {
+                name: declaration.name,
+                first: self.leading_comments(i, from),
+                header: header.unwrap_or(i),
+                last,
+                members,
+            });
+            i = last + 1;
+        }
+        items
+    }
+
+    // Python items indented by `indent` within lines `from..to`, ending
+    // before the next line that is indented no deeper
+    fn indented_items(&self, from: usize, to: usize, indent: usize) -> Vec<Item> {
+        let mut items = vec![];
+        let mut i = from;
+        while i < to {
+            let statement = !self.in_string[i] && self.depths[i] == 0 && !self.code[i].trim().is_empty();
+            let declaration = match statement && indentation(self.lines[i]) == indent {
+                true => declaration(self.language, self.code[i].trim(), indent > 0),
+                false => None,
+            };
+            let Some(declaration) = declaration else {
+                i += 1;
+                continue;
+            };
+
+            // The signature may run over several lines, the body follows it
+            let mut header = i;
+            while header + 1 < to && self.depths[header + 1] > 0 {
+                header += 1;
+            }
+            let mut last = header;
+            for k in header + 1..to {
+                let blank = self.code[k].trim().is_empty() && !self.in_string[k];
+                if blank {
+                    continue;
+                }
+                if !self.in_string[k] && self.depths[k] == 0 && indentation(self.lines[k]) <= indent {
+                    break;
+                }
+                last = k;
+            }
+
+            let body = (header + 1..=last).find(|&k| !self.in_string[k] && !self.code[k].trim().is_empty());
+            let members = match (declaration.container, body) {
+                (true, Some(body)) => self.indented_items(body, last + 1, indentation(self.lines[body])),
+                _ => vec![],
+            };
+            items.push(Item {
+                name: declaration.name,
+                first: self.leading_comments(i, from),
+                header,
+                last,
+                members,
+            });
+            i = last + 1;
+        }
+        items
+    }
+
+    // First of the comment, attribute and decorator lines right above line `at`
+    fn leading_comments(&self, at: usize, from: usize) -> usize {
+        let prefixes: &[&str] = match self.language {
+            Language::Python => &["#", "@"],
+            Language::Rust => &["//", "/*", "*", "#["],
+            Language::Cpp => &["//", "/*", "*", "template", "[["],
+            Language::CSharp => &["//", "/*", "*", "["],
+            _ => &["//", "/*", "*", "@"],
+        };
+        let indent = indentation(self.lines[at]);
+        let mut first = at;
+        while first > from {
+            let line = self.lines[first - 1];
+            let trimmed = line.trim();
+            if trimmed.is_empty() || indentation(line) != indent || !prefixes.iter().any(|prefix| trimmed.starts_with(prefix)) {
+                break;
+            }
+            first -= 1;
+        }
+        first
+    }
+
+    // Spans of lines `from..to` holding `items`: every item on its own, or
+    // its members for a container, and the lines around them
+    fn spans(&self, items: &[Item], from: usize, to: usize, container: Option<&str>, top: Option<&str>, spans: &mut Vec<Span>) {
+        let mut next = from;
+        for item in items {
+            if item.first > next {
+                self.gap(next, item.first - 1, container, top, spans);
+            }
+            let symbol = match container {
+                Some(container) => format!("{}{}{}", container, self.language.separator(), item.name),
+                None => item.name.clone(),
+            };
+            let top = top.unwrap_or(&symbol).to_string();
+            match item.members.is_empty() {
+                true => spans.push(Span {
+                    first: item.first,
+                    last: item.last,
+                    symbol: Some(symbol),
+                    item: Some(top),
+                }),
+                false => {
+                    // The header goes with the lines up to the first member
+                    self.gap(item.first, item.header, Some(&symbol), Some(&top), spans);
+                    self.spans(&item.members, item.header + 1, item.last + 1, Some(&symbol), Some(&top), spans);
+                }
+            }
+            next = item.last + 1;
+        }
+        if next < to {
+            self.gap(next, to - 1, container, top, spans);
+        }
+    }
+
+    // Lines between items, joined to the previous gap of the same container
+    fn gap(&self, first: usize, last: usize, container: Option<&str>, top: Option<&str>, spans: &mut Vec<Span>) {
+        let symbol = container.map(str::to_string);
+        if let Some(previous) = spans.last_mut() {
+            if previous.last + 1 == first && previous.symbol == symbol && previous.item.as_deref() == top {
+                previous.last = last;
+                return;
+            }
+        }
+        spans.push(Span {
+            first,
+            last,
+            symbol,
+            item: top.map(str::to_string),
+        });
+    }
+}
+
+fn declaration_at(source: &Source, line: usize, depth: usize) -> bool {
+    source.depths[line] == depth && declaration(source.language, source.code[line].trim(), depth > 0).is_some()
+}
+
+fn indentation(line: &str) -> usize {
+    line.chars().take_while(|c| *c == ' ' || *c == '\t').map(|c| if c == '\t' { 4 } else { 1 }).sum()
+}
+
+// A line with its comments removed and the contents of its strings blanked,
+// `state` carries comments and strings over to the next line
+fn mask(line: &str, language: Language, state: &mut State) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut code = String::with_capacity(line.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match *state {
+            State::BlockComment => {
+                if c == '*' && next == Some('/') {
+                    *state = State::Code;
+                    i += 1;
+                }
+            }
+            State::Quoted(quote) => {
+                if c == '\\' {
+                    i += 1;
+                } else if c == quote {
+                    *state = State::Code;
+                    code.push(quote);
+                }
+            }
+            State::Triple(quote) => {
+                if c == '\\' {
+                    i += 1;
+                } else if c == quote && next == Some(quote) && chars.get(i + 2) == Some(&quote) {
+                    *state = State::Code;
+                    code.push_str(&quote.to_string().repeat(3));
+                    i += 2;
+                }
+            }
+            State::Raw(hashes) => {
+                if c == '"' && chars[i + 1..].iter().take(hashes).filter(|&&h| h == '#').count() == hashes {
+                    *state = State::Code;
+                    code.push('"');
+                    i += hashes;
+                }
+            }
+            State::Code => {
+                let python = language == Language::Python;
+                let word_start = i == 0 || !(chars[i - 1].is_alphanumeric() || chars[i - 1] == '_');
+                if (python && c == '#') || (!python && c == '/' && next == Some('/')) {
+                    break;
+                } else if !python && c == '/' && next == Some('*') {
+                    *state = State::BlockComment;
+                    i += 1;
+                } else if (c == '"' || (python && c == '\''))
+                    && matches!(language, Language::Python | Language::Kotlin | Language::Java | Language::CSharp)
+                    && next == Some(c)
+                    && chars.get(i + 2) == Some(&c)
+                {
+                    *state = State::Triple(c);
+                    code.push_str(&c.to_string().repeat(3));
+                    i += 2;
+                } else if language == Language::Rust && c == 'r' && word_start && matches!(next, Some('"' | '#')) {
+                    let hashes = chars[i + 1..].iter().take_while(|&&h| h == '#').count();
+                    if chars.get(i + 1 + hashes) == Some(&'"') {
+                        *state = State::Raw(hashes);
+                        code.push_str("r\"");
+                        i += 1 + hashes;
+                    } else {
+                        code.push(c);
+                    }
+                } else if c == '"' || (c == '`' && matches!(language, Language::TypeScript | Language::JavaScript | Language::Go)) {
+                    *state = State::Quoted(c);
+                    code.push(c);
+                } else if c == '\'' {
+                    // A Rust quote is a lifetime unless it closes a character right after
+                    let character = next == Some('\\') || chars.get(i + 2) == Some(&'\'');
+                    if language != Language::Rust || character {
+                        *state = State::Quoted(c);
+                    }
+                    code.push(c);
+                } else {
+                    code.push(c);
+                }
+            }
+        }
+        i += 1;
+    }
+
+    // Only Rust strings and template literals run on to the next line
+    if let State::Quoted(quote) = *state {
+        if quote != '`' && !(language == Language::Rust && quote == '"') {
+            *state = State::Code;
+        }
+    }
+    code
+}
+
+// The item a masked, trimmed line declares, `member` for lines in the body
+// of a container
+fn declaration(language: Language, line: &str, member: bool) -> Option<Declaration> {
+    let declared = |name: &str, container: bool| {
+        Some(Declaration {
+            name: name.to_string(),
+            container,
+        })
+    };
+    match language {
+        Language::Rust => {
+            let line = strip_words(line, &["pub", "async", "unsafe", "default", "extern"]);
+            let line = match line.strip_prefix("const ") {
+                Some(rest) if rest.trim_start().starts_with("fn ") || rest.trim_start().starts_with("unsafe ") => {
+                    strip_words(rest, &["unsafe", "extern"])
+                }
+                _ => line,
+            };
+            if let Some(rest) = line.strip_prefix("impl") {
+                if rest.starts_with([' ', '<']) {
+                    return declared(&impl_type(rest), true);
+                }
+            }
+            if let Some(rest) = line.strip_prefix("macro_rules!") {
+                return declared(identifier(rest.trim_start())?, false);
+            }
+            let (keyword, rest) = line.split_once(' ')?;
+            let rest = rest.trim_start();
+            match keyword {
+                "fn" | "struct" | "enum" | "union" | "type" | "const" => declared(identifier(rest)?, false),
+                "static" => declared(identifier(rest.strip_prefix("mut ").unwrap_or(rest))?, false),
+                "trait" | "mod" => declared(identifier(rest)?, true),
+                _ => None,
+            }
+        }
+        Language::TypeScript | Language::JavaScript if !member => {
+            let line = strip_words(line, &["export", "default", "declare", "async", "abstract"]);
+            let (keyword, rest) = line.split_once([' ', '*']).unwrap_or((line, ""));
+            let rest = rest.trim_start_matches([' ', '*']);
+            match keyword {
+                "function" => declared(identifier(rest)?, false),
+                "class" => declared(identifier(rest).unwrap_or("default"), true),
+                "interface" | "namespace" | "module" => declared(identifier(rest)?, true),
+                "type" | "enum" | "const" | "let" | "var" => declared(identifier(rest)?, false),
+                _ => None,
+            }
+        }
+        Language::TypeScript | Language::JavaScript => {
+            let line = strip_words(
+                line,
+                &["public", "private", "protected", "static", "readonly", "async", "abstract", "override", "declare", "get", "set", "accessor"],
+            );
+            let line = line.trim_start_matches(['*', '#']);
+            let name = identifier(line)?;
+            let rest = line[name.len()..].trim_start().trim_start_matches('?');
+            let method = rest.starts_with(['(', '<']) || (rest.starts_with('=') && rest.contains("=>"));
+            (method && !CONTROL.contains(&name)).then(|| Declaration {
+                name: name.to_string(),
+                container: false,
+            })
+        }
+        Language::Python => {
+            let line = strip_words(line, &["async"]);
+            let (keyword, rest) = line.split_once(' ')?;
+            match keyword {
+                "def" => declared(identifier(rest.trim_start())?, false),
+                "class" => declared(identifier(rest.trim_start())?, true),
+                _ => None,
+            }
+        }
+        Language::Go => {
+            let (keyword, rest) = line.split_once(' ')?;
+            let rest = rest.trim_start();
+            match keyword {
+                "func" => match rest.strip_prefix('(') {
+                    // A method is named after its receiver's type
+                    Some(receiver) => {
+                        let (receiver, rest) = receiver.split_once(')')?;
+                        let receiver = receiver.split_whitespace().last()?.trim_start_matches('*');
+                        let receiver = receiver.split('[').next()?;
+                        declared(&format!("{}.{}", receiver, identifier(rest.trim_start())?), false)
+                    }
+                    None => declared(identifier(rest)?, false),
+                },
+                "type" | "var" | "const" => declared(identifier(rest)?, false),
+                _ => None,
+            }
+        }
+        Language::Java | Language::Kotlin | Language::CSharp => {
+            let mut line = line;
+            // Annotations and attributes on the declaration's own line
+            while let Some(rest) = line.strip_prefix('@') {
+                line = rest.split_once(' ').map_or("", |(_, rest)| rest).trim_start();
+            }
+            let line = strip_words(
+                line,
+                &[
+                    "public", "private", "protected", "internal", "static", "final", "abstract", "sealed", "open",
+                    "data", "override", "suspend", "inline", "virtual", "async", "partial", "readonly", "extern",
+                    "unsafe", "synchronized", "native", "default", "strictfp", "lateinit", "inner", "value",
+                    "companion", "operator", "infix", "tailrec", "external", "annotation", "const", "enum",
+                ],
+            );
+            let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let rest = rest.trim_start();
+            match keyword {
+                "class" | "interface" | "record" | "object" | "struct" | "namespace" => declared(identifier(rest)?, true),
+                "fun" => {
+                    let rest = skip_generics(rest);
+                    let name = rest.split('(').next()?.trim();
+                    (!name.is_empty()).then(|| Declaration {
+                        name: name.to_string(),
+                        container: false,
+                    })
+                }
+                "val" | "var" => declared(identifier(rest)?, false),
+                _ if member || language == Language::CSharp => call_like(line),
+                _ => None,
+            }
+        }
+        Language::C | Language::Cpp => {
+            if line.starts_with('#') {
+                return None;
+            }
+            let line = strip_words(line, &["static", "inline", "extern", "virtual", "constexpr", "explicit", "friend"]);
+            let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let rest = rest.trim_start();
+            match keyword {
+                "class" | "struct" | "namespace" if !line.ends_with(';') => {
+                    declared(identifier(rest.strip_prefix("class ").unwrap_or(rest)).unwrap_or("anonymous"), true)
+                }
+                "enum" | "union" if !line.ends_with(';') => {
+                    declared(identifier(rest.strip_prefix("class ").unwrap_or(rest)).unwrap_or("anonymous"), false)
+                }
+                "typedef" | "using" | "return" => None,
+                // Prototypes end in `;`, only members are declared that way
+                _ if member || !line.ends_with(';') => call_like(line),
+                _ => None,
+            }
+        }
+    }
+}
+
+// A method or function signature, the name right before the parameters
+fn call_like(line: &str) -> Option<Declaration> {
+    let (before, _) = line.split_once('(')?;
+    if before.contains(['=', '.', '"']) || before.trim().is_empty() {
+        return None;
+    }
+    let name = before.trim_end().rsplit([' ', '*', '&']).next()?;
+    let words = before.split_whitespace().count();
+    let first = before.split_whitespace().next()?;
+    let valid = name.chars().all(|c| c.is_alphanumeric() || matches!(c, '_' | ':' | '~' | '<' | '>' | ','));
+    // A bare call has no return type in front, only constructors and macros look like that
+    (valid && !CONTROL.contains(&first) && !CONTROL.contains(&name) && (words > 1 || name.starts_with(char::is_uppercase)))
+        .then(|| Declaration {
+            name: name.split('<').next().unwrap_or(name).to_string(),
+            container: false,
+        })
+}
+
+// The type an impl block is for, `impl<T> Display for Wrapper<T>` is for `Wrapper`
+fn impl_type(rest: &str) -> String {
+    let rest = skip_generics(rest.trim_start());
+    let rest = rest.split(['{', ';']).next().unwrap_or(rest);
+    let rest = rest.split(" where").next().unwrap_or(rest);
+    let target = rest.rsplit(" for ").next().unwrap_or(rest).trim();
+    let target = target.trim_start_matches(['&', '*']).trim_start_matches("dyn ").trim();
+    target.split('<').next().unwrap_or(target).trim().to_string()
+}
+
+// The text after a leading `<...>`
+fn skip_generics(text: &str) -> &str {
+    if !text.starts_with('<') {
+        return text;
+    }
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return text[i + 1..].trim_start();
+        }
+    }
+    ""
+}
+
+// `line` after any of the leading `words`, and a visibility like `pub(crate)`
+fn strip_words<'l>(mut line: &'l str, words: &[&str]) -> &'l str {
+    loop {
+        if let Some(rest) = line.strip_prefix("pub(").and_then(|rest| rest.split_once(')')) {
+            line = rest.1.trim_start();
+            continue;
+        }
+        // The ABI of an `extern "C"`, its contents masked
+        if line.starts_with('"') {
+            match line[1..].find('"') {
+                Some(end) => line = line[end + 2..].trim_start(),
+                None => return line,
+            }
+            continue;
+        }
+        let stripped = words.iter().find_map(|word| {
+            let rest = line.strip_prefix(word)?;
+            rest.starts_with([' ', '\t']).then(|| rest.trim_start())
+        });
+        match stripped {
+            Some(rest) => line = rest,
+            None => return line,
+        }
+    }
+}
+
+fn identifier(text: &str) -> Option<&str> {
+    let end = text.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$')).unwrap_or(text.len());
+    let name = &text[..end];
+    (!name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit())).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_text_from_code, Language};
+
+    // Symbol, top-level item and text of every block
+    fn blocks(source: &str, language: Language) -> Vec<(Option<String>, Option<String>, String)> {
+        let extracted = extract_text_from_code(source.as_bytes(), language).unwrap();
+        assert_eq!(extracted.text, source);
+        let chars: Vec<char> = extracted.text.chars().collect();
+        extracted
+            .blocks
+            .iter()
+            .map(|block| (block.symbol.clone(), block.item.clone(), chars[block.start..block.end].iter().collect()))
+            .collect()
+    }
+
+    fn symbols(source: &str, language: Language) -> Vec<Option<String>> {
+        blocks(source, language).into_iter().map(|(symbol, _, _)| symbol).collect()
+    }
+
+    fn some(names: &[&str]) -> Vec<Option<String>> {
+        names.iter().map(|name| (!name.is_empty()).then(|| name.to_string())).collect()
+    }
+
+    const RUST: &str = r##"use std::fmt;
+
+/// A parser
+#[derive(Debug)]
+pub struct Parser<'a> {
+    input: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    pub const LIMIT: usize = 10;
+
+    /// Makes one
+    pub fn new(input: &'a str) -> Self {
+        let brace = '{';
+        let raw = r#"fn fake() { "#;
+        Self { input }
+    }
+
+    fn skip(&mut self)
+    where
+        'a: 'static,
+    {
+        // fn commented() {
+    }
+}
+
+impl fmt::Display for Parser<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "}}")
+    }
+}
+
+pub(crate) async fn run() {}
+"##;
+
+    #[test]
+    fn rust_items_and_members() {
+        let blocks = blocks(RUST, Language::Rust);
+        let symbols: Vec<Option<String>> = blocks.iter().map(|(symbol, _, _)| symbol.clone()).collect();
+        assert_eq!(
+            symbols,
+            some(&["", "Parser", "", "Parser", "Parser::LIMIT", "Parser", "Parser::new", "Parser", "Parser::skip", "Parser", "", "Parser", "Parser::fmt", "Parser", "", "run", ""])
+        );
+        // Doc comments and attributes stay with their item
+        assert!(blocks[1].2.starts_with("/// A parser\n#[derive(Debug)]\npub struct Parser"));
+        assert!(blocks[6].2.starts_with("    /// Makes one\n    pub fn new"));
+        assert!(blocks[6].2.ends_with("Self { input }\n    }"));
+        assert!(blocks[8].2.ends_with("// fn commented() {\n    }"));
+        // Members know the top-level item they belong to
+        assert_eq!(blocks[6].1.as_deref(), Some("Parser"));
+        assert_eq!(blocks[15].2, "pub(crate) async fn run() {}");
+    }
+
+    #[test]
+    fn python_indentation() {
+        let python = "import os\n\n\n@dataclass\nclass Job:\n    \"\"\"A job.\n\ndef not_a_function():\n    \"\"\"\n\n    def run(\n        self,\n    ):\n        return os.getcwd()\n\n    async def wait(self): pass\n\n\ndef main():\n    # def nested\n    Job().run()\n";
+        assert_eq!(symbols(python, Language::Python), some(&["", "Job", "Job.run", "Job", "Job.wait", "", "main", ""]));
+        let blocks = blocks(python, Language::Python);
+        assert!(blocks[1].2.starts_with("@dataclass\nclass Job:"));
+        assert!(blocks[2].2.starts_with("    def run(") && blocks[2].2.ends_with("return os.getcwd()"));
+    }
+
+    #[test]
+    fn typescript_and_go() {
+        let typescript = "import { a } from \"./a\";\n\nexport default class Store {\n  private items: string[] = [];\n\n  @Input()\n  async load(id: string): Promise<void> {\n    if (id) { return; }\n  }\n\n  handle = (event: Event) => {\n    const text = `${event} }`;\n  };\n}\n\nexport const routes = [\n  { path: \"/\" },\n];\n\nfunction* ids() {\n  yield 1;\n}\n";
+        assert_eq!(
+            symbols(typescript, Language::TypeScript),
+            some(&["", "Store", "Store.load", "Store", "Store.handle", "Store", "", "routes", "", "ids", ""])
+        );
+
+        let go = "package main\n\ntype Server struct {\n\tAddr string\n}\n\nfunc (s *Server) Start() error {\n\treturn nil\n}\n\nfunc main() {\n\tfmt.Println(`}`)\n}\n";
+        assert_eq!(symbols(go, Language::Go), some(&["", "Server", "", "Server.Start", "", "main", ""]));
+    }
+
+    #[test]
+    fn java_and_c() {
+        let java = "package app;\n\n@Service\npublic class Billing {\n    private final int rate = compute();\n\n    @Override\n    public String toString() {\n        return \"}\";\n    }\n\n    Billing(int rate) {\n        this.rate = rate;\n    }\n}\n";
+        assert_eq!(symbols(java, Language::Java), some(&["", "Billing", "Billing.toString", "Billing", "Billing.Billing", "Billing", ""]));
+
+        let c = "#include <stdio.h>\n\nint add(int a, int b);\n\n/* Adds */\nstatic int add(int a,\n               int b)\n{\n    return a + b;\n}\n\nstruct point {\n    int x;\n};\n";
+        assert_eq!(symbols(c, Language::C), some(&["", "add", "", "point", ""]));
+        assert_eq!(Language::from_extension("HPP"), Some(Language::Cpp));
+        assert_eq!(Language::from_extension("txt"), None);
+    }
+}
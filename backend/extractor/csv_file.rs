@@ -0,0 +1,124 @@
+use super::{ExtractedText, TextWriter};
+use crate::vdb::error::Error;
+
+/// Rows of a comma separated file, each written as "header: value" lines
+/// under the header of the first non-empty record. Files exported with a
+/// semicolon separator, as spreadsheets do in many locales, are read too.
+pub fn extract_text_from_csv(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    let text = decode(bytes)?;
+    // The separator is whichever of the two the first line uses more
+    let first_line = text.lines().next().unwrap_or_default();
+    let delimiter = match first_line.matches(';').count() > first_line.matches(',').count() {
+        true => ';',
+        false => ',',
+    };
+    Ok(extract_rows(text, delimiter))
+}
+
+/// Rows of a tab separated file, see `extract_text_from_csv`
+pub fn extract_text_from_tsv(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    Ok(extract_rows(decode(bytes)?, '\t'))
+}
+
+fn decode(bytes: &[u8]) -> Result<&str, Error> {
+    let text = std::str::from_utf8(bytes).map_err(|_| Error::FileTypeNotSupported)?;
+    Ok(text.strip_prefix('\u{feff}').unwrap_or(text))
+}
+
+fn extract_rows(text: &str, delimiter: char) -> ExtractedText {
+    let mut writer = TextWriter::default();
+    let mut header: Option<Vec<String>> = None;
+    for (i, record) in records(text, delimiter).into_iter().enumerate() {
+        if record.iter().all(|field| field.trim().is_empty()) {
+            continue;
+        }
+        let Some(header) = &header else {
+            header = Some(record);
+            continue;
+        };
+
+        let names: Vec<String> = (0..record.len())
+            .map(|column| match header.get(column).map(|name| name.trim()) {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => format!("Column {}", column + 1),
+            })
+            .collect();
+        let cells: Vec<(&str, &str)> = names.iter().map(String::as_str).zip(record.iter().map(String::as_str)).collect();
+        // Rows count from 1 like a spreadsheet's, blank lines included
+        writer.row(None, i as u32 + 1, &cells);
+    }
+    writer.finish()
+}
+
+// Records of delimited text, with RFC 4180 quoting: a quoted field may hold
+// the delimiter, line breaks and quotes doubled. Every line break outside
+// quotes ends a record, so blank lines are empty records.
+fn records(text: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            c if quoted => field.push(c),
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            '\r' | '\n' => {
+                if c == '\r' && chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_text_from_csv, extract_text_from_tsv};
+
+    #[test]
+    fn rows_become_header_value_pairs() {
+        let csv = "\u{feff}Account,Amount,,Note\r\n4000,\"1,250.00\",x,\"Said \"\"hi\"\"\nthen left\"\r\n\r\n4100,,,\r\n,,,\n4200,7\n";
+        let extracted = extract_text_from_csv(csv.as_bytes()).unwrap();
+        assert_eq!(
+            extracted.text,
+            "Account: 4000\nAmount: 1,250.00\nColumn 3: x\nNote: Said \"hi\"\nthen left\n\nAccount: 4100\n\nAccount: 4200\nAmount: 7"
+        );
+        // The quoted line break doesn't start a row, the blank line does
+        let rows: Vec<(u32, &str)> = extracted.rows.iter().map(|row| (row.number, &extracted.text[row.start..row.end])).collect();
+        assert_eq!(rows[0].0, 2);
+        assert_eq!(rows[1], (4, "Account: 4100"));
+        assert_eq!(rows[2], (6, "Account: 4200\nAmount: 7"));
+        assert!(rows.iter().all(|(_, text)| !text.is_empty()));
+    }
+
+    #[test]
+    fn delimiters() {
+        let semicolons = extract_text_from_csv(b"Name;Price\nBolt;0,10").unwrap();
+        assert_eq!(semicolons.text, "Name: Bolt\nPrice: 0,10");
+        let tabs = extract_text_from_tsv(b"Name\tPrice\nBolt, large\t0.10").unwrap();
+        assert_eq!(tabs.text, "Name: Bolt, large\nPrice: 0.10");
+        assert_eq!(tabs.rows[0].sheet, None);
+        assert!(extract_text_from_csv(&[0xff, 0xfe]).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use super::archive::Archive;
+use super::xml::{Event, Reader};
+use super::{ExtractedText, TextWriter};
+use crate::vdb::error::Error;
+use std::collections::HashMap;
+
+/// Text of a Word (OOXML) document, one paragraph per `w:p` with headings marked
+pub fn extract_text_from_docx(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    let archive = Archive::new(bytes)?;
+    let document = archive.read_string("word/document.xml")?;
+    // Style ids are localised ("Heading1", "berschrift1", ...), their names are not
+    let headings = match archive.read_string("word/styles.xml") {
+        Ok(styles) => heading_styles(&styles),
+        Err(_) => HashMap::new(),
+    };
+
+    let mut writer = TextWriter::default();
+    // Text boxes nest paragraphs inside paragraphs, the inner ones are written first
+    let mut paragraphs: Vec<(String, Option<u32>)> = vec![];
+    let mut in_text = false;
+    for event in Reader::new(&document) {
+        match event {
+            Event::Start(tag) => match (tag.name, paragraphs.last_mut()) {
+                ("w:p", _) if !tag.empty => paragraphs.push((String::new(), None)),
+                ("w:pStyle", Some((_, heading))) => {
+                    if let Some(level) = tag.attribute("w:val").and_then(|style| headings.get(style.as_ref())) {
+                        *heading = Some(*level);
+                    }
+                }
+                ("w:outlineLvl", Some((_, heading))) => {
+                    if let Some(level) = tag.attribute("w:val").and_then(|level| outline_level(&level)) {
+                        *heading = Some(level);
+                    }
+                }
+                ("w:t", _) => in_text = !tag.empty,
+                // Tab stops of the paragraph properties are `w:tab`s too, but carry a value
+                ("w:tab", Some((text, _))) if tag.attribute("w:val").is_none() => text.push('\t'),
+                ("w:br" | "w:cr", Some((text, _))) => text.push('\n'),
+                _ => {}
+            },
+            Event::End("w:t") => in_text = false,
+            Event::End("w:p") => {
+                if let Some((text, heading)) = paragraphs.pop() {
+                    writer.paragraph(&text, heading);
+                }
+            }
+            Event::Text(content) if in_text => {
+                if let Some((text, _)) = paragraphs.last_mut() {
+                    text.push_str(&content);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(writer.finish())
+}
+
+// Heading level of every paragraph style named "heading N" or "Title", or
+// given an outline level
+fn heading_styles(styles: &str) -> HashMap<String, u32> {
+    let mut headings = HashMap::new();
+    let mut style: Option<(String, Option<u32>)> = None;
+    for event in Reader::new(styles) {
+        match event {
+            Event::Start(tag) => match (tag.name, style.as_mut()) {
+                ("w:style", _) => style = tag.attribute("w:styleId").map(|id| (id.into_owned(), None)),
+                ("w:name", Some((_, level))) => {
+                    let name = tag.attribute("w:val").unwrap_or_default().to_lowercase();
+                    match name.strip_prefix("heading ") {
+                        Some(number) => *level = number.trim().parse().ok().or(*level),
+                        None if name == "title" => *level = Some(1),
+                        None => {}
+                    }
+                }
+                ("w:outlineLvl", Some((_, level))) => {
+                    *level = tag.attribute("w:val").and_then(|value| outline_level(&value)).or(*level);
+                }
+                _ => {}
+            },
+            Event::End("w:style") => {
+                if let Some((id, Some(level))) = style.take() {
+                    headings.insert(id, level);
+                }
+            }
+            _ => {}
+        }
+    }
+    headings
+}
+
+// Outline levels count from 0, level 9 is body text
+fn outline_level(value: &str) -> Option<u32> {
+    match value.parse::<u32>() {
+        Ok(level) if level < 9 => Some(level + 1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_text_from_docx;
+    use crate::extractor::archive::zip;
+    use crate::extractor::extract_text_from_document;
+
+    const STYLES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:styles xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">
+  <w:style w:type="paragraph" w:styleId="berschrift1"><w:name w:val="heading 1"/></w:style>
+  <w:style w:type="paragraph" w:styleId="Custom"><w:name w:val="Custom"/><w:pPr><w:outlineLvl w:val="1"/></w:pPr></w:style>
+  <w:style w:type="paragraph" w:styleId="Normal"><w:name w:val="Normal"/></w:style>
+</w:styles>"#;
+
+    const DOCUMENT: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">
+  <w:body>
+    <w:p><w:pPr><w:pStyle w:val="berschrift1"/></w:pPr><w:r><w:t>Introduction</w:t></w:r></w:p>
+    <w:p>
+      <w:pPr><w:pStyle w:val="Normal"/><w:tabs><w:tab w:val="left" w:pos="720"/></w:tabs></w:pPr>
+      <w:r><w:t xml:space="preserve">Fish &amp; chips, </w:t></w:r><w:r><w:t>split runs.</w:t></w:r>
+      <w:r><w:delText>removed</w:delText><w:tab/><w:t>After tab</w:t><w:br/><w:t>next line</w:t></w:r>
+    </w:p>
+    <w:p/>
+    <w:p><w:pPr><w:pStyle w:val="Custom"/></w:pPr><w:r><w:t>Details</w:t></w:r></w:p>
+    <w:tbl><w:tr><w:tc><w:p><w:r><w:t>Cell text</w:t></w:r></w:p></w:tc></w:tr></w:tbl>
+  </w:body>
+</w:document>"#;
+
+    #[test]
+    fn paragraphs_and_headings() {
+        let bytes = zip(&[("word/document.xml", DOCUMENT), ("word/styles.xml", STYLES)], true);
+        let extracted = extract_text_from_docx(&bytes).unwrap();
+        assert_eq!(
+            extracted.text,
+            "# Introduction\n\nFish & chips, split runs.\tAfter tab\nnext line\n\n## Details\n\nCell text"
+        );
+        assert_eq!(extract_text_from_document(&bytes).unwrap(), extracted);
+
+        // Headings are recorded where their marker starts
+        let headings: Vec<(u32, &str, usize)> = extracted.headings.iter().map(|h| (h.level, h.title.as_str(), h.start)).collect();
+        assert_eq!(headings, vec![(1, "Introduction", 0), (2, "Details", 63)]);
+        assert!(extracted.text[63..].starts_with("## Details"));
+    }
+
+    #[test]
+    fn missing_styles_keep_the_text() {
+        let bytes = zip(&[("word/document.xml", DOCUMENT)], false);
+        let text = extract_text_from_docx(&bytes).unwrap().text;
+        assert!(text.starts_with("Introduction\n\nFish & chips"));
+        assert!(extract_text_from_docx(&zip(&[("content.xml", "<a/>")], false)).is_err());
+    }
+}
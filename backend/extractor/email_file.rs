@@ -0,0 +1,586 @@
+use super::html_file::extract_text_from_html;
+use super::pdf_file::days_from_civil;
+use super::{DocumentInfo, ExtractedText, TextWriter};
+use crate::vdb::error::Error;
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+/// Text of an RFC 822 message as a single record, see `extract_text_from_mbox`
+pub fn extract_text_from_eml(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    // Some clients save the mbox separator line along with the message
+    let bytes = match bytes.starts_with(b"From ") {
+        true => bytes.iter().position(|&b| b == b'\n').map_or(&[][..], |end| &bytes[end + 1..]),
+        false => bytes,
+    };
+    extract_messages(&[bytes.to_vec()])
+}
+
+/// Messages of an mbox file, a record each. A record holds the sender, the
+/// subject and the message's own text, without the replies it quotes, and
+/// keeps the sender, recipients, subject and day it was sent as fields. The
+/// first message's subject, sender and date describe the document.
+pub fn extract_text_from_mbox(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    extract_messages(&split_mbox(bytes))
+}
+
+fn extract_messages(messages: &[Vec<u8>]) -> Result<ExtractedText, Error> {
+    let mut writer = TextWriter::default();
+    let mut info = None;
+    for (i, message) in messages.iter().enumerate() {
+        let message = Part::parse(message);
+        if message.headers.is_empty() {
+            continue;
+        }
+
+        let header = |name: &str| message.header(name).map(decode_header).unwrap_or_default();
+        let (from, subject) = (header("from"), header("subject"));
+        let date = message.header("date").and_then(message_date);
+        let author = display_name(&from);
+        info.get_or_insert_with(|| DocumentInfo {
+            title: (!subject.trim().is_empty()).then(|| subject.trim().to_string()),
+            author: (!author.is_empty()).then(|| author.clone()),
+            creation_date: date.as_ref().map(|(timestamp, _)| *timestamp),
+        });
+
+        let body = strip_quotes(&part_text(&message));
+        if body.is_empty() && subject.trim().is_empty() {
+            continue;
+        }
+        let mut fields = vec![];
+        for key in ["from", "to", "cc"] {
+            fields.extend(addresses(&header(key)).into_iter().map(|address| (key.to_string(), address)));
+        }
+        if !subject.trim().is_empty() {
+            fields.push(("subject".to_string(), subject.trim().to_string()));
+        }
+        let timestamp = date.map(|(timestamp, day)| {
+            fields.push(("date".to_string(), day));
+            timestamp
+        });
+        writer.record(i as u32 + 1, &[("From", &author), ("Subject", &subject), ("", &body)], fields, timestamp);
+    }
+
+    let Some(info) = info else {
+        return Err(Error::FileTypeNotSupported);
+    };
+    let mut extracted = writer.finish();
+    extracted.info = info;
+    Ok(extracted)
+}
+
+// Messages of an mbox, each starting after a `From ` line at the top of the
+// file or after a blank line. Body lines that start with `From ` are escaped
+// as `>From `, the escape is removed.
+fn split_mbox(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    let mut message: Option<Vec<u8>> = None;
+    let mut blank = true;
+    for line in bytes.split(|&b| b == b'\n') {
+        if blank && line.starts_with(b"From ") {
+            messages.extend(message.take());
+            message = Some(vec![]);
+            blank = false;
+            continue;
+        }
+        blank = line.strip_suffix(b"\r").unwrap_or(line).is_empty();
+        let Some(message) = &mut message else {
+            continue;
+        };
+        let quotes = line.iter().take_while(|&&b| b == b'>').count();
+        match quotes > 0 && line[quotes..].starts_with(b"From ") {
+            true => message.extend_from_slice(&line[1..]),
+            false => message.extend_from_slice(line),
+        }
+        message.push(b'\n');
+    }
+    messages.extend(message);
+    messages
+}
+
+// A message or a MIME part: its headers, names lowercased and folded lines
+// joined, and its raw body
+struct Part<'b> {
+    headers: Vec<(String, String)>,
+    body: &'b [u8],
+}
+
+impl<'b> Part<'b> {
+    fn parse(bytes: &'b [u8]) -> Self {
+        let mut headers: Vec<(String, String)> = vec![];
+        let mut at = 0;
+        while at < bytes.len() {
+            let end = bytes[at..].iter().position(|&b| b == b'\n').map_or(bytes.len(), |i| at + i);
+            let line = String::from_utf8_lossy(&bytes[at..end]);
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                at = end + 1;
+                break;
+            }
+            if line.starts_with([' ', '\t']) && !headers.is_empty() {
+                if let Some((_, value)) = headers.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else {
+                // Anything but a header line starts the body early
+                match line.split_once(':') {
+                    Some((name, value)) if !name.is_empty() && !name.contains([' ', '\t']) => {
+                        headers.push((name.to_ascii_lowercase(), value.trim().to_string()))
+                    }
+                    _ => break,
+                }
+            }
+            at = end + 1;
+        }
+        Part {
+            headers,
+            body: &bytes[at.min(bytes.len())..],
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+
+    fn content_type(&self) -> (String, Vec<(String, String)>) {
+        parameters(self.header("content-type").unwrap_or("text/plain"))
+    }
+
+    // The body with its transfer encoding undone, in the part's charset
+    fn decoded(&self) -> String {
+        let encoding = self.header("content-transfer-encoding").unwrap_or_default().trim().to_ascii_lowercase();
+        let bytes = match encoding.as_str() {
+            "base64" => base64(self.body),
+            "quoted-printable" => quoted_printable(self.body, false),
+            _ => self.body.to_vec(),
+        };
+        let (_, parameters) = self.content_type();
+        let charset = parameter(&parameters, "charset").unwrap_or("utf-8");
+        decode_charset(&bytes, charset).replace("\r\n", "\n")
+    }
+}
+
+// Readable text of a part: plain text as is, HTML reduced to its text and
+// multipart bodies from their parts, where alternatives are read as plain
+// text when there is some. Attachments and other media are left out.
+fn part_text(part: &Part) -> String {
+    let (kind, parameters) = part.content_type();
+    let (disposition, _) = parameters_of(part.header("content-disposition"));
+    if disposition == "attachment" {
+        return String::new();
+    }
+
+    if let Some(subtype) = kind.strip_prefix("multipart/") {
+        let Some(boundary) = parameter(&parameters, "boundary") else {
+            return String::new();
+        };
+        let bodies = split_multipart(part.body, boundary);
+        let parts: Vec<Part> = bodies.iter().map(|body| Part::parse(body)).collect();
+        if subtype == "alternative" {
+            let plain = parts.iter().filter(|part| part.content_type().0 == "text/plain");
+            let text = plain.chain(&parts).map(part_text).find(|text| !text.trim().is_empty());
+            return text.unwrap_or_default();
+        }
+        let texts: Vec<String> = parts.iter().map(part_text).filter(|text| !text.trim().is_empty()).collect();
+        return texts.join("\n\n");
+    }
+
+    match kind.as_str() {
+        "text/plain" => part.decoded(),
+        "text/html" => extract_text_from_html(part.decoded().as_bytes()).map(|html| html.text).unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+// Bodies of the parts between the boundary delimiter lines
+fn split_multipart(body: &[u8], boundary: &str) -> Vec<Vec<u8>> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = vec![];
+    let mut lines: Option<Vec<&[u8]>> = None;
+    for line in body.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if let Some(rest) = line.strip_prefix(delimiter.as_bytes()) {
+            parts.extend(lines.take().map(|lines| lines.join(&b'\n')));
+            if rest.starts_with(b"--") {
+                break;
+            }
+            lines = Some(vec![]);
+            continue;
+        }
+        if let Some(lines) = &mut lines {
+            lines.push(line);
+        }
+    }
+    parts.extend(lines.map(|lines| lines.join(&b'\n')));
+    parts
+}
+
+// The lowercased value of a header like Content-Type, before its parameters,
+// and the parameters with their quotes removed
+fn parameters(value: &str) -> (String, Vec<(String, String)>) {
+    let mut parts = value.split(';');
+    let kind = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+    let parameters = parts
+        .filter_map(|part| {
+            let (name, value) = part.split_once('=')?;
+            Some((name.trim().to_ascii_lowercase(), value.trim().trim_matches('"').to_string()))
+        })
+        .collect();
+    (kind, parameters)
+}
+
+fn parameters_of(value: Option<&str>) -> (String, Vec<(String, String)>) {
+    value.map(parameters).unwrap_or_default()
+}
+
+fn parameter<'p>(parameters: &'p [(String, String)], name: &str) -> Option<&'p str> {
+    parameters.iter().find(|(parameter, _)| parameter == name).map(|(_, value)| value.as_str())
+}
+
+fn base64(bytes: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(bytes.len() * 3 / 4);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in bytes {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            // Line breaks and other noise
+            _ => continue,
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    decoded
+}
+
+// Quoted-printable bytes decoded, `underscores` for the variant of encoded
+// header words where `_` stands for a space
+fn quoted_printable(bytes: &[u8], underscores: bool) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'=' => {
+                let rest = &bytes[i + 1..];
+                // A soft line break, added to keep lines short
+                let soft = rest.iter().take_while(|&&b| b == b' ' || b == b'\t' || b == b'\r').count();
+                if rest.get(soft) == Some(&b'\n') {
+                    i += soft + 2;
+                    continue;
+                }
+                let hex = rest.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+                match hex.and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'='),
+                }
+            }
+            b'_' if underscores => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+// Text in a MIME charset, the Latin ones agree with Latin-1 on the letters
+// and anything else is read as UTF-8
+fn decode_charset(bytes: &[u8], charset: &str) -> String {
+    match charset.trim().to_ascii_lowercase().as_str() {
+        "iso-8859-1" | "iso-8859-15" | "latin1" | "windows-1252" | "cp1252" => bytes.iter().map(|&byte| byte as char).collect(),
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+// A header value with its RFC 2047 encoded words, like `=?UTF-8?B?...?=`,
+// decoded. The space between two encoded words is part of the encoding.
+fn decode_header(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let Some((charset, encoding, text, after)) = encoded_word(&rest[start + 2..]) else {
+            break;
+        };
+        let between = &rest[..start];
+        if !(after_word && between.trim().is_empty()) {
+            decoded.push_str(between);
+        }
+        let bytes = match encoding {
+            "B" | "b" => base64(text.as_bytes()),
+            _ => quoted_printable(text.as_bytes(), true),
+        };
+        // A language may follow the charset, as in `UTF-8*en`
+        decoded.push_str(&decode_charset(&bytes, charset.split('*').next().unwrap_or(charset)));
+        rest = after;
+        after_word = true;
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// Charset, encoding and text of an encoded word after its `=?`, and what
+// follows its closing `?=`
+fn encoded_word(word: &str) -> Option<(&str, &str, &str, &str)> {
+    let (charset, rest) = word.split_once('?')?;
+    let (encoding, rest) = rest.split_once('?')?;
+    let (text, after) = rest.split_once("?=")?;
+    matches!(encoding, "B" | "b" | "Q" | "q").then_some((charset, encoding, text, after))
+}
+
+// Lowercased addresses of an address list like `"Doe, Jane" <jane@example.com>, bob@example.com`
+fn addresses(list: &str) -> Vec<String> {
+    let mut addresses = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut angle = false;
+    for c in list.chars().chain([',']) {
+        match c {
+            '"' => quoted = !quoted,
+            '<' if !quoted => angle = true,
+            '>' if !quoted => angle = false,
+            ',' if !quoted && !angle => {
+                let entry = std::mem::take(&mut current);
+                let address = match (entry.find('<'), entry.rfind('>')) {
+                    (Some(open), Some(close)) if open < close => &entry[open + 1..close],
+                    _ => entry.as_str(),
+                };
+                let address = address.trim().to_lowercase();
+                if address.contains('@') {
+                    addresses.push(address);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    addresses
+}
+
+// The name of `Jane Doe <jane@example.com>`, or the address when there's none
+fn display_name(from: &str) -> String {
+    let from = from.trim();
+    match from.split_once('<') {
+        Some((name, address)) => match name.trim().trim_matches('"').trim() {
+            "" => address.trim_end_matches('>').trim().to_string(),
+            name => name.to_string(),
+        },
+        None => from.to_string(),
+    }
+}
+
+// The message's own text: lines quoting earlier messages with `>`, the
+// "On ... wrote:" line introducing them and everything below an "Original
+// Message" separator or an Outlook style header block are left out
+fn strip_quotes(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut kept: Vec<&str> = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('>') {
+            continue;
+        }
+        if trimmed.trim_matches('-').trim().eq_ignore_ascii_case("original message") {
+            break;
+        }
+        let next = lines.get(i + 1).map_or("", |next| next.trim());
+        if trimmed.starts_with("From:") && (next.starts_with("Sent:") || next.starts_with("Date:")) {
+            break;
+        }
+        if trimmed.ends_with("wrote:") {
+            // The attribution may wrap onto a second line
+            if trimmed.starts_with("On ") {
+                continue;
+            }
+            if kept.last().is_some_and(|last| last.trim().starts_with("On ")) {
+                kept.pop();
+                continue;
+            }
+        }
+        kept.push(line);
+    }
+    kept.join("\n").trim().to_string()
+}
+
+// Milliseconds since the epoch and the ISO day of a date like
+// `Mon, 15 Jan 2024 10:30:00 +0100`, the day as the sender saw it
+fn message_date(value: &str) -> Option<(u64, String)> {
+    let value = value.split_once(',').map_or(value, |(_, rest)| rest);
+    let mut words = value.split_whitespace();
+    let day: i64 = words.next()?.parse().ok()?;
+    let month_name = words.next()?.get(..3)?;
+    let month = MONTHS.iter().position(|month| month.eq_ignore_ascii_case(month_name))? as i64 + 1;
+    let year: i64 = match words.next()?.parse().ok()? {
+        year @ 0..=49 => year + 2000,
+        year @ 50..=99 => year + 1900,
+        year => year,
+    };
+    let mut time = words.next()?.split(':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute) = (time.next()??, time.next()??);
+    let second = time.next().flatten().unwrap_or(0);
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Minutes ahead of UTC, a few zones still go by name
+    let offset = match words.next().unwrap_or("+0000") {
+        zone if zone.len() == 5 && zone.starts_with(['+', '-']) => {
+            let (hours, minutes): (i64, i64) = (zone[1..3].parse().ok()?, zone[3..].parse().ok()?);
+            let offset = hours * 60 + minutes;
+            if zone.starts_with('-') {
+                -offset
+            } else {
+                offset
+            }
+        }
+        "EDT" => -4 * 60,
+        "EST" | "CDT" => -5 * 60,
+        "CST" | "MDT" => -6 * 60,
+        "MST" | "PDT" => -7 * 60,
+        "PST" => -8 * 60,
+        _ => 0,
+    };
+
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3600 + (minute - offset) * 60 + second;
+    let timestamp = u64::try_from(seconds).ok()? * 1000;
+    Some((timestamp, format!("{:04}-{:02}-{:02}", year, month, day)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{addresses, decode_header, extract_text_from_eml, extract_text_from_mbox, message_date, strip_quotes};
+    use crate::vdb::error::Error;
+
+    const REPLY: &str = "Return-Path: <jane@example.com>\r
+From: =?UTF-8?Q?Jane_D=C3=B6e?= <Jane@Example.com>\r
+To: \"Support, Team\" <support@example.com>,\r
+ bob@example.com\r
+Subject: =?UTF-8?B?UmU6IFJlZnVuZA==?=\r
+ =?UTF-8?B?IOKCrDQy?=\r
+Date: Mon, 15 Jan 2024 10:30:00 +0100\r
+MIME-Version: 1.0\r
+Content-Type: multipart/mixed; boundary=\"outer\"\r
+\r
+This is a multi-part message in MIME format.\r
+--outer\r
+Content-Type: multipart/alternative; boundary=inner\r
+\r
+--inner\r
+Content-Type: text/plain; charset=utf-8\r
+Content-Transfer-Encoding: quoted-printable\r
+\r
+Thanks, the refund arriv=\r
+ed. Caf=C3=A9 is open again.\r
+\r
+On Sun, 14 Jan 2024 at 09:00, Support <support@example.com>\r
+wrote:\r
+> Your refund is on its way.\r
+>> Where is my refund?\r
+--inner\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<p>HTML copy</p>\r
+--inner--\r
+--outer\r
+Content-Type: text/plain\r
+Content-Disposition: attachment; filename=\"log.txt\"\r
+\r
+attached log\r
+--outer--\r
+";
+
+    #[test]
+    fn message_is_decoded_without_quotes() {
+        let extracted = extract_text_from_eml(REPLY.as_bytes()).unwrap();
+        assert_eq!(
+            extracted.text,
+            "From: Jane Döe\nSubject: Re: Refund €42\nThanks, the refund arrived. Café is open again."
+        );
+
+        let fields: Vec<(&str, &str)> =
+            extracted.records[0].fields.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("from", "jane@example.com"),
+                ("to", "support@example.com"),
+                ("to", "bob@example.com"),
+                ("subject", "Re: Refund €42"),
+                ("date", "2024-01-15"),
+            ]
+        );
+        assert_eq!(extracted.info.title.as_deref(), Some("Re: Refund €42"));
+        assert_eq!(extracted.info.author.as_deref(), Some("Jane Döe"));
+        assert_eq!(extracted.info.creation_date, Some(1_705_311_000_000));
+        assert_eq!(extracted.records[0].date, Some(1_705_311_000_000));
+    }
+
+    #[test]
+    fn mbox_messages_are_records() {
+        let mbox = "From jane@example.com Mon Jan 15 10:30:00 2024\n\
+From: jane@example.com\n\
+Subject: Printer\n\
+Content-Type: text/plain; charset=iso-8859-1\n\
+Content-Transfer-Encoding: base64\n\
+\n\
+UHJpbnRlciBpcyBicm9rZW4uIEdy/N9lLg==\n\
+\n\
+From bob@example.com Tue Jan 16 08:00:00 2024\n\
+From: Bob <bob@example.com>\n\
+Subject: Re: Printer\n\
+\n\
+Fixed.\n\
+>From now on use tray 2.\n\
+\n\
+-----Original Message-----\n\
+From: jane@example.com\n\
+Printer is broken.\n\
+\n\
+From carol@example.com Tue Jan 16 09:00:00 2024\n\
+From: carol@example.com\n\
+Content-Type: text/html; charset=utf-8\n\
+\n\
+<html><body><p>Thanks!</p></body></html>\n";
+        let extracted = extract_text_from_mbox(mbox.as_bytes()).unwrap();
+        assert_eq!(
+            extracted.text,
+            "From: jane@example.com\nSubject: Printer\nPrinter is broken. Grüße.\n\nFrom: Bob\nSubject: Re: Printer\nFixed.\nFrom now on use tray 2.\n\nFrom: carol@example.com\nThanks!"
+        );
+        let numbers: Vec<u32> = extracted.records.iter().map(|record| record.number).collect();
+        assert_eq!(numbers, vec![1, 2, 3]);
+        assert_eq!(extracted.records[1].fields[0], ("from".to_string(), "bob@example.com".to_string()));
+        assert_eq!(extracted.info.title.as_deref(), Some("Printer"));
+        assert_eq!(extracted.info.creation_date, None);
+
+        assert_eq!(extract_text_from_mbox(b"no messages here"), Err(Error::FileTypeNotSupported));
+    }
+
+    #[test]
+    fn headers_addresses_and_dates() {
+        assert_eq!(decode_header("=?ISO-8859-1?Q?Gr=FC=DFe?= aus =?utf-8?q?K=C3=B6ln?="), "Grüße aus Köln");
+        assert_eq!(decode_header("Plain =?x"), "Plain =?x");
+        assert_eq!(
+            addresses("\"Doe, Jane\" <Jane@Example.com>, bob@example.com, undisclosed-recipients:;"),
+            vec!["jane@example.com", "bob@example.com"]
+        );
+        assert_eq!(message_date("15 Jan 24 10:30 GMT"), Some((1_705_314_600_000, "2024-01-15".to_string())));
+        assert_eq!(message_date("Thu, 1 Jan 1970 00:00:00 EST").map(|(timestamp, _)| timestamp), Some(18_000_000));
+        assert_eq!(message_date("Fri, 32 Jan 2024 10:00:00 +0000"), None);
+        assert_eq!(message_date("tomorrow"), None);
+        assert_eq!(strip_quotes("Yes.\n\nOn Mon, Jane wrote:\n> Ok?"), "Yes.");
+    }
+}
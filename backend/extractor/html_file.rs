@@ -0,0 +1,187 @@
+use super::xml::{Event, Reader};
+use super::{ExtractedText, TextWriter};
+use crate::vdb::error::Error;
+
+/// Elements whose contents are never shown as text
+const SKIPPED: [&str; 6] = ["head", "noscript", "template", "svg", "iframe", "object"];
+
+/// Elements holding raw text that can't be read as markup
+const RAW: [&str; 2] = ["script", "style"];
+
+/// Elements that start a new paragraph of their own
+const BLOCKS: [&str; 25] = [
+    "address", "article", "aside", "blockquote", "body", "caption", "dd", "div", "dl", "dt", "fieldset", "figcaption",
+    "figure", "footer", "form", "header", "hr", "html", "li", "main", "nav", "ol", "p", "section", "ul",
+];
+
+/// Text of an HTML page. Scripts and styles are dropped, every block becomes
+/// a paragraph, list items keep a bullet and table rows are written one per
+/// line with their cells separated by `|`. `h1` to `h6` are the headings.
+pub fn extract_text_from_html(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    let html = std::str::from_utf8(bytes).map_err(|_| Error::FileTypeNotSupported)?;
+
+    let mut page = Page::default();
+    let mut reader = Reader::new(html);
+    while let Some(event) = reader.next() {
+        match event {
+            Event::Start(tag) => {
+                let name = tag.name.to_ascii_lowercase();
+                if RAW.contains(&name.as_str()) {
+                    if !tag.empty {
+                        reader.skip_raw(&name);
+                    }
+                } else if SKIPPED.contains(&name.as_str()) {
+                    if !tag.empty {
+                        page.skipped += 1;
+                    }
+                } else if page.skipped == 0 {
+                    page.start(&name);
+                }
+            }
+            Event::End(name) => {
+                let name = name.to_ascii_lowercase();
+                if SKIPPED.contains(&name.as_str()) {
+                    page.skipped = page.skipped.saturating_sub(1);
+                } else if page.skipped == 0 {
+                    page.end(&name);
+                }
+            }
+            Event::Text(text) if page.skipped == 0 => page.text(&text),
+            Event::Text(_) => {}
+        }
+    }
+    page.flush();
+
+    Ok(page.writer.finish())
+}
+
+#[derive(Default)]
+struct Page {
+    writer: TextWriter,
+    paragraph: String,
+    heading: Option<u32>,
+    // Depth of the skipped, preformatted and table elements around the text
+    skipped: usize,
+    pre: usize,
+    tables: usize,
+    // Whether the next cell is the first of its row
+    first_cell: bool,
+}
+
+impl Page {
+    fn start(&mut self, name: &str) {
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.flush();
+                self.heading = name[1..].parse().ok();
+            }
+            "br" => self.separate("\n"),
+            "pre" => {
+                self.flush();
+                self.pre += 1;
+            }
+            "table" => {
+                self.flush();
+                self.tables += 1;
+            }
+            "tr" => {
+                if !self.paragraph.is_empty() {
+                    self.separate("\n");
+                }
+                self.first_cell = true;
+            }
+            "td" | "th" => {
+                if !self.first_cell {
+                    self.separate(" | ");
+                }
+                self.first_cell = false;
+            }
+            "li" if self.tables == 0 => {
+                self.flush();
+                self.paragraph.push_str("- ");
+            }
+            name if BLOCKS.contains(&name) && self.tables == 0 => self.flush(),
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, name: &str) {
+        match name {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => self.flush(),
+            "pre" => {
+                self.flush();
+                self.pre = self.pre.saturating_sub(1);
+            }
+            "table" => {
+                self.flush();
+                self.tables = self.tables.saturating_sub(1);
+            }
+            name if BLOCKS.contains(&name) && self.tables == 0 => self.flush(),
+            _ => {}
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.pre > 0 {
+            self.paragraph.push_str(text);
+            return;
+        }
+        // Whitespace in the markup collapses to one space, and none at the start of a line
+        for c in text.chars() {
+            match c {
+                ' ' | '\t' | '\r' | '\n' if self.paragraph.is_empty() || self.paragraph.ends_with([' ', '\n']) => {}
+                ' ' | '\t' | '\r' | '\n' => self.paragraph.push(' '),
+                c => self.paragraph.push(c),
+            }
+        }
+    }
+
+    // Put `separator` after the text so far, without the spaces that trail it
+    fn separate(&mut self, separator: &str) {
+        let len = self.paragraph.trim_end_matches(' ').len();
+        self.paragraph.truncate(len);
+        self.paragraph.push_str(separator);
+    }
+
+    fn flush(&mut self) {
+        self.writer.paragraph(&self.paragraph, self.heading.take());
+        self.paragraph.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_text_from_html;
+
+    const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Ignored</title><style>p { color: red; }</style></head>
+<body>
+  <script>if (a < b) { document.write("</p>hidden"); }</script>
+  <H1>Guide</H1>
+  <p>Intro text with <b>bold</b>
+     and a&nbsp;link.<br>Second line.
+  <h2 class="x">Parts</h2>
+  <ul><li>First item</li><li>Second <i>item</i></ul>
+  <table>
+    <tr><th>Name</th><th>Price</th></tr>
+    <tr><td> Bolt </td><td><p>0.10</p></td></tr>
+  </table>
+  <pre>keep   this
+  layout</pre>
+  <noscript>Enable scripts</noscript>
+</body>
+</html>"#;
+
+    #[test]
+    fn blocks_lists_and_tables() {
+        let extracted = extract_text_from_html(PAGE.as_bytes()).unwrap();
+        assert_eq!(
+            extracted.text,
+            "# Guide\n\nIntro text with bold and a\u{a0}link.\nSecond line.\n\n## Parts\n\n- First item\n\n- Second item\n\nName | Price\nBolt | 0.10\n\nkeep   this\n  layout"
+        );
+        let titles: Vec<(u32, &str)> = extracted.headings.iter().map(|h| (h.level, h.title.as_str())).collect();
+        assert_eq!(titles, vec![(1, "Guide"), (2, "Parts")]);
+        assert!(extract_text_from_html(&[0xff, 0xfe]).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use super::{ExtractedText, TextWriter};
+use crate::vdb::error::Error;
+use serde_json::Value;
+
+/// Records of a JSON file: the elements of a top-level array, or the whole
+/// document otherwise. See `write_record` for how a record is split into
+/// text and fields.
+pub fn extract_text_from_json(bytes: &[u8], text_paths: &[String]) -> Result<ExtractedText, Error> {
+    let value: Value = serde_json::from_str(decode(bytes)?).map_err(|_| Error::FileTypeNotSupported)?;
+    let records = match value {
+        Value::Array(records) => records,
+        record => vec![record],
+    };
+
+    let mut writer = TextWriter::default();
+    for (i, record) in records.iter().enumerate() {
+        write_record(&mut writer, i as u32 + 1, record, text_paths);
+    }
+    Ok(writer.finish())
+}
+
+/// Records of a JSON Lines file, one per non-blank line and numbered by it
+pub fn extract_text_from_jsonl(bytes: &[u8], text_paths: &[String]) -> Result<ExtractedText, Error> {
+    let mut writer = TextWriter::default();
+    for (i, line) in decode(bytes)?.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Value = serde_json::from_str(line).map_err(|_| Error::FileTypeNotSupported)?;
+        write_record(&mut writer, i as u32 + 1, &record, text_paths);
+    }
+    Ok(writer.finish())
+}
+
+fn decode(bytes: &[u8]) -> Result<&str, Error> {
+    let text = std::str::from_utf8(bytes).map_err(|_| Error::FileTypeNotSupported)?;
+    Ok(text.strip_prefix('\u{feff}').unwrap_or(text))
+}
+
+// The values under `text_paths` become the record's text, in the order of
+// the paths, and every other value one of its fields. Without paths all
+// the strings are text. Paths are keys joined by dots and look through
+// arrays, `comments.body` is the body of every comment.
+fn write_record(writer: &mut TextWriter, number: u32, record: &Value, text_paths: &[String]) {
+    let mut leaves = vec![];
+    flatten(record, "", &mut leaves);
+
+    let mut text: Vec<(&str, String)> = vec![];
+    let mut fields: Vec<(String, String)> = vec![];
+    match text_paths.is_empty() {
+        true => {
+            for (path, value) in &leaves {
+                match value {
+                    Value::String(string) => text.push((path, string.clone())),
+                    value => fields.push((path.clone(), scalar(value))),
+                }
+            }
+        }
+        false => {
+            for text_path in text_paths {
+                let text_path = text_path.trim();
+                text.extend(leaves.iter().filter(|(path, _)| is_under(path, text_path)).map(|(path, value)| (path.as_str(), scalar(value))));
+            }
+            fields.extend(
+                leaves
+                    .iter()
+                    .filter(|(path, _)| !text_paths.iter().any(|text_path| is_under(path, text_path.trim())))
+                    .map(|(path, value)| (path.clone(), scalar(value))),
+            );
+        }
+    }
+
+    let text: Vec<(&str, &str)> = text.iter().map(|(path, value)| (*path, value.as_str())).collect();
+    writer.record(number, &text, fields, None);
+}
+
+// Every non-null scalar of `value` with the dotted path of keys leading to
+// it, a record that is a bare scalar is called "value"
+fn flatten<'v>(value: &'v Value, path: &str, leaves: &mut Vec<(String, &'v Value)>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let path = match path {
+                    "" => key.clone(),
+                    path => format!("{}.{}", path, key),
+                };
+                flatten(value, &path, leaves);
+            }
+        }
+        Value::Array(values) => values.iter().for_each(|value| flatten(value, path, leaves)),
+        Value::Null => {}
+        value => leaves.push((if path.is_empty() { "value" } else { path }.to_string(), value)),
+    }
+}
+
+fn is_under(path: &str, parent: &str) -> bool {
+    path.strip_prefix(parent).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_text_from_json, extract_text_from_jsonl};
+
+    const TICKETS: &str = r#"[
+        {"id": 17, "title": "Login fails", "body": {"text": "Password reset loops."}, "tags": ["auth", "web"], "open": true, "owner": null},
+        {"id": 18, "title": "", "body": {"text": ""}, "tags": []},
+        {"id": 19, "title": "Export", "comments": [{"body": "Works now"}, {"body": "Thanks"}], "priority": "low"}
+    ]"#;
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    #[test]
+    fn configured_paths_are_text() {
+        let extracted = extract_text_from_json(TICKETS.as_bytes(), &paths(&["title", "body.text", "comments"])).unwrap();
+        assert_eq!(
+            extracted.text,
+            "title: Login fails\nbody.text: Password reset loops.\n\ntitle: Export\ncomments.body: Works now\ncomments.body: Thanks"
+        );
+
+        // The record without text is left out, the others keep their position
+        let records = &extracted.records;
+        let fields = |i: usize| -> Vec<(&str, &str)> {
+            records[i].fields.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect()
+        };
+        assert_eq!(records.iter().map(|record| record.number).collect::<Vec<u32>>(), vec![1, 3]);
+        assert_eq!(fields(0), vec![("id", "17"), ("open", "true"), ("tags", "auth"), ("tags", "web")]);
+        assert!(extracted.text[records[1].start..records[1].end].starts_with("title: Export"));
+        assert_eq!(fields(1), vec![("id", "19"), ("priority", "low")]);
+    }
+
+    #[test]
+    fn strings_are_text_without_paths() {
+        let jsonl = "\u{feff}{\"q\": \"How do I pay?\", \"votes\": 3}\n\n\"Bare answer\"\n{\"q\": \"Refunds?\", \"a\": \"Within 30 days\"}\n";
+        let extracted = extract_text_from_jsonl(jsonl.as_bytes(), &[]).unwrap();
+        assert_eq!(extracted.text, "q: How do I pay?\n\nvalue: Bare answer\n\na: Within 30 days\nq: Refunds?");
+        let numbers: Vec<u32> = extracted.records.iter().map(|record| record.number).collect();
+        assert_eq!(numbers, vec![1, 3, 4]);
+        assert_eq!(extracted.records[0].fields, vec![("votes".to_string(), "3".to_string())]);
+
+        assert!(extract_text_from_jsonl(b"{\"a\": 1}\n{broken", &[]).is_err());
+        assert!(extract_text_from_json(b"[1, 2", &[]).is_err());
+    }
+}
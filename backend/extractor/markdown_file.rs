@@ -0,0 +1,120 @@
+use super::{ExtractedText, TextWriter};
+use crate::vdb::error::Error;
+
+/// Text of a Markdown document. Blocks stay as written apart from the
+/// headings, ATX (`## Title`) and setext (underlined) ones, which are
+/// normalised to the ATX form and recorded. Fenced code is kept verbatim.
+pub fn extract_text_from_markdown(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    let markdown = std::str::from_utf8(bytes).map_err(|_| Error::FileTypeNotSupported)?;
+
+    let mut writer = TextWriter::default();
+    let mut block: Vec<&str> = vec![];
+    let mut fence: Option<&str> = None;
+    for line in markdown.lines() {
+        let trimmed = line.trim();
+        if let Some(marker) = fence {
+            block.push(line);
+            if trimmed.starts_with(marker) {
+                fence = None;
+            }
+            continue;
+        }
+
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+            block.push(line);
+        } else if trimmed.is_empty() {
+            writer.paragraph(&block.join("\n"), None);
+            block.clear();
+        } else if let Some((level, title)) = atx_heading(line) {
+            writer.paragraph(&block.join("\n"), None);
+            block.clear();
+            writer.paragraph(title, Some(level));
+        } else if let Some(level) = setext_underline(trimmed) {
+            // Underlines the paragraph above, a lone `---` is a thematic break
+            if !block.is_empty() {
+                writer.paragraph(&block.join(" "), Some(level));
+                block.clear();
+            } else if level == 1 {
+                block.push(line);
+            }
+        } else {
+            block.push(line);
+        }
+    }
+    writer.paragraph(&block.join("\n"), None);
+
+    Ok(writer.finish())
+}
+
+// Level and title of a `#` heading line
+fn atx_heading(line: &str) -> Option<(u32, &str)> {
+    // Indented by four spaces or more it is code
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return None;
+    }
+    let line = line.trim();
+    let level = line.len() - line.trim_start_matches('#').len();
+    let rest = &line[level..];
+    if level == 0 || level > 6 || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+
+    // An optional closing sequence of `#`s is not part of the title
+    let title = rest.trim();
+    let title = match title.trim_end_matches('#') {
+        stripped if stripped.is_empty() || stripped.ends_with([' ', '\t']) => stripped.trim_end(),
+        _ => title,
+    };
+    Some((level as u32, title))
+}
+
+fn setext_underline(trimmed: &str) -> Option<u32> {
+    if !trimmed.is_empty() && trimmed.chars().all(|c| c == '=') {
+        Some(1)
+    } else if !trimmed.is_empty() && trimmed.chars().all(|c| c == '-') {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_text_from_markdown;
+
+    const DOCUMENT: &str = "Handbook
+========
+
+Some *intro* text
+over two lines.
+
+## Setup ##
+Install it first.
+
+```sh
+# not a heading
+
+cargo build
+```
+
+Usage
+-----
+
+---
+
+#hashtag is text
+### Advanced";
+
+    #[test]
+    fn headings_are_recorded() {
+        let extracted = extract_text_from_markdown(DOCUMENT.as_bytes()).unwrap();
+        assert_eq!(
+            extracted.text,
+            "# Handbook\n\nSome *intro* text\nover two lines.\n\n## Setup\n\nInstall it first.\n\n```sh\n# not a heading\n\ncargo build\n```\n\n## Usage\n\n#hashtag is text\n\n### Advanced"
+        );
+        let titles: Vec<(u32, &str)> = extracted.headings.iter().map(|h| (h.level, h.title.as_str())).collect();
+        assert_eq!(titles, vec![(1, "Handbook"), (2, "Setup"), (2, "Usage"), (3, "Advanced")]);
+    }
+}
@@ -1 +1,217 @@
-pub mod pdf_file;
\ No newline at end of file
+pub mod archive;
+pub mod code_file;
+pub mod csv_file;
+pub mod docx_file;
+pub mod email_file;
+pub mod html_file;
+pub mod json_file;
+pub mod markdown_file;
+pub mod odt_file;
+pub mod pdf_file;
+pub mod rtf_file;
+pub mod xlsx_file;
+pub mod xml;
+
+use crate::vdb::error::Error;
+use archive::Archive;
+use docx_file::extract_text_from_docx;
+use odt_file::extract_text_from_odt;
+use rtf_file::extract_text_from_rtf;
+
+/// Terminates every page of extracted text, used by the chunkers to split and number pages
+pub const PAGE_BREAK: char = '\x0c';
+
+/// A section heading of an extracted document
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heading {
+    // 1 for the top level
+    pub level: u32,
+    pub title: String,
+    // Character offset of the heading in the extracted text
+    pub start: usize,
+}
+
+/// A table row of an extracted spreadsheet
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    pub sheet: Option<String>,
+    // 1-based row number as the spreadsheet shows it, the header is usually row 1
+    pub number: u32,
+    // Character range of the row in the extracted text
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A record of a structured data file, such as a JSON object, that is
+/// embedded on its own
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    // 1-based position of the record in its file, the line number for JSON Lines
+    pub number: u32,
+    // Character range of the record's text in the extracted text
+    pub start: usize,
+    pub end: usize,
+    // The record's fields that weren't written as text, as key and value
+    pub fields: Vec<(String, String)>,
+    // When the record was written, such as an email's date, in milliseconds since the epoch
+    pub date: Option<u64>,
+}
+
+/// A block of a source file: a function, a class or its header, or the
+/// lines between them
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeBlock {
+    // Name of the item qualified by its containers, like `Parser::new`, the
+    // container's name for the lines around its members and `None` between
+    // top-level items
+    pub symbol: Option<String>,
+    // Name of the top-level item the block is part of
+    pub item: Option<String>,
+    // Character range of the block in the extracted text
+    pub start: usize,
+    pub end: usize,
+}
+
+/// What a document's own metadata says about it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DocumentInfo {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    // Milliseconds since the epoch
+    pub creation_date: Option<u64>,
+}
+
+/// Text of a document together with its outline, its rows for tabular
+/// formats, its records for structured data or its blocks for source code,
+/// all empty for unstructured formats
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExtractedText {
+    pub text: String,
+    pub headings: Vec<Heading>,
+    pub rows: Vec<Row>,
+    pub records: Vec<Record>,
+    pub blocks: Vec<CodeBlock>,
+    // Programming language of source code
+    pub language: Option<String>,
+    pub info: DocumentInfo,
+    // 1-based pages whose text couldn't be read, they are left empty
+    pub failed_pages: Vec<u32>,
+}
+
+impl From<String> for ExtractedText {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            ..Default::default()
+        }
+    }
+}
+
+/// Extracted text built up one paragraph at a time. Paragraphs are separated
+/// by a blank line, a boundary the sentence chunker never packs across, and
+/// headings are marked Markdown style so chunks keep the document's outline.
+#[derive(Default)]
+pub struct TextWriter {
+    text: String,
+    headings: Vec<Heading>,
+    rows: Vec<Row>,
+    records: Vec<Record>,
+    // Length of `text` in characters, the unit of chunk offsets
+    chars: usize,
+}
+
+impl TextWriter {
+    pub fn paragraph(&mut self, text: &str, heading: Option<u32>) {
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        if !self.text.is_empty() {
+            self.push("\n\n");
+        }
+        if let Some(level) = heading {
+            let level = level.clamp(1, 6);
+            self.headings.push(Heading {
+                level,
+                title: text.split_whitespace().collect::<Vec<&str>>().join(" "),
+                start: self.chars,
+            });
+            self.push(&"#".repeat(level as usize));
+            self.push(" ");
+        }
+        self.push(text);
+    }
+
+    /// A table row, written as one "header: value" line per cell that has a value
+    pub fn row(&mut self, sheet: Option<&str>, number: u32, cells: &[(&str, &str)]) {
+        if let Some((start, end)) = self.pairs(cells) {
+            self.rows.push(Row {
+                sheet: sheet.map(str::to_string),
+                number,
+                start,
+                end,
+            });
+        }
+    }
+
+    /// A structured record, its text written like a row's cells and its
+    /// other fields kept aside. Records without any text are left out.
+    pub fn record(&mut self, number: u32, text: &[(&str, &str)], fields: Vec<(String, String)>, date: Option<u64>) {
+        if let Some((start, end)) = self.pairs(text) {
+            self.records.push(Record { number, start, end, fields, date });
+        }
+    }
+
+    // "key: value" lines of the pairs that have a value, just the value for
+    // an empty key, and their range
+    fn pairs(&mut self, pairs: &[(&str, &str)]) -> Option<(usize, usize)> {
+        let lines: Vec<String> = pairs
+            .iter()
+            .filter(|(_, value)| !value.trim().is_empty())
+            .map(|(key, value)| match key.trim() {
+                "" => value.trim().to_string(),
+                key => format!("{}: {}", key, value.trim()),
+            })
+            .collect();
+        if lines.is_empty() {
+            return None;
+        }
+        if !self.text.is_empty() {
+            self.push("\n\n");
+        }
+        let start = self.chars;
+        self.push(&lines.join("\n"));
+        Some((start, self.chars))
+    }
+
+    fn push(&mut self, text: &str) {
+        self.text.push_str(text);
+        self.chars += text.chars().count();
+    }
+
+    pub fn finish(self) -> ExtractedText {
+        ExtractedText {
+            text: self.text,
+            headings: self.headings,
+            rows: self.rows,
+            records: self.records,
+            ..Default::default()
+        }
+    }
+}
+
+/// Text of a word processing document, the format is told by its contents:
+/// RTF, or a zip container holding a Word or OpenDocument body
+pub fn extract_text_from_document(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    if bytes.starts_with(b"{\\rtf") {
+        return extract_text_from_rtf(bytes);
+    }
+    let archive = Archive::new(bytes)?;
+    if archive.contains("word/document.xml") {
+        extract_text_from_docx(bytes)
+    } else if archive.contains("content.xml") {
+        extract_text_from_odt(bytes)
+    } else {
+        Err(Error::FileTypeNotSupported)
+    }
+}
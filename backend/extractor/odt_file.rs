@@ -0,0 +1,98 @@
+use super::archive::Archive;
+use super::xml::{Event, Reader};
+use super::{ExtractedText, TextWriter};
+use crate::vdb::error::Error;
+
+/// Elements holding text that isn't part of the document's flow
+const SKIPPED: [&str; 3] = ["text:note-citation", "office:annotation", "text:tracked-changes"];
+
+/// Text of an OpenDocument text file, one paragraph per `text:p` and `text:h`
+/// with headings marked
+pub fn extract_text_from_odt(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    let archive = Archive::new(bytes)?;
+    let content = archive.read_string("content.xml")?;
+
+    let mut writer = TextWriter::default();
+    // Notes nest paragraphs inside paragraphs, the inner ones are written first
+    let mut paragraphs: Vec<(String, Option<u32>)> = vec![];
+    let mut skipped: usize = 0;
+    for event in Reader::new(&content) {
+        match event {
+            Event::Start(tag) if SKIPPED.contains(&tag.name) => {
+                if !tag.empty {
+                    skipped += 1;
+                }
+            }
+            Event::End(name) if SKIPPED.contains(&name) => skipped = skipped.saturating_sub(1),
+            _ if skipped > 0 => {}
+            Event::Start(tag) => match (tag.name, paragraphs.last_mut()) {
+                ("text:p", _) if !tag.empty => paragraphs.push((String::new(), None)),
+                ("text:h", _) if !tag.empty => {
+                    let level = tag.attribute("text:outline-level").and_then(|level| level.parse().ok()).unwrap_or(1);
+                    paragraphs.push((String::new(), Some(level)));
+                }
+                ("text:s", Some((text, _))) => {
+                    let count: usize = tag.attribute("text:c").and_then(|count| count.parse().ok()).unwrap_or(1);
+                    text.push_str(&" ".repeat(count));
+                }
+                ("text:tab", Some((text, _))) => text.push('\t'),
+                ("text:line-break", Some((text, _))) => text.push('\n'),
+                _ => {}
+            },
+            Event::End("text:p" | "text:h") => {
+                if let Some((text, heading)) = paragraphs.pop() {
+                    writer.paragraph(&text, heading);
+                }
+            }
+            Event::Text(content) => {
+                if let Some((text, _)) = paragraphs.last_mut() {
+                    // Whitespace in the markup collapses to one space, `text:s` spells out more
+                    for c in content.chars() {
+                        match c {
+                            ' ' | '\t' | '\r' | '\n' if text.ends_with(' ') => {}
+                            ' ' | '\t' | '\r' | '\n' => text.push(' '),
+                            c => text.push(c),
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(writer.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_text_from_odt;
+    use crate::extractor::archive::zip;
+    use crate::extractor::extract_text_from_document;
+
+    const CONTENT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<office:document-content xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0">
+  <office:automatic-styles><style:style style:name="P1"/></office:automatic-styles>
+  <office:body>
+    <office:text>
+      <text:h text:style-name="Heading_20_1" text:outline-level="1">Overview</text:h>
+      <text:p>Spread
+        over lines, <text:span text:style-name="T1">styled</text:span> and<text:s text:c="2"/>spaced.<text:note text:note-class="footnote"><text:note-citation>1</text:note-citation><text:note-body><text:p>A footnote.</text:p></text:note-body></text:note></text:p>
+      <text:p/>
+      <text:h text:outline-level="2">Next</text:h>
+      <text:list><text:list-item><text:p>Item<text:tab/>one<text:line-break/>wrapped</text:p></text:list-item></text:list>
+      <office:annotation><text:p>Reviewer comment</text:p></office:annotation>
+    </office:text>
+  </office:body>
+</office:document-content>"#;
+
+    #[test]
+    fn paragraphs_and_headings() {
+        let bytes = zip(&[("mimetype", "application/vnd.oasis.opendocument.text"), ("content.xml", CONTENT)], true);
+        let text = extract_text_from_odt(&bytes).unwrap().text;
+        assert_eq!(
+            text,
+            "# Overview\n\nA footnote.\n\nSpread over lines, styled and  spaced.\n\n## Next\n\nItem\tone\nwrapped"
+        );
+        assert_eq!(extract_text_from_document(&bytes).unwrap().text, text);
+    }
+}
@@ -1,18 +1,229 @@
-use lopdf::Document;
+use lopdf::{Dictionary, Document, Object};
 use crate::vdb::error::Error;
+use super::{DocumentInfo, ExtractedText, PAGE_BREAK};
 
-pub fn extract_text_from_pdf(pdf_bytes: &[u8]) -> Result<String, Error> {
-    let doc = match Document::load_mem(pdf_bytes) {
+/// Text of a PDF, every page terminated by a page break. A page whose text
+/// can't be read is left empty and listed in `failed_pages`, so the others
+/// keep their numbers. Title, author and creation date come from the Info
+/// dictionary.
+pub fn extract_text_from_pdf(pdf_bytes: &[u8]) -> Result<ExtractedText, Error> {
+    let mut doc = match Document::load_mem(pdf_bytes) {
         Ok(doc) => doc,
         Err(_) => return Err(Error::FileTypeNotSupported),
     };
+    // Documents only protected against editing open with the empty password
+    if doc.is_encrypted() && doc.decrypt("").is_err() {
+        return Err(Error::EncryptedDocument);
+    }
+
+    let mut extracted = ExtractedText {
+        info: document_info(&doc),
+        ..Default::default()
+    };
+    let mut images = false;
+    for (number, page_id) in doc.get_pages() {
+        match doc.extract_text(&[number]) {
+            Ok(text) => extracted.text.push_str(&text),
+            Err(_) => extracted.failed_pages.push(number),
+        }
+        extracted.text.push(PAGE_BREAK);
+        images |= doc.get_page_images(page_id).is_ok_and(|found| !found.is_empty());
+    }
+
+    // Scanned pages are images, there's nothing to read without OCR
+    if images && extracted.failed_pages.is_empty() && extracted.text.trim().is_empty() {
+        return Err(Error::NoTextLayer);
+    }
+    Ok(extracted)
+}
+
+fn document_info(doc: &Document) -> DocumentInfo {
+    let info = match doc.trailer.get(b"Info") {
+        Ok(Object::Reference(id)) => doc.get_dictionary(*id).ok(),
+        Ok(Object::Dictionary(info)) => Some(info),
+        _ => None,
+    };
+    let Some(info) = info else {
+        return DocumentInfo::default();
+    };
+
+    DocumentInfo {
+        title: info_string(info, b"Title"),
+        author: info_string(info, b"Author"),
+        creation_date: info_string(info, b"CreationDate").and_then(|date| pdf_date(&date)),
+    }
+}
+
+fn info_string(info: &Dictionary, key: &[u8]) -> Option<String> {
+    let bytes = info.get(key).and_then(Object::as_str).ok()?;
+    let text = text_string(bytes);
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+// PDF text strings are UTF-16 or UTF-8 behind a byte order mark, or else
+// PDFDocEncoding, which agrees with Latin-1 on the letters
+fn text_string(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        let units: Vec<u16> = utf16.as_chunks::<2>().0.iter().map(|pair| u16::from_be_bytes(*pair)).collect();
+        return String::from_utf16_lossy(&units);
+    }
+    if let Some(utf8) = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]) {
+        return String::from_utf8_lossy(utf8).into_owned();
+    }
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+// Milliseconds since the epoch of a date like `D:20240115103000+01'00'`,
+// everything after the year is optional and the time zone defaults to UTC
+fn pdf_date(date: &str) -> Option<u64> {
+    let date = date.trim();
+    let date = date.strip_prefix("D:").unwrap_or(date);
+    let digits = date.bytes().take_while(u8::is_ascii_digit).count();
+    if digits < 4 {
+        return None;
+    }
+    let field = |at: usize, default: i64| match at + 2 <= digits {
+        true => date[at..at + 2].parse::<i64>().ok(),
+        false => Some(default),
+    };
+    let year: i64 = date[..4].parse().ok()?;
+    let (month, day) = (field(4, 1)?, field(6, 1)?);
+    let (hour, minute, second) = (field(8, 0)?, field(10, 0)?, field(12, 0)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    // `+HH'mm'` ahead of UTC, `-HH'mm'` behind it
+    let zone = &date[digits..];
+    let offset = match zone.chars().next() {
+        Some(sign @ ('+' | '-')) => {
+            let mut parts = zone[1..].split(|c: char| !c.is_ascii_digit()).filter(|part| !part.is_empty());
+            let hours: i64 = parts.next().and_then(|part| part.parse().ok()).unwrap_or(0);
+            let minutes: i64 = parts.next().and_then(|part| part.parse().ok()).unwrap_or(0);
+            let offset = hours * 60 + minutes;
+            if sign == '-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => 0,
+    };
+
+    let seconds = days_from_civil(year, month, day) * 86_400 + hour * 3600 + (minute - offset) * 60 + second;
+    u64::try_from(seconds).ok().map(|seconds| seconds * 1000)
+}
+
+// Days from 1970-01-01 to a date of the proleptic Gregorian calendar
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Years start in March here, so the leap day ends them
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_text_from_pdf, pdf_date, text_string};
+    use crate::extractor::PAGE_BREAK;
+    use crate::vdb::error::Error;
+    use lopdf::{dictionary, Document, Object, Stream};
 
-    let mut full_text = String::new();
+    // A PDF of one page per content stream, sharing a Courier font and, when
+    // any page draws it, an image
+    fn pdf(pages: &[&str], info: Option<Object>, encrypt: bool) -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Courier" });
+        let image_id = doc.add_object(Stream::new(
+            dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => 1, "Height" => 1, "ColorSpace" => "DeviceGray", "BitsPerComponent" => 8 },
+            vec![0],
+        ));
+        let mut resources = dictionary! { "Font" => dictionary! { "F1" => font_id } };
+        if pages.iter().any(|content| content.contains("/Im1 Do")) {
+            resources.set("XObject", dictionary! { "Im1" => image_id });
+        }
+        let resources_id = doc.add_object(resources);
+        let kids: Vec<Object> = pages
+            .iter()
+            .map(|content| {
+                let content_id = doc.add_object(Stream::new(dictionary! {}, content.as_bytes().to_vec()));
+                doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "Contents" => content_id,
+                    "Resources" => resources_id,
+                })
+                .into()
+            })
+            .collect();
+        let count = kids.len() as i64;
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => kids, "Count" => count }));
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog_id);
+        if let Some(info) = info {
+            let info_id = doc.add_object(info);
+            doc.trailer.set("Info", info_id);
+        }
+        if encrypt {
+            let encrypt_id = doc.add_object(dictionary! { "Filter" => "Standard", "V" => 5, "R" => 6, "Length" => 256 });
+            doc.trailer.set("Encrypt", encrypt_id);
+        }
 
-    for page in doc.get_pages() {
-        let text = doc.extract_text(&[page.0]);
-        full_text.push_str(text.unwrap().as_str());
+        let mut bytes = vec![];
+        doc.save_to(&mut bytes).unwrap();
+        bytes
     }
 
-    Ok(full_text)
-}
\ No newline at end of file
+    #[test]
+    fn pages_are_read_one_by_one() {
+        let info = dictionary! {
+            "Title" => Object::string_literal(vec![0xfe, 0xff, 0x00, b'Q', 0x00, b'3', 0x20, 0xac]),
+            "Author" => Object::string_literal("  Finance Team "),
+            "CreationDate" => Object::string_literal("D:20240115103000+01'00'"),
+        };
+        let bytes = pdf(
+            &["BT /F1 12 Tf (First page) Tj ET", "BT Tf (lost) Tj ET", "q /Im1 Do Q", "BT /F1 12 Tf (Last page) Tj ET"],
+            Some(info.into()),
+            false,
+        );
+        let extracted = extract_text_from_pdf(&bytes).unwrap();
+
+        // The unreadable page keeps its place, the pages after it their numbers
+        let pages: Vec<&str> = extracted.text.split(PAGE_BREAK).map(str::trim).collect();
+        assert_eq!(pages, vec!["First page", "", "", "Last page", ""]);
+        assert_eq!(extracted.failed_pages, vec![2]);
+
+        assert_eq!(extracted.info.title.as_deref(), Some("Q3€"));
+        assert_eq!(extracted.info.author.as_deref(), Some("Finance Team"));
+        assert_eq!(extracted.info.creation_date, Some(1_705_311_000_000));
+    }
+
+    #[test]
+    fn unreadable_documents_have_their_own_errors() {
+        assert_eq!(extract_text_from_pdf(&pdf(&["q /Im1 Do Q", "q /Im1 Do Q"], None, false)), Err(Error::NoTextLayer));
+        assert_eq!(extract_text_from_pdf(&pdf(&["BT /F1 12 Tf (Secret) Tj ET"], None, true)), Err(Error::EncryptedDocument));
+        assert_eq!(extract_text_from_pdf(b"not a pdf"), Err(Error::FileTypeNotSupported));
+
+        // A page without images or text is blank, not scanned
+        let blank = extract_text_from_pdf(&pdf(&[""], None, false)).unwrap();
+        assert_eq!(blank.text.trim(), "");
+        assert_eq!(blank.info, Default::default());
+    }
+
+    #[test]
+    fn dates_and_strings() {
+        assert_eq!(pdf_date("D:1970"), Some(0));
+        assert_eq!(pdf_date("D:20000229"), Some(951_782_400_000));
+        assert_eq!(pdf_date("20240115103000Z"), Some(1_705_314_600_000));
+        assert_eq!(pdf_date("D:20240115103000-05'30'"), Some(1_705_334_400_000));
+        assert_eq!(pdf_date("D:20241315"), None);
+        assert_eq!(pdf_date("yesterday"), None);
+        assert_eq!(text_string(b"Caf\xe9"), "Café");
+        assert_eq!(text_string(b"\xef\xbb\xbfCaf\xc3\xa9"), "Café");
+    }
+}
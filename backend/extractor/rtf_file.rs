@@ -0,0 +1,271 @@
+use super::{ExtractedText, TextWriter};
+use crate::vdb::error::Error;
+use std::collections::HashMap;
+
+/// Destinations whose text is never part of the document body
+const SKIPPED: [&str; 22] = [
+    "fonttbl", "colortbl", "info", "pict", "object", "header", "headerl", "headerr", "headerf", "footer", "footerl",
+    "footerr", "footerf", "footnote", "listtable", "listoverridetable", "revtbl", "rsidtbl", "filetbl", "fldinst",
+    "themedata", "datastore",
+];
+
+/// Windows-1252 characters of the bytes 0x80 to 0x9f, the rest match Latin-1
+const CP1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}', '\u{90}', '‘', '’', '“',
+    '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Destination {
+    Text,
+    Stylesheet,
+    Skip,
+}
+
+#[derive(Clone, Copy)]
+struct Group {
+    destination: Destination,
+    // Fallback characters following a `\u` escape
+    unicode_skip: usize,
+}
+
+/// Text of an RTF document, one paragraph per `\par` with headings marked.
+/// Headings are paragraphs with an outline level or a "heading N" style.
+pub fn extract_text_from_rtf(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    if !bytes.starts_with(b"{\\rtf") {
+        return Err(Error::FileTypeNotSupported);
+    }
+
+    let mut parser = Parser::default();
+    let mut groups = vec![Group { destination: Destination::Text, unicode_skip: 1 }];
+    let mut i = 0;
+    while i < bytes.len() {
+        let group = *groups.last().unwrap_or(&Group { destination: Destination::Skip, unicode_skip: 1 });
+        match bytes[i] {
+            b'{' => {
+                groups.push(group);
+                parser.pending_skip = 0;
+                i += 1;
+            }
+            b'}' => {
+                groups.pop();
+                parser.pending_skip = 0;
+                i += 1;
+            }
+            b'\\' => {
+                let (control, next) = read_control(bytes, i + 1);
+                i = next;
+                match control {
+                    Control::Word(word, parameter) => match word {
+                        "bin" => i += parameter.unwrap_or(0).max(0) as usize,
+                        "stylesheet" => set_destination(&mut groups, Destination::Stylesheet),
+                        word if SKIPPED.contains(&word) => set_destination(&mut groups, Destination::Skip),
+                        "uc" => {
+                            if let Some(group) = groups.last_mut() {
+                                group.unicode_skip = parameter.unwrap_or(1).max(0) as usize;
+                            }
+                        }
+                        "u" => {
+                            // Negative values stand for code units above 32767
+                            let unit = parameter.unwrap_or(0).rem_euclid(65536) as u32;
+                            parser.text(group.destination, char::from_u32(unit).unwrap_or('\u{fffd}'));
+                            parser.pending_skip = group.unicode_skip;
+                        }
+                        word => parser.word(group.destination, word, parameter),
+                    },
+                    // An ignorable destination, none of them hold body text
+                    Control::Symbol(b'*') => set_destination(&mut groups, Destination::Skip),
+                    Control::Symbol(b'\'') => {
+                        let byte = bytes.get(i..i + 2).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+                        if let Some(byte) = byte {
+                            parser.text(group.destination, decode(byte));
+                            i += 2;
+                        }
+                    }
+                    Control::Symbol(b'\r' | b'\n') => parser.word(group.destination, "par", None),
+                    Control::Symbol(b'~') => parser.text(group.destination, '\u{a0}'),
+                    Control::Symbol(b'_') => parser.text(group.destination, '-'),
+                    Control::Symbol(symbol @ (b'\\' | b'{' | b'}')) => parser.text(group.destination, symbol as char),
+                    Control::Symbol(_) => {}
+                }
+            }
+            b'\r' | b'\n' => i += 1,
+            byte => {
+                parser.text(group.destination, decode(byte));
+                i += 1;
+            }
+        }
+    }
+    parser.end_paragraph();
+
+    Ok(parser.writer.finish())
+}
+
+fn set_destination(groups: &mut [Group], destination: Destination) {
+    if let Some(group) = groups.last_mut() {
+        group.destination = destination;
+    }
+}
+
+fn decode(byte: u8) -> char {
+    match byte {
+        0x80..=0x9f => CP1252[(byte - 0x80) as usize],
+        byte => byte as char,
+    }
+}
+
+enum Control<'a> {
+    Word(&'a str, Option<i32>),
+    Symbol(u8),
+}
+
+// The control word or symbol starting at `at`, right after its backslash, and
+// the position following it
+fn read_control(bytes: &[u8], at: usize) -> (Control<'_>, usize) {
+    let letters = bytes[at.min(bytes.len())..].iter().take_while(|b| b.is_ascii_alphabetic()).count();
+    if letters == 0 {
+        return match bytes.get(at) {
+            Some(&symbol) => (Control::Symbol(symbol), at + 1),
+            None => (Control::Symbol(0), at),
+        };
+    }
+    let word = std::str::from_utf8(&bytes[at..at + letters]).unwrap_or_default();
+    let mut end = at + letters;
+
+    let sign = usize::from(bytes.get(end) == Some(&b'-'));
+    let digits = bytes[(end + sign).min(bytes.len())..].iter().take_while(|b| b.is_ascii_digit()).count();
+    let parameter = match digits {
+        0 => None,
+        _ => std::str::from_utf8(&bytes[end..end + sign + digits]).ok().and_then(|number| number.parse().ok()),
+    };
+    if digits > 0 {
+        end += sign + digits;
+    }
+    // A single space delimits the control word and belongs to it
+    if bytes.get(end) == Some(&b' ') {
+        end += 1;
+    }
+    (Control::Word(word, parameter), end)
+}
+
+#[derive(Default)]
+struct Parser {
+    writer: TextWriter,
+    paragraph: String,
+    // Properties of the current paragraph, reset by `\pard`
+    style: Option<i32>,
+    outline: Option<u32>,
+    // Heading level of the stylesheet's heading styles
+    headings: HashMap<i32, u32>,
+    // Stylesheet entry being read
+    entry_style: Option<i32>,
+    entry_name: String,
+    // Fallback characters of a `\u` escape still to drop
+    pending_skip: usize,
+}
+
+impl Parser {
+    fn text(&mut self, destination: Destination, c: char) {
+        if self.pending_skip > 0 {
+            self.pending_skip -= 1;
+            return;
+        }
+        match destination {
+            Destination::Text => self.paragraph.push(c),
+            // Entries look like `{\s1\outlinelevel0 heading 1;}`
+            Destination::Stylesheet if c == ';' => {
+                let name = std::mem::take(&mut self.entry_name).trim().to_lowercase();
+                let level = match name.strip_prefix("heading ") {
+                    Some(number) => number.trim().parse().ok(),
+                    None => (name == "title").then_some(1),
+                };
+                if let (Some(style), Some(level)) = (self.entry_style.take(), level) {
+                    self.headings.entry(style).or_insert(level);
+                }
+            }
+            Destination::Stylesheet => self.entry_name.push(c),
+            Destination::Skip => {}
+        }
+    }
+
+    fn word(&mut self, destination: Destination, word: &str, parameter: Option<i32>) {
+        match (destination, word) {
+            (Destination::Stylesheet, "s") => self.entry_style = parameter,
+            (Destination::Stylesheet, "outlinelevel") => {
+                if let (Some(style), Some(level)) = (self.entry_style, outline_level(parameter)) {
+                    self.headings.insert(style, level);
+                }
+            }
+            (Destination::Text, "par" | "sect" | "page" | "row") => self.end_paragraph(),
+            (Destination::Text, "pard") => {
+                self.style = None;
+                self.outline = None;
+            }
+            (Destination::Text, "s") => self.style = parameter,
+            (Destination::Text, "outlinelevel") => self.outline = outline_level(parameter),
+            (Destination::Text, word) => {
+                let c = match word {
+                    "line" => '\n',
+                    "tab" | "cell" => '\t',
+                    "emdash" => '—',
+                    "endash" => '–',
+                    "bullet" => '•',
+                    "lquote" => '‘',
+                    "rquote" => '’',
+                    "ldblquote" => '“',
+                    "rdblquote" => '”',
+                    "emspace" | "enspace" | "qmspace" => ' ',
+                    _ => return,
+                };
+                self.paragraph.push(c);
+            }
+            _ => {}
+        }
+    }
+
+    fn end_paragraph(&mut self) {
+        let heading = self.outline.or_else(|| self.style.and_then(|style| self.headings.get(&style).copied()));
+        self.writer.paragraph(&std::mem::take(&mut self.paragraph), heading);
+    }
+}
+
+// Outline levels count from 0, level 9 is body text
+fn outline_level(parameter: Option<i32>) -> Option<u32> {
+    match parameter {
+        Some(level @ 0..=8) => Some(level as u32 + 1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_text_from_rtf;
+    use crate::extractor::extract_text_from_document;
+
+    const DOCUMENT: &str = r"{\rtf1\ansi\ansicpg1252\deff0{\fonttbl{\f0\froman Times New Roman;}}{\colortbl;\red0\green0\blue0;}
+{\stylesheet{\s0 Normal;}{\s1\outlinelevel0 heading 1;}{\s2 Heading 2;}}
+{\*\generator Writer;}{\info{\title Hidden}}
+{\pard\s1 Introduction\par}
+\pard First paragraph with caf\'e9, \u8364?uro and \{braces\}.\par
+\pard\s2 Details\par
+\pard Line one\line line two\tab end{\footnote not here}\par
+\pard\outlinelevel2 Deep\par
+\pard Last one without par}";
+
+    #[test]
+    fn paragraphs_and_headings() {
+        let text = extract_text_from_rtf(DOCUMENT.as_bytes()).unwrap().text;
+        assert_eq!(
+            text,
+            "# Introduction\n\nFirst paragraph with café, €uro and {braces}.\n\n## Details\n\nLine one\nline two\tend\n\n### Deep\n\nLast one without par"
+        );
+        assert_eq!(extract_text_from_document(DOCUMENT.as_bytes()).unwrap().text, text);
+    }
+
+    #[test]
+    fn unicode_fallbacks_are_dropped() {
+        let text = extract_text_from_rtf(br"{\rtf1{\uc2\u26085\'93\'fa\u26412 ??}\par}").unwrap().text;
+        assert_eq!(text, "日本");
+        assert!(extract_text_from_rtf(b"plain text").is_err());
+    }
+}
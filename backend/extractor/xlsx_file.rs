@@ -0,0 +1,370 @@
+use super::archive::Archive;
+use super::xml::{Event, Reader};
+use super::{ExtractedText, TextWriter};
+use crate::vdb::error::Error;
+use std::collections::HashMap;
+
+/// Rows of every worksheet of an Excel (OOXML) workbook, each written as
+/// "header: value" lines under the first non-empty row of its sheet. Cells
+/// formatted as dates are written as ISO dates rather than serial numbers.
+pub fn extract_text_from_xlsx(bytes: &[u8]) -> Result<ExtractedText, Error> {
+    let archive = Archive::new(bytes)?;
+    let workbook = archive.read_string("xl/workbook.xml")?;
+    let targets = match archive.read_string("xl/_rels/workbook.xml.rels") {
+        Ok(relationships) => sheet_targets(&relationships),
+        Err(_) => HashMap::new(),
+    };
+    let strings = match archive.read_string("xl/sharedStrings.xml") {
+        Ok(strings) => shared_strings(&strings),
+        Err(_) => vec![],
+    };
+    let dates = match archive.read_string("xl/styles.xml") {
+        Ok(styles) => date_styles(&styles),
+        Err(_) => vec![],
+    };
+
+    let mut writer = TextWriter::default();
+    for (i, (name, id)) in sheets(&workbook).into_iter().enumerate() {
+        // Without relationships the sheets are where spreadsheet apps put them
+        let path = match targets.get(&id) {
+            Some(target) => target.clone(),
+            None => format!("xl/worksheets/sheet{}.xml", i + 1),
+        };
+        let Ok(sheet) = archive.read_string(&path) else {
+            continue;
+        };
+
+        let mut rows = read_rows(&sheet, &strings, &dates).into_iter();
+        let Some((_, header)) = rows.next() else {
+            continue;
+        };
+        let header: HashMap<usize, &str> = header.iter().map(|(column, name)| (*column, name.trim())).collect();
+        for (number, cells) in rows {
+            let names: Vec<String> = cells
+                .iter()
+                .map(|(column, _)| match header.get(column) {
+                    Some(name) if !name.is_empty() => name.to_string(),
+                    _ => format!("Column {}", column_name(*column)),
+                })
+                .collect();
+            let cells: Vec<(&str, &str)> = names.iter().map(String::as_str).zip(cells.iter().map(|(_, value)| value.as_str())).collect();
+            writer.row(Some(&name), number, &cells);
+        }
+    }
+
+    Ok(writer.finish())
+}
+
+// Name and relationship id of the workbook's sheets, in tab order
+fn sheets(workbook: &str) -> Vec<(String, String)> {
+    Reader::new(workbook)
+        .filter_map(|event| match event {
+            Event::Start(tag) if tag.name == "sheet" => {
+                Some((tag.attribute("name")?.into_owned(), tag.attribute("r:id")?.into_owned()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Archive path of every worksheet relationship, targets are relative to `xl/`
+fn sheet_targets(relationships: &str) -> HashMap<String, String> {
+    Reader::new(relationships)
+        .filter_map(|event| match event {
+            Event::Start(tag) if tag.name == "Relationship" => {
+                let target = tag.attribute("Target")?;
+                let path = match target.strip_prefix('/') {
+                    Some(absolute) => absolute.to_string(),
+                    None => format!("xl/{}", target),
+                };
+                Some((tag.attribute("Id")?.into_owned(), path))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Strings of the shared string table, rich text runs joined and phonetic
+// guides left out
+fn shared_strings(table: &str) -> Vec<String> {
+    let mut strings = vec![];
+    let mut in_text = false;
+    let mut phonetic = false;
+    for event in Reader::new(table) {
+        match event {
+            Event::Start(tag) => match tag.name {
+                "si" => strings.push(String::new()),
+                "t" => in_text = !tag.empty,
+                "rPh" => phonetic = !tag.empty,
+                _ => {}
+            },
+            Event::End("t") => in_text = false,
+            Event::End("rPh") => phonetic = false,
+            Event::Text(text) if in_text && !phonetic => {
+                if let Some(string) = strings.last_mut() {
+                    string.push_str(&text);
+                }
+            }
+            _ => {}
+        }
+    }
+    strings
+}
+
+// Whether each cell format, by index, shows a date
+fn date_styles(styles: &str) -> Vec<bool> {
+    let mut custom: HashMap<String, bool> = HashMap::new();
+    let mut dates = vec![];
+    let mut in_cell_formats = false;
+    for event in Reader::new(styles) {
+        match event {
+            Event::Start(tag) => match tag.name {
+                "numFmt" => {
+                    if let (Some(id), Some(code)) = (tag.attribute("numFmtId"), tag.attribute("formatCode")) {
+                        custom.insert(id.into_owned(), is_date_format(&code));
+                    }
+                }
+                "cellXfs" => in_cell_formats = !tag.empty,
+                "xf" if in_cell_formats => {
+                    let id = tag.attribute("numFmtId").unwrap_or_default();
+                    let date = match custom.get(id.as_ref()) {
+                        Some(date) => *date,
+                        // The built in date and time formats
+                        None => matches!(id.parse::<u32>(), Ok(14..=22 | 45..=47)),
+                    };
+                    dates.push(date);
+                }
+                _ => {}
+            },
+            Event::End("cellXfs") => in_cell_formats = false,
+            _ => {}
+        }
+    }
+    dates
+}
+
+// A custom format shows a date when it has day or year codes outside its
+// literal text, bare `m`s could as well be minutes
+fn is_date_format(code: &str) -> bool {
+    let mut literal = false;
+    let mut bracket = false;
+    for c in code.chars() {
+        match c {
+            '"' => literal = !literal,
+            '[' if !literal => bracket = true,
+            ']' if !literal => bracket = false,
+            'd' | 'D' | 'y' | 'Y' if !literal && !bracket => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+// Non-empty rows of a worksheet with their number and the values of their
+// non-empty cells by column
+fn read_rows(sheet: &str, strings: &[String], dates: &[bool]) -> Vec<(u32, Vec<(usize, String)>)> {
+    let mut rows: Vec<(u32, Vec<(usize, String)>)> = vec![];
+    let mut row: Option<(u32, Vec<(usize, String)>)> = None;
+    let mut number = 0;
+    let mut cell: Option<Cell> = None;
+    let mut in_value = false;
+    let mut phonetic = false;
+    for event in Reader::new(sheet) {
+        match event {
+            Event::Start(tag) => match tag.name {
+                "row" => {
+                    // Row and cell references are optional, they default to the next one
+                    number = tag.attribute("r").and_then(|r| r.parse().ok()).unwrap_or(number + 1);
+                    if let Some(finished) = row.take().filter(|(_, cells)| !cells.is_empty()) {
+                        rows.push(finished);
+                    }
+                    row = Some((number, vec![]));
+                }
+                "c" => {
+                    let next = row.as_ref().and_then(|(_, cells)| cells.last()).map_or(0, |(column, _)| column + 1);
+                    let column = tag.attribute("r").and_then(|r| column_index(&r)).unwrap_or(next);
+                    let kind = tag.attribute("t").unwrap_or_default().into_owned();
+                    let date = tag.attribute("s").and_then(|s| s.parse::<usize>().ok()).is_some_and(|s| dates.get(s) == Some(&true));
+                    cell = Some(Cell { column, kind, date, value: String::new() });
+                }
+                "v" | "t" => in_value = !tag.empty,
+                "rPh" => phonetic = !tag.empty,
+                _ => {}
+            },
+            Event::End("v" | "t") => in_value = false,
+            Event::End("rPh") => phonetic = false,
+            Event::End("c") => {
+                if let (Some(cell), Some((_, cells))) = (cell.take(), row.as_mut()) {
+                    let column = cell.column;
+                    if let Some(value) = cell.value(strings).filter(|value| !value.trim().is_empty()) {
+                        cells.push((column, value));
+                    }
+                }
+            }
+            Event::End("row") => {
+                if let Some(finished) = row.take().filter(|(_, cells)| !cells.is_empty()) {
+                    rows.push(finished);
+                }
+            }
+            Event::Text(text) if in_value && !phonetic => {
+                if let Some(cell) = cell.as_mut() {
+                    cell.value.push_str(&text);
+                }
+            }
+            _ => {}
+        }
+    }
+    rows
+}
+
+struct Cell {
+    column: usize,
+    // The `t` attribute: shared string, inline string, boolean, error or number
+    kind: String,
+    date: bool,
+    value: String,
+}
+
+impl Cell {
+    fn value(self, strings: &[String]) -> Option<String> {
+        match self.kind.as_str() {
+            "s" => strings.get(self.value.trim().parse::<usize>().ok()?).cloned(),
+            "b" => Some(if self.value.trim() == "1" { "TRUE" } else { "FALSE" }.to_string()),
+            "n" | "" if self.date => Some(self.value.trim().parse().ok().and_then(date).unwrap_or(self.value)),
+            _ => Some(self.value),
+        }
+    }
+}
+
+// Zero-based column of a cell reference like "AB12"
+fn column_index(reference: &str) -> Option<usize> {
+    let letters: Vec<u8> = reference.bytes().take_while(u8::is_ascii_alphabetic).collect();
+    if letters.is_empty() {
+        return None;
+    }
+    let number = letters.iter().fold(0, |number, letter| number * 26 + (letter.to_ascii_uppercase() - b'A') as usize + 1);
+    Some(number - 1)
+}
+
+// Letters of a zero-based column, the inverse of `column_index`
+fn column_name(column: usize) -> String {
+    let mut name = vec![];
+    let mut number = column + 1;
+    while number > 0 {
+        name.push(b'A' + ((number - 1) % 26) as u8);
+        number = (number - 1) / 26;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap_or_default()
+}
+
+// ISO date of a serial date, the days since 1899-12-30, and the time of
+// day when it has one
+fn date(serial: f64) -> Option<String> {
+    if !(1.0..2_958_466.0).contains(&serial) {
+        return None;
+    }
+    let minutes = (serial * 1440.0).round() as i64;
+    let days = minutes / 1440;
+    // Days since 0000-03-01, years then start with March and end with the leap day
+    let shifted = days - 25569 + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    match minutes % 1440 {
+        0 => Some(format!("{:04}-{:02}-{:02}", year, month, day)),
+        minutes => Some(format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, minutes / 60, minutes % 60)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{column_index, column_name, date, extract_text_from_xlsx};
+    use crate::extractor::archive::zip;
+
+    const WORKBOOK: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships">
+  <sheets><sheet name="Q1 &amp; Q2" sheetId="1" r:id="rId2"/><sheet name="Notes" sheetId="2" r:id="rId1"/></sheets>
+</workbook>"#;
+
+    const RELATIONSHIPS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+  <Relationship Id="rId1" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="worksheets/sheet2.xml"/>
+  <Relationship Id="rId2" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/worksheet" Target="/xl/worksheets/sheet1.xml"/>
+  <Relationship Id="rId3" Type="http://schemas.openxmlformats.org/officeDocument/2006/relationships/styles" Target="styles.xml"/>
+</Relationships>"#;
+
+    const STRINGS: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<sst xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main" count="5" uniqueCount="5">
+  <si><t>Account</t></si>
+  <si><t>Booked</t></si>
+  <si><r><rPr><b/></rPr><t>Amount</t></r><r><t xml:space="preserve"> (EUR)</t></r></si>
+  <si><t>Rent</t><rPh sb="0" eb="1"><t>rento</t></rPh></si>
+  <si><t>Remember the audit</t></si>
+</sst>"#;
+
+    const STYLES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<styleSheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main">
+  <numFmts count="1"><numFmt numFmtId="164" formatCode="[$-409]d\-mmm\-yy;@"/></numFmts>
+  <cellStyleXfs count="1"><xf numFmtId="0"/></cellStyleXfs>
+  <cellXfs count="4"><xf numFmtId="0"/><xf numFmtId="14"/><xf numFmtId="164"/><xf numFmtId="4"/></cellXfs>
+</styleSheet>"#;
+
+    const SHEET1: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main">
+  <sheetData>
+    <row r="2"><c r="B2" t="s"><v>0</v></c><c r="C2" t="s"><v>1</v></c><c r="D2" t="s"><v>2</v></c></row>
+    <row r="3"><c r="B3" t="s"><v>3</v></c><c r="C3" s="1"><v>45292</v></c><c r="D3" s="3"><v>1250.5</v></c><c r="F3" t="b"><v>1</v></c></row>
+    <row r="4"><c r="B4" s="3"/></row>
+    <row r="5"><c r="B5" t="inlineStr"><is><t>Travel</t></is></c><c r="C5" s="2"><v>45306.75</v></c><c r="D5" t="str"><f>D3/10</f><v>125.05</v></c></row>
+  </sheetData>
+</worksheet>"#;
+
+    const SHEET2: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<worksheet xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main">
+  <sheetData><row><c t="inlineStr"><is><t>Note</t></is></c></row><row><c t="s"><v>4</v></c></row></sheetData>
+</worksheet>"#;
+
+    #[test]
+    fn rows_of_every_sheet() {
+        let bytes = zip(
+            &[
+                ("xl/workbook.xml", WORKBOOK),
+                ("xl/_rels/workbook.xml.rels", RELATIONSHIPS),
+                ("xl/sharedStrings.xml", STRINGS),
+                ("xl/styles.xml", STYLES),
+                ("xl/worksheets/sheet1.xml", SHEET1),
+                ("xl/worksheets/sheet2.xml", SHEET2),
+            ],
+            true,
+        );
+        let extracted = extract_text_from_xlsx(&bytes).unwrap();
+        assert_eq!(
+            extracted.text,
+            "Account: Rent\nBooked: 2024-01-01\nAmount (EUR): 1250.5\nColumn F: TRUE\n\nAccount: Travel\nBooked: 2024-01-15 18:00\nAmount (EUR): 125.05\n\nNote: Remember the audit"
+        );
+        let rows: Vec<(Option<&str>, u32)> = extracted.rows.iter().map(|row| (row.sheet.as_deref(), row.number)).collect();
+        assert_eq!(rows, vec![(Some("Q1 & Q2"), 3), (Some("Q1 & Q2"), 5), (Some("Notes"), 2)]);
+        assert!(extract_text_from_xlsx(&zip(&[("word/document.xml", "<a/>")], false)).is_err());
+    }
+
+    #[test]
+    fn references_and_dates() {
+        assert_eq!(column_index("A1"), Some(0));
+        assert_eq!(column_index("ab12"), Some(27));
+        assert_eq!(column_index("12"), None);
+        assert_eq!(column_name(27), "AB");
+        assert_eq!(column_name(701), "ZZ");
+        assert_eq!(date(36526.0).as_deref(), Some("2000-01-01"));
+        assert_eq!(date(36525.9999999).as_deref(), Some("2000-01-01"));
+        assert_eq!(date(44985.0).as_deref(), Some("2023-02-28"));
+        assert_eq!(date(45351.5).as_deref(), Some("2024-02-29 12:00"));
+        assert_eq!(date(-1.0), None);
+    }
+}
@@ -0,0 +1,220 @@
+use std::borrow::Cow;
+
+/// Piece of an XML document, as returned by `Reader`
+#[derive(Debug, PartialEq)]
+pub enum Event<'a> {
+    /// Opening tag, `empty` for a self closing one which gets no `End`
+    Start(Tag<'a>),
+    End(&'a str),
+    /// Character data with the entities resolved
+    Text(Cow<'a, str>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Tag<'a> {
+    pub name: &'a str,
+    pub empty: bool,
+    attributes: &'a str,
+}
+
+impl Tag<'_> {
+    /// Value of the attribute with the qualified `name`
+    pub fn attribute(&self, name: &str) -> Option<Cow<'_, str>> {
+        let mut rest = self.attributes;
+        loop {
+            let eq = rest.find('=')?;
+            let key = rest[..eq].trim();
+            let after = rest[eq + 1..].trim_start();
+            let quote = after.chars().next()?;
+            if quote != '"' && quote != '\'' {
+                return None;
+            }
+            let end = after[1..].find(quote)? + 1;
+            if key == name {
+                return Some(unescape(&after[1..end]));
+            }
+            rest = &after[end + 1..];
+        }
+    }
+}
+
+/// Minimal pull parser, enough to walk the markup of office documents.
+/// Declarations, comments and processing instructions are skipped and the
+/// document is not validated, malformed markup ends the events early.
+pub struct Reader<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(xml: &'a str) -> Self {
+        Self { rest: xml }
+    }
+
+    /// Skip past the closing tag of `name`, for HTML elements such as
+    /// `script` whose contents are raw text rather than markup
+    pub fn skip_raw(&mut self, name: &str) {
+        let closing = format!("</{}", name);
+        let at = self
+            .rest
+            .as_bytes()
+            .windows(closing.len())
+            .position(|window| window.eq_ignore_ascii_case(closing.as_bytes()))
+            .unwrap_or(self.rest.len());
+        self.rest = &self.rest[at..];
+        self.rest = match self.rest.find('>') {
+            Some(end) => &self.rest[end + 1..],
+            None => "",
+        };
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+            if !self.rest.starts_with('<') {
+                let end = self.rest.find('<').unwrap_or(self.rest.len());
+                let text = &self.rest[..end];
+                self.rest = &self.rest[end..];
+                return Some(Event::Text(unescape(text)));
+            }
+
+            if let Some(rest) = self.rest.strip_prefix("<![CDATA[") {
+                let end = rest.find("]]>")?;
+                self.rest = &rest[end + 3..];
+                return Some(Event::Text(Cow::Borrowed(&rest[..end])));
+            }
+            if let Some(rest) = self.rest.strip_prefix("<!--") {
+                self.rest = &rest[rest.find("-->")? + 3..];
+                continue;
+            }
+            if self.rest.starts_with("<?") || self.rest.starts_with("<!") {
+                self.rest = &self.rest[self.rest.find('>')? + 1..];
+                continue;
+            }
+
+            let end = self.rest.find('>')?;
+            let markup = &self.rest[1..end];
+            self.rest = &self.rest[end + 1..];
+            if let Some(name) = markup.strip_prefix('/') {
+                return Some(Event::End(name.trim()));
+            }
+            let (markup, empty) = match markup.strip_suffix('/') {
+                Some(markup) => (markup, true),
+                None => (markup, false),
+            };
+            let split = markup.find(char::is_whitespace).unwrap_or(markup.len());
+            return Some(Event::Start(Tag {
+                name: &markup[..split],
+                empty,
+                attributes: &markup[split..],
+            }));
+        }
+    }
+}
+
+/// Resolve the predefined and numeric character entities, and the named ones
+/// HTML text commonly uses
+pub fn unescape(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let entity = rest.find(';').map(|end| &rest[1..end]);
+        let resolved = match entity {
+            Some("lt") => Some('<'),
+            Some("gt") => Some('>'),
+            Some("amp") => Some('&'),
+            Some("quot") => Some('"'),
+            Some("apos") => Some('\''),
+            Some("nbsp") => Some('\u{a0}'),
+            Some("copy") => Some('©'),
+            Some("reg") => Some('®'),
+            Some("trade") => Some('™'),
+            Some("hellip") => Some('…'),
+            Some("mdash") => Some('—'),
+            Some("ndash") => Some('–'),
+            Some("lsquo") => Some('‘'),
+            Some("rsquo") => Some('’'),
+            Some("ldquo") => Some('“'),
+            Some("rdquo") => Some('”'),
+            Some(entity) => match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity.strip_prefix('#').and_then(|decimal| decimal.parse().ok()).and_then(char::from_u32),
+            },
+            None => None,
+        };
+        match (resolved, entity) {
+            (Some(c), Some(entity)) => {
+                out.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            // Not an entity, keep the ampersand as it is
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unescape, Event, Reader, Tag};
+
+    fn start<'a>(event: &'a Event) -> &'a Tag<'a> {
+        match event {
+            Event::Start(tag) => tag,
+            _ => panic!("not a start tag: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn walks_tags_and_text() {
+        let xml = r#"<?xml version="1.0"?><!-- note --><w:p a="1"><w:t xml:space='preserve'>a &amp; b</w:t><w:tab/><![CDATA[<raw>]]></w:p>"#;
+        let events: Vec<Event> = Reader::new(xml).collect();
+        assert_eq!(events.len(), 7);
+
+        let tag = start(&events[0]);
+        assert_eq!((tag.name, tag.empty), ("w:p", false));
+        assert_eq!(tag.attribute("a").as_deref(), Some("1"));
+        let tag = start(&events[1]);
+        assert_eq!(tag.attribute("xml:space").as_deref(), Some("preserve"));
+        assert_eq!(tag.attribute("space"), None);
+
+        assert_eq!(events[2], Event::Text("a & b".into()));
+        assert_eq!(events[3], Event::End("w:t"));
+        let tag = start(&events[4]);
+        assert_eq!((tag.name, tag.empty), ("w:tab", true));
+        assert_eq!(events[5], Event::Text("<raw>".into()));
+        assert_eq!(events[6], Event::End("w:p"));
+    }
+
+    #[test]
+    fn skips_raw_text() {
+        let mut reader = Reader::new("<script>if (a < b && c) { x = '</p>'; }</SCRIPT ><p>after</p>");
+        assert_eq!(start(&reader.next().unwrap()).name, "script");
+        reader.skip_raw("script");
+        assert_eq!(start(&reader.next().unwrap()).name, "p");
+        assert_eq!(reader.next(), Some(Event::Text("after".into())));
+    }
+
+    #[test]
+    fn resolves_entities() {
+        assert_eq!(unescape("&lt;a&gt; &#233;&#x2014;&quot;&apos;"), "<a> é—\"'");
+        assert_eq!(unescape("a&nbsp;b &mdash; &copy;"), "a\u{a0}b — ©");
+        // Unknown or unterminated ones are left alone
+        assert_eq!(unescape("AT&T &bogus; &"), "AT&T &bogus; &");
+    }
+}
@@ -1,66 +1,92 @@
-use std::env::args;
 use candid::{CandidType, Principal};
 mod vdb;
 mod client;
 mod extractor;
+mod chunker;
+mod embedding;
+mod chat;
 
 use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
 use ic_cdk_macros::{query, update};
-use ic_stable_structures::Storable;
-use ic_stable_structures::writer::Writer;
-use ic_stable_structures::Memory as _;
 use serde::Deserialize;
 use serde_bytes::ByteBuf;
 use vdb::db::DB;
-use vdb::collection::DocMetadata;
+use vdb::collection::{CollectionQuery, DocMetadata, HybridQuery, SearchMode, SearchParams, SearchResult};
 use vdb::error::Error;
-use vdb::memory::{is_owner, set_config_map,get_config_map_by_key, get_upgrades_memory};
-use crate::client::{generate_embeddings, extract_text_from_bytebuf};
-
-const OPENAI_API_KEY: &str = "OPENAI_KEY";
+use vdb::index::{IndexConfig, Metric, Vector};
+use vdb::legacy::migrate_legacy_state;
+use vdb::memory::{is_owner, set_config_map};
+use crate::client::extract_text_from_bytebuf;
+use crate::chunker::{chunk_document, ChunkConfig};
+use crate::extractor::code_file::Language;
+use crate::chat::{build_prompt, complete, validate_messages, ChatMessage, ChatResponse};
+use crate::embedding::{embedding_dimension, generate_embeddings, InputType, OPENAI_API_KEY};
 
 #[derive(Clone, Debug, Default, CandidType, Deserialize)]
 pub struct InstallArgs {
     #[serde(rename = "openApiKeys")]
     pub openai_key: String,
+    // Embedding provider settings, e.g. ("EMBEDDING_PROVIDER", "ollama"), see `embedding`
+    pub config: Option<Vec<(String, String)>>,
 }
-#[ic_cdk::init]
-fn init(args: InstallArgs) {
+
+fn apply_install_args(args: InstallArgs) {
     set_config_map(OPENAI_API_KEY.to_string(), args.openai_key);
+    for (key, value) in args.config.unwrap_or_default() {
+        set_config_map(key, value);
+    }
 }
 
-#[ic_cdk::pre_upgrade]
-fn pre_upgrade() {
-    // Serialize the state.
-    let mut state_bytes = vec![];
-    DB.with(|s| ciborium::ser::into_writer(&*s.borrow(), &mut state_bytes))
-        .expect("failed to encode state");
-
-    // Write the length of the serialized bytes to memory, followed by the
-    // by the bytes themselves.
-    let len = state_bytes.len() as u32;
-    let mut memory = get_upgrades_memory();
-    let mut writer = Writer::new(&mut memory, 0);
-    writer.write(&len.to_le_bytes()).unwrap();
-    writer.write(&state_bytes).unwrap()
+#[ic_cdk::init]
+fn init(args: InstallArgs) {
+    apply_install_args(args);
 }
+
 #[ic_cdk::post_upgrade]
 fn post_upgrade(args: InstallArgs) {
-    set_config_map(OPENAI_API_KEY.to_string(), args.openai_key);
+    apply_install_args(args);
+
+    // Collections already live in stable memory, only state written by the
+    // old heap based `pre_upgrade` has to be imported, once.
+    DB.with(|db| {
+        let mut db = db.borrow_mut();
+        migrate_legacy_state(&mut db);
+
+        // Older collections were created with a made up dimension, record the model's one
+        match embedding_dimension() {
+            Ok(dimension) => {
+                for name in db.migrate_dimension(dimension) {
+                    ic_cdk::println!("collection {} holds vectors of another model, re-upload needed", name);
+                }
+            }
+            Err(err) => ic_cdk::println!("embedding config: {}", err),
+        }
 
-    let memory = get_upgrades_memory();
-    // Read the length of the state bytes.
-    let mut state_len_bytes = [0; 4];
-    memory.read(0, &mut state_len_bytes);
-    let state_len = u32::from_le_bytes(state_len_bytes) as usize;
+        // Graphs stored whole before their nodes got a map of their own
+        for name in db.migrate_graph_nodes() {
+            ic_cdk::println!("collection {} graph nodes moved", name);
+        }
 
-    // Read the bytes
-    let mut state_bytes = vec![0; state_len];
-    memory.read(4, &mut state_bytes);
+        // Flat indexes stored whole before their ids got a map of their own
+        for name in db.migrate_flat_ids() {
+            ic_cdk::println!("collection {} flat index ids moved", name);
+        }
 
-    // Deserialize and set the state.
-    let state = ciborium::de::from_reader(&*state_bytes).expect("failed to decode state");
-    DB.with(|s| *s.borrow_mut() = state);
+        // Vectors stored before cosine collections normalised them on insert
+        for name in db.migrate_cosine_vectors() {
+            ic_cdk::println!("collection {} vectors normalised", name);
+        }
+
+        // Documents' chunk ids kept in the collection record before they had a map of their own
+        for name in db.migrate_doc_chunks() {
+            ic_cdk::println!("collection {} document chunks moved", name);
+        }
+
+        // Chunks stored before keyword search existed still need their terms indexed
+        for name in db.migrate_lexical_index() {
+            ic_cdk::println!("collection {} indexed for keyword search", name);
+        }
+    });
 }
 
 #[query]
@@ -78,12 +104,52 @@ fn transform_exchange_http_response(args: TransformArgs) -> HttpResponse {
 
 #[query]
 fn check_is_owner() -> bool {
-    let is_owner = is_owner();
-    is_owner
+    is_owner()
 }
 
-//// VECTOR DB CRUD
+// VECTOR DB CRUD
 // --- CREATE + INSERT ---
+// Collections are created on first use with the default metric and index, this
+// creates one up front with others
+#[update]
+fn create_collection(metric: Option<Metric>, index: Option<IndexConfig>) -> Result<String, Error> {
+    // get user from ic_cdk::caller()
+    let user = ic_cdk::caller();
+    // check if user is authenticated
+    if user == Principal::anonymous() {
+        return Err(Error::Unauthorized);
+    }
+    // user principal id as collection name
+    let name = user.to_string();
+
+    let dimension = embedding_dimension().map_err(Error::ModelError)?;
+    DB.with(|db| {
+        let mut db = db.borrow_mut();
+        db.create_collection(name, dimension, metric.unwrap_or_default(), index.unwrap_or_default(), ic_cdk::api::time())?;
+        Ok("Collection created".to_string())
+    })
+}
+
+#[query]
+fn get_index_config() -> Result<IndexConfig, Error> {
+    // get user from ic_cdk::caller()
+    let user = ic_cdk::caller();
+    // check if user is authenticated
+    if user == Principal::anonymous() {
+        return Err(Error::Unauthorized);
+    }
+    // user principal id as collection name
+    let name = user.to_string();
+
+    DB.with(|db| {
+        let db = db.borrow();
+        match db.collections.contains_key(&name) {
+            true => db.get_index_config(&name),
+            false => Ok(IndexConfig::default()),
+        }
+    })
+}
+
 #[update]
 async fn upload_file(file_type: String, title: String, filename: String, data: ByteBuf) -> Result<String, Error> {
     // get user from ic_cdk::caller()
@@ -95,24 +161,54 @@ async fn upload_file(file_type: String, title: String, filename: String, data: B
     // user principal id as collection name
     let collection_name = user.to_string();
 
-    // Check if file_type is valid, only pdf, txt, docs  are allowed. and throw FileTypeNotSupported error
-    let valid_file_types = vec!["pdf", "text", "docs"];
-    if !valid_file_types.contains(&file_type.as_str()) {
+    // Check if file_type is valid, only pdf, txt, web pages and word processing documents are allowed. and throw FileTypeNotSupported error
+    let valid_file_types = vec!["pdf", "text", "docs", "docx", "odt", "rtf", "html", "markdown", "csv", "tsv", "xlsx", "json", "jsonl", "eml", "mbox"];
+    // Source files, typed by their extension or as "code" to take it from the file name
+    let file_type = match file_type.as_str() {
+        "code" => filename.rsplit_once('.').map(|(_, extension)| extension.to_lowercase()).unwrap_or_default(),
+        _ => file_type,
+    };
+    if !valid_file_types.contains(&file_type.as_str()) && Language::from_extension(&file_type).is_none() {
         return Err(Error::FileTypeNotSupported);
     }
 
-    // let content = data.clone();
-    // get open api key from env var
-    let api_key = get_config_map_by_key(OPENAI_API_KEY.to_string()).unwrap();
+    // The collection's chunking config, its text paths pick the text of JSON records
+    let chunk_config = DB.with(|db| {
+        let db = db.borrow();
+        match db.collections.contains_key(&collection_name) {
+            true => db.get_chunk_config(&collection_name),
+            false => Ok(ChunkConfig::default()),
+        }
+    })?;
 
+    // let content = data.clone();
     // Extract text content based on file type
-    let text_content = match extract_text_from_bytebuf(&data, &file_type) {
-        Ok(text) => text,
-        Err(_) => return Err(Error::FileTypeNotSupported),
+    let text_content = extract_text_from_bytebuf(&data, &file_type, &chunk_config.text_paths)?;
+    let info = text_content.info.clone();
+    // A blank title falls back to the one the document gives itself
+    let title = match (title.trim().is_empty(), info.title) {
+        (true, Some(own_title)) => own_title,
+        _ => title,
     };
 
-    // Generate embeddings using OpenAI API
-    let embeddings = match generate_embeddings(&text_content, &api_key).await {
+    DB.with(|db| {
+        let mut db = db.borrow_mut();
+        if !db.collections.contains_key(&collection_name) {
+            let dimension = embedding_dimension().map_err(Error::ModelError)?;
+            db.create_collection(collection_name.clone(), dimension, Metric::default(), IndexConfig::default(), ic_cdk::api::time())?;
+        }
+        Ok::<(), Error>(())
+    })?;
+
+    // Split the document into passages using the collection's chunking config
+    let chunks = chunk_document(&text_content, &chunk_config)?;
+    if chunks.is_empty() {
+        return Err(Error::InvalidInput);
+    }
+
+    // Generate embeddings with the configured provider, many chunks per request
+    let texts: Vec<String> = chunks.iter().map(|chunk| chunk.text.clone()).collect();
+    let embeddings = match generate_embeddings(&texts, InputType::Document).await {
         Ok(emb) => emb,
         Err(err) => return Err(Error::ModelError(err)),
     };
@@ -123,19 +219,92 @@ async fn upload_file(file_type: String, title: String, filename: String, data: B
     // Insert into collection with proper error handling
     DB.with(|db| {
         let mut db = db.borrow_mut();
-        let exist = db.collections.contains_key(&collection_name);
-        if !exist {
-            db.create_collection(collection_name.clone(), 1000).unwrap();
+
+        // Insert the document and handle error, the chunks are linked into the index as they go in
+        let doc = DocMetadata {
+            title,
+            file_name: filename.clone(),
+            file_type: Some(file_type),
+            file_size,
+            created_at,
+            author: info.author,
+            creation_date: info.creation_date,
+        };
+        db.insert_into_collection(&collection_name, embeddings, chunks, doc)?;
+
+        // Pages that couldn't be read were skipped, say which
+        match text_content.failed_pages.is_empty() {
+            true => Ok(format!("Doc {} upload success!", filename)),
+            false => {
+                let pages: Vec<String> = text_content.failed_pages.iter().map(|page| page.to_string()).collect();
+                Ok(format!("Doc {} upload success! Pages {} could not be read.", filename, pages.join(", ")))
+            }
         }
-        
-        // Insert the document and handle error
-        match db.insert_into_collection(&collection_name, vec![embeddings], vec![text_content], filename.clone(), title, file_type, file_size, created_at) {
-            Ok(_) => {
-                // Rebuild index
-                db.build_index(&collection_name)?;
-                Ok(format!("Doc {} upload success!", filename))
-            },
-            Err(e) => Err(e),
+    })
+}
+
+// --- SEARCH ---
+#[update]
+async fn search(
+    query_text: String,
+    top_k: Option<usize>,
+    filters: Option<CollectionQuery>,
+    mode: Option<SearchMode>,
+    params: Option<SearchParams>,
+) -> Result<Vec<SearchResult>, Error> {
+    // get user from ic_cdk::caller()
+    let user = ic_cdk::caller();
+    // check if user is authenticated
+    if user == Principal::anonymous() {
+        return Err(Error::Unauthorized);
+    }
+    // user principal id as collection name
+    let collection_name = user.to_string();
+
+    if query_text.trim().is_empty() {
+        return Err(Error::InvalidInput);
+    }
+    let top_k = top_k.unwrap_or(5); // Default to the 5 closest chunks
+
+    retrieve(&collection_name, query_text, top_k, filters, mode.unwrap_or(SearchMode::Vector), params).await
+}
+
+// Rank the caller's chunks against the query, only embedding it when the mode needs a vector
+async fn retrieve(
+    collection_name: &String,
+    query_text: String,
+    top_k: usize,
+    filters: Option<CollectionQuery>,
+    mode: SearchMode,
+    params: Option<SearchParams>,
+) -> Result<Vec<SearchResult>, Error> {
+    // Nothing uploaded yet, skip the embedding call entirely
+    let exist = DB.with(|db| db.borrow().collections.contains_key(collection_name));
+    if !exist {
+        return Ok(vec![]);
+    }
+
+    if mode == SearchMode::Keyword {
+        return DB.with(|db| {
+            let mut db = db.borrow_mut();
+            db.keyword_query(collection_name, &query_text, top_k, filters, params)
+        });
+    }
+
+    // Embed the query with the same model used for the documents
+    let embeddings = match generate_embeddings(std::slice::from_ref(&query_text), InputType::Query).await {
+        Ok(mut emb) => emb.remove(0),
+        Err(err) => return Err(Error::ModelError(err)),
+    };
+
+    DB.with(|db| {
+        let mut db = db.borrow_mut();
+        match mode {
+            SearchMode::Hybrid { keyword_weight } => {
+                let query = HybridQuery { vector: Vector::from(embeddings), text: &query_text, keyword_weight };
+                db.hybrid_query(collection_name, query, top_k, filters, params)
+            }
+            _ => db.query(collection_name, embeddings, top_k, filters, params),
         }
     })
 }
@@ -159,6 +328,27 @@ async fn delete_document(filename: String) -> Result<String, Error> {
     })
 }
 
+// --- COMPACT INDEX ---
+#[update]
+fn compact_index() -> Result<String, Error> {
+    // get user from ic_cdk::caller()
+    let user = ic_cdk::caller();
+    // check if user is authenticated
+    if user == Principal::anonymous() {
+        return Err(Error::Unauthorized);
+    }
+    // user principal id as collection name
+    let collection_name = user.to_string();
+
+    DB.with(|db| {
+        let mut db = db.borrow_mut();
+        // Rebuild the index without the points of deleted documents, a flat index
+        // past its threshold becomes the configured kind
+        let reclaimed = db.build_index(&collection_name)?;
+        Ok(format!("Index compacted, {} removed chunks dropped", reclaimed))
+    })
+}
+
 // --- LIST DOCS (without embedding) ---
 #[query]
 async fn list_documents(limit: Option<usize>, offset: Option<usize>) -> Result<Vec<DocMetadata>, Error> {
@@ -180,7 +370,8 @@ async fn list_documents(limit: Option<usize>, offset: Option<usize>) -> Result<V
         match db.collections.contains_key(&name.clone()){
             true => {},
             false => {
-                db.create_collection(name.clone(), 1000).unwrap();
+                let dimension = embedding_dimension().map_err(Error::ModelError)?;
+                db.create_collection(name.clone(), dimension, Metric::default(), IndexConfig::default(), ic_cdk::api::time())?;
             }
         };
 
@@ -200,76 +391,88 @@ async fn list_documents(limit: Option<usize>, offset: Option<usize>) -> Result<V
     })
 }
 
-//// LLM Integration | SKIP For now
+// --- CHUNKING CONFIG ---
+#[query]
+fn get_chunk_config() -> Result<ChunkConfig, Error> {
+    // get user from ic_cdk::caller()
+    let user = ic_cdk::caller();
+    // check if user is authenticated
+    if user == Principal::anonymous() {
+        return Err(Error::Unauthorized);
+    }
+    // user principal id as collection name
+    let name = user.to_string();
+
+    DB.with(|db| {
+        let db = db.borrow();
+        match db.collections.contains_key(&name) {
+            true => db.get_chunk_config(&name),
+            false => Ok(ChunkConfig::default()),
+        }
+    })
+}
+
+#[update]
+fn set_chunk_config(config: ChunkConfig) -> Result<String, Error> {
+    // get user from ic_cdk::caller()
+    let user = ic_cdk::caller();
+    // check if user is authenticated
+    if user == Principal::anonymous() {
+        return Err(Error::Unauthorized);
+    }
+    // user principal id as collection name
+    let name = user.to_string();
+
+    DB.with(|db| {
+        let mut db = db.borrow_mut();
+        if !db.collections.contains_key(&name) {
+            let dimension = embedding_dimension().map_err(Error::ModelError)?;
+            db.create_collection(name.clone(), dimension, Metric::default(), IndexConfig::default(), ic_cdk::api::time())?;
+        }
+        // Only documents uploaded from now on are chunked with the new config
+        db.set_chunk_config(&name, config)?;
+        Ok("Chunk config updated".to_string())
+    })
+}
+
+// LLM Integration
 // --- Chat LLM ---
-// #[update]
-// async fn chat(messages: Vec<ChatMessage>) -> Result<String, Error> {
-//     // get user from ic_cdk::caller()
-//     let user = ic_cdk::caller();
-//     // check if user is authenticated
-//     if user == Principal::anonymous() {
-//         return Err(Error::Unauthorized);
-//     }
-//     // user principal id as collection name
-//     let collection_name = user.to_string();
-//
-//     if messages.is_empty() {
-//         return Err(Error::InvalidInput);
-//     }
-//
-//     // Get the last message which should be from user
-//     let last_message = match messages.last() {
-//         Some(msg) if msg.role == "user" => &msg.content,
-//         _ => return Err(Error::InvalidInput),
-//     };
-//
-//     // 1. Create a knowledge base vector retriever from our vector db
-//     let retriever = DB.with(|db| -> Result<VectorDBRetriever, Error> {
-//         let db = db.borrow_mut();
-//         // Check if collection exists
-//         if !db.collections.contains_key(&collection_name) {
-//             return Err(Error::NotFound);
-//         }
-//
-//         Ok(VectorDBRetriever::new(collection_name.clone()))
-//     })?;
-//
-//     // 2. Create chat client
-//     let chat_client = OpenAIChatCompletionClient::try_new(Gpt3Point5Turbo)
-//         .map_err(|_| Error::MemoryError)?;
-//
-//     // 3. Create a RAG chain
-//     let chain: BasicRAGChain<OpenAIChatCompletionClient, VectorDBRetriever> = BasicRAGChain::builder()
-//         .retriever(retriever)
-//         .chat_client(chat_client)
-//         .build();
-//
-//     // let store: PostgresVectorStore =
-//     //     PostgresVectorStore::try_new("embeddings", TextEmbeddingAda002)
-//     //         .await
-//     //         .unwrap();
-//     // let embedding_client: OpenAIEmbeddingClient =
-//     //     OpenAIEmbeddingClient::try_new(TextEmbeddingAda002).unwrap();
-//     // let retriever: PostgresVectorRetriever<OpenAIEmbeddingClient> =
-//     //     store.as_retriever(embedding_client, DistanceFunction::Cosine);
-//     // let chain: BasicRAGChain<OpenAIChatCompletionClient, PostgresVectorRetriever<_>> =
-//     //     BasicRAGChain::builder()
-//     //         .chat_client(chat_client)
-//     //         .retriever(retriever)
-//     //         .build();
-//
-//     // 4. Create a human prompt from the user's message
-//     let prompt: PromptMessage = PromptMessage::HumanMessage(last_message.into());
-//
-//     // 5. Invoke the chain
-//     let response = chain
-//         .invoke_chain(prompt, NonZeroU32::new(2).unwrap())
-//         .await
-//         .map_err(|_| Error::MemoryError)?;
-//
-//     Ok(response.content().to_string())
-//
-// }
+#[update]
+async fn chat(
+    messages: Vec<ChatMessage>,
+    top_k: Option<usize>,
+    filters: Option<CollectionQuery>,
+    mode: Option<SearchMode>,
+    params: Option<SearchParams>,
+) -> Result<ChatResponse, Error> {
+    // get user from ic_cdk::caller()
+    let user = ic_cdk::caller();
+    // check if user is authenticated
+    if user == Principal::anonymous() {
+        return Err(Error::Unauthorized);
+    }
+    // user principal id as collection name
+    let collection_name = user.to_string();
+
+    // Get the last message which should be from user
+    let last_message = match validate_messages(&messages) {
+        Some(content) => content.to_string(),
+        None => return Err(Error::InvalidInput),
+    };
+    let top_k = top_k.unwrap_or(5); // Default to the 5 closest chunks
+
+    // 1. Retrieve the passages closest to the question from the caller's documents
+    let sources = retrieve(&collection_name, last_message, top_k, filters, mode.unwrap_or(SearchMode::Vector), params).await?;
+
+    // 2. Ground the conversation on them and ask the model
+    let prompt = build_prompt(&messages, &sources);
+    let answer = match complete(&prompt).await {
+        Ok(answer) => answer,
+        Err(err) => return Err(Error::ModelError(err)),
+    };
+
+    Ok(ChatResponse { answer, sources })
+}
 
 #[query]
 fn healthcheck() -> String {
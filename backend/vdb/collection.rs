@@ -1,12 +1,22 @@
-use super::index::{generate_index, Vector};
+use super::error::Error;
+use super::flat::IdStore;
+use super::index::{Index, IndexConfig, Metric, Node, NodeStore, PointStore, SearchBudget, Vector};
+use super::ivf::{List, ListStore, DEFAULT_NPROBE};
+use super::lexical::{self, LexicalStats, Posting, TermKey};
+use super::memory::Memory;
+use super::quantization::{Code, Quantization};
+use crate::chunker::ChunkConfig;
 use candid::{CandidType};
 use ciborium::de;
-use ic_stable_structures::{storable::Bound, Storable};
-use instant_distance::{HnswMap, Search};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::clone::Clone;
-use std::{collections::HashMap, usize};
+use std::collections::{HashMap, HashSet};
+
+/// Filters matching at most this many chunks are searched exactly, a graph
+/// search would have to visit most of the graph to find them anyway
+const EXACT_FILTER_LIMIT: usize = 1000;
 
 #[derive(CandidType, Clone, Serialize, Deserialize, Hash, Eq, PartialEq, Debug)]
 pub struct DocMetadata {
@@ -15,6 +25,132 @@ pub struct DocMetadata {
     pub file_type: Option<String>,
     pub file_size: u64,
     pub created_at: u64,
+    // From the document's own metadata, such as a PDF's Info dictionary
+    #[serde(default)]
+    pub author: Option<String>,
+    // When the document says it was created, in milliseconds since the epoch
+    #[serde(default)]
+    pub creation_date: Option<u64>,
+}
+
+/// Where a chunk sits in its document
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct Citation {
+    // Position of the chunk among the document's chunks
+    pub chunk_index: u32,
+    // Character range in the extracted text
+    pub start: usize,
+    pub end: usize,
+    // 1-based page the chunk starts on, for paged documents such as PDFs
+    pub page: Option<u32>,
+    // Headings the chunk starts under, outermost first, for structured documents
+    #[serde(default)]
+    pub section: Vec<String>,
+    // Sheet and first and last row the chunk covers, for spreadsheets and CSV
+    // files, or the record it comes from for JSON
+    #[serde(default)]
+    pub sheet: Option<String>,
+    #[serde(default)]
+    pub rows: Option<(u32, u32)>,
+    // Programming language, qualified name of the function or class and
+    // first and last line the chunk covers, for source code
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub symbol: Option<String>,
+    #[serde(default)]
+    pub lines: Option<(u32, u32)>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Chunk {
+    pub file_name: String,
+    pub text: String,
+    #[serde(default)]
+    pub citation: Citation,
+    // Metadata of the chunk itself, such as the fields of its JSON record or
+    // the sender and recipients of its email
+    #[serde(default)]
+    pub fields: Vec<(String, String)>,
+    // When its record was written, such as its email's date, in milliseconds since the epoch
+    #[serde(default)]
+    pub date: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    pub score: f32,
+    pub text: String,
+    pub metadata: DocMetadata,
+    pub citation: Citation,
+    pub fields: Vec<(String, String)>,
+}
+
+/// How the chunks of a search are ranked
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SearchMode {
+    /// Nearest embeddings, scored by cosine similarity
+    Vector,
+    /// Matching terms, scored with BM25
+    Keyword,
+    /// Both rankings fused with reciprocal rank fusion, `keyword_weight` in
+    /// `[0, 1]` goes from pure vector to pure keyword ranking
+    Hybrid { keyword_weight: f32 },
+}
+
+/// A hybrid search: the query's embedding and text, and how much the keyword
+/// ranking weighs in the fused score, see `SearchMode::Hybrid`
+pub struct HybridQuery<'q> {
+    pub vector: Vector,
+    pub text: &'q str,
+    pub keyword_weight: f32,
+}
+
+/// Per query tuning of the approximate indexes and of which results are kept,
+/// unset values fall back to the collection's configuration
+#[derive(CandidType, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct SearchParams {
+    /// HNSW candidate list size, more finds more of the true neighbours but is slower
+    pub ef_search: Option<usize>,
+    /// IVF lists to scan, the same trade-off
+    pub nprobe: Option<usize>,
+    /// Re-rank by maximal marginal relevance, in `[0, 1]` from most diverse to
+    /// plain relevance order
+    pub mmr_lambda: Option<f32>,
+    /// Drop results scoring below this, on the scale of the search mode's scores
+    pub min_score: Option<f32>,
+    /// Most results returned from a single document
+    pub max_per_document: Option<usize>,
+}
+
+impl SearchParams {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(lambda) = self.mmr_lambda {
+            if !(0.0..=1.0).contains(&lambda) {
+                return Err(Error::InvalidInput);
+            }
+        }
+        if self.max_per_document == Some(0) {
+            return Err(Error::InvalidInput);
+        }
+        Ok(())
+    }
+
+    pub fn budget(&self, config: &IndexConfig, limit: usize) -> SearchBudget {
+        let ef = self.ef_search.unwrap_or(config.hnsw.ef_search as usize).max(1);
+        SearchBudget {
+            ef: ef.max(limit),
+            nprobe: self.nprobe.unwrap_or(DEFAULT_NPROBE),
+        }
+    }
+
+    // Candidates to rank before selecting, re-ranking and capping need more than are returned
+    fn candidates(&self, budget: &SearchBudget, limit: usize) -> usize {
+        match self.mmr_lambda.is_some() || self.max_per_document.is_some() {
+            true => budget.ef,
+            false => limit,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -22,28 +158,372 @@ pub struct Metadata {
     pub docs: HashMap<String, DocMetadata>,
     pub count: u64,
     pub created_at: u64,
+    // Collections stored before documents' chunks had their own map kept their
+    // ids here, see `Database::migrate_doc_chunks`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub doc_chunks: HashMap<String, Vec<u32>>,
 }
 
+/// Collection record kept in stable memory. The vectors, chunk texts and the
+/// index's graph nodes or lists are stored in their own maps, see `CollectionStore`.
 #[derive(Serialize, Deserialize)]
 pub struct Collection {
     pub dimension: usize,
+    // Collections created before the metric was configurable were scored by cosine similarity
+    #[serde(default)]
+    pub metric: Metric,
+    #[serde(default)]
+    pub index_config: IndexConfig,
     pub metadata: Metadata,
-    inner: HnswMap<Vector, String>,
-    keys: Vec<Vector>,
-    values: Vec<String>,
+    #[serde(default)]
+    pub chunk_config: ChunkConfig,
+    // Statistics of the collection's inverted index
+    #[serde(default)]
+    pub lexical: LexicalStats,
+    next_id: u32,
+}
+
+/// Key of a single point in the vector and chunk maps
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct PointKey {
+    pub collection: String,
+    pub id: u32,
+}
+
+impl PointKey {
+    pub fn new(collection: &str, id: u32) -> Self {
+        Self {
+            collection: collection.to_string(),
+            id,
+        }
+    }
+}
+
+impl Storable for PointKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.collection.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let collection = String::from_utf8(bytes[4..].to_vec()).unwrap();
+        PointKey { collection, id }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Key of a chunk in the map of every document's chunks. A document's chunks
+/// are read back with a range scan, the value is the chunk's own date if any.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct DocChunkKey {
+    pub collection: String,
+    pub file_name: String,
+    pub id: u32,
+}
+
+impl DocChunkKey {
+    pub fn new(collection: &str, file_name: &str, id: u32) -> Self {
+        Self {
+            collection: collection.to_string(),
+            file_name: file_name.to_string(),
+            id,
+        }
+    }
+}
+
+impl Storable for DocChunkKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&(self.collection.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.collection.as_bytes());
+        bytes.extend_from_slice(self.file_name.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let len = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+        let collection = String::from_utf8(bytes[6..6 + len].to_vec()).unwrap();
+        let file_name = String::from_utf8(bytes[6 + len..].to_vec()).unwrap();
+        DocChunkKey { collection, file_name, id }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A document's chunk, as kept in the map of every document's chunks
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DocChunk {
+    // Date of the chunk when it has its own, such as an email's
+    pub date: Option<u64>,
+}
+
+impl Storable for DocChunk {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = vec![self.date.is_some() as u8];
+        bytes.extend_from_slice(&self.date.unwrap_or(0).to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let date = u64::from_be_bytes(bytes[1..9].try_into().unwrap());
+        DocChunk { date: (bytes[0] == 1).then_some(date) }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 9, is_fixed_size: true };
+}
+
+impl Storable for Chunk {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        de::from_reader(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The vectors and their quantized codes, graph nodes, IVF-PQ lists or flat
+/// index ids, chunk texts, the chunks of every document and inverted index
+/// entries of one collection
+pub struct CollectionStore<'a> {
+    pub name: &'a String,
+    pub vectors: &'a mut StableBTreeMap<PointKey, Vector, Memory>,
+    pub codes: &'a mut StableBTreeMap<PointKey, Vec<u8>, Memory>,
+    pub nodes: &'a mut StableBTreeMap<PointKey, Node, Memory>,
+    // Keyed by list rather than by point
+    pub lists: &'a mut StableBTreeMap<PointKey, List, Memory>,
+    pub flat_ids: &'a mut StableBTreeMap<PointKey, (), Memory>,
+    pub chunks: &'a mut StableBTreeMap<PointKey, Chunk, Memory>,
+    pub doc_chunks: &'a mut StableBTreeMap<DocChunkKey, DocChunk, Memory>,
+    pub postings: &'a mut StableBTreeMap<TermKey, Posting, Memory>,
+}
+
+impl CollectionStore<'_> {
+    // Ids of a document's chunks along with their own dates
+    fn doc_chunks(&self, file_name: &str) -> Vec<(u32, Option<u64>)> {
+        let range = DocChunkKey::new(self.name, file_name, 0)..=DocChunkKey::new(self.name, file_name, u32::MAX);
+        self.doc_chunks.range(range).map(|(key, chunk)| (key.id, chunk.date)).collect()
+    }
+
+    // Ids of every chunk in the collection, ordered by document
+    fn chunk_ids(&self) -> Vec<u32> {
+        self.doc_chunks
+            .range(DocChunkKey::new(self.name, "", 0)..)
+            .take_while(|(key, _)| &key.collection == self.name)
+            .map(|(key, _)| key.id)
+            .collect()
+    }
+
+    // Forget a point's vector or codes, once no index routes through it anymore
+    fn remove_point(&mut self, id: u32) {
+        self.vectors.remove(&PointKey::new(self.name, id));
+        self.codes.remove(&PointKey::new(self.name, id));
+    }
+
+    // Drop the collection's graph nodes, lists and flat index ids, returns the tombstoned nodes
+    fn clear_index(&mut self) -> Vec<u32> {
+        let range = PointKey::new(self.name, 0)..=PointKey::new(self.name, u32::MAX);
+        let nodes: Vec<(PointKey, Node)> = self.nodes.range(range.clone()).collect();
+        let mut tombstoned = vec![];
+        for (key, node) in nodes {
+            if node.is_deleted() {
+                tombstoned.push(key.id);
+            }
+            self.nodes.remove(&key);
+        }
+        let keys: Vec<PointKey> = self.lists.range(range.clone()).map(|(key, _)| key).collect();
+        for key in keys {
+            self.lists.remove(&key);
+        }
+        let keys: Vec<PointKey> = self.flat_ids.range(range).map(|(key, _)| key).collect();
+        for key in keys {
+            self.flat_ids.remove(&key);
+        }
+        tombstoned
+    }
+}
+
+impl NodeStore for CollectionStore<'_> {
+    fn node(&self, id: u32) -> Option<Cow<'_, Node>> {
+        self.nodes.get(&PointKey::new(self.name, id)).map(Cow::Owned)
+    }
+
+    fn set_node(&mut self, id: u32, node: Node) {
+        self.nodes.insert(PointKey::new(self.name, id), node);
+    }
+}
+
+impl ListStore for CollectionStore<'_> {
+    fn list(&self, list: u32) -> Option<Cow<'_, List>> {
+        self.lists.get(&PointKey::new(self.name, list)).map(Cow::Owned)
+    }
+
+    fn set_list(&mut self, list: u32, entries: List) {
+        self.lists.insert(PointKey::new(self.name, list), entries);
+    }
+}
+
+impl IdStore for CollectionStore<'_> {
+    fn ids(&self) -> Vec<u32> {
+        let range = PointKey::new(self.name, 0)..=PointKey::new(self.name, u32::MAX);
+        self.flat_ids.range(range).map(|(key, _)| key.id).collect()
+    }
+
+    fn insert_id(&mut self, id: u32) -> bool {
+        self.flat_ids.insert(PointKey::new(self.name, id), ()).is_none()
+    }
+
+    fn remove_id(&mut self, id: u32) -> bool {
+        self.flat_ids.remove(&PointKey::new(self.name, id)).is_some()
+    }
+}
+
+/// The points of a collection: the index compares the quantized codes when
+/// the collection has any, the full precision vectors otherwise
+struct IndexPoints<'s> {
+    name: &'s String,
+    vectors: &'s StableBTreeMap<PointKey, Vector, Memory>,
+    codes: &'s StableBTreeMap<PointKey, Vec<u8>, Memory>,
+    quantization: Quantization,
+    dimension: usize,
+}
+
+impl PointStore for IndexPoints<'_> {
+    type Point = Code;
+
+    fn point(&self, id: u32) -> Cow<'_, Code> {
+        let key = PointKey::new(self.name, id);
+        Cow::Owned(match self.quantization {
+            Quantization::None => Code::Float(self.vectors.get(&key).unwrap()),
+            quantization => quantization.code(self.codes.get(&key).unwrap(), self.dimension),
+        })
+    }
+
+    fn query(&self, query: &Vector) -> Code {
+        Code::Float(query.clone())
+    }
+
+    fn vector(&self, id: u32) -> Cow<'_, Vector> {
+        Cow::Owned(self.vectors.get(&PointKey::new(self.name, id)).unwrap())
+    }
+}
+
+/// The graph nodes, lists or flat index ids of one collection, borrowed apart
+/// from its points
+struct IndexEntries<'s> {
+    name: &'s String,
+    nodes: &'s mut StableBTreeMap<PointKey, Node, Memory>,
+    lists: &'s mut StableBTreeMap<PointKey, List, Memory>,
+    flat_ids: &'s mut StableBTreeMap<PointKey, (), Memory>,
+}
+
+impl NodeStore for IndexEntries<'_> {
+    fn node(&self, id: u32) -> Option<Cow<'_, Node>> {
+        self.nodes.get(&PointKey::new(self.name, id)).map(Cow::Owned)
+    }
+
+    fn set_node(&mut self, id: u32, node: Node) {
+        self.nodes.insert(PointKey::new(self.name, id), node);
+    }
+}
+
+impl ListStore for IndexEntries<'_> {
+    fn list(&self, list: u32) -> Option<Cow<'_, List>> {
+        self.lists.get(&PointKey::new(self.name, list)).map(Cow::Owned)
+    }
+
+    fn set_list(&mut self, list: u32, entries: List) {
+        self.lists.insert(PointKey::new(self.name, list), entries);
+    }
+}
+
+impl IdStore for IndexEntries<'_> {
+    fn ids(&self) -> Vec<u32> {
+        let range = PointKey::new(self.name, 0)..=PointKey::new(self.name, u32::MAX);
+        self.flat_ids.range(range).map(|(key, _)| key.id).collect()
+    }
+
+    fn insert_id(&mut self, id: u32) -> bool {
+        self.flat_ids.insert(PointKey::new(self.name, id), ()).is_none()
+    }
+
+    fn remove_id(&mut self, id: u32) -> bool {
+        self.flat_ids.remove(&PointKey::new(self.name, id)).is_some()
+    }
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct CollectionQuery {
     pub title: Option<String>,
     pub file_name: Option<String>,
     pub file_type: Option<String>,
+    // Milliseconds since the epoch, compared with a chunk's own date, such as
+    // its email's, else its document's upload time
     pub date_from: Option<u64>,
     pub date_to: Option<u64>,
+    // Only chunks under a heading containing this text, ignoring case
+    pub section: Option<String>,
+    // Only chunks having every one of these fields, values compared ignoring case
+    pub fields: Option<Vec<(String, String)>>,
+}
+
+impl CollectionQuery {
+    // Check a document's conditions other than its date, chunks may have their own
+    fn matches_document(&self, doc_metadata: &DocMetadata) -> bool {
+        if self.title.is_some() && &doc_metadata.title != self.title.as_ref().unwrap() {
+            return false;
+        }
+        if self.file_name.is_some() && &doc_metadata.file_name != self.file_name.as_ref().unwrap() {
+            return false;
+        }
+        if self.file_type.is_some() && doc_metadata.file_type.as_ref() != self.file_type.as_ref() {
+            return false;
+        }
+        true
+    }
+
+    // Check whether a date in milliseconds since the epoch is in the query's range
+    pub fn matches_date(&self, date: u64) -> bool {
+        self.date_from.is_none_or(|from| date >= from) && self.date_to.is_none_or(|to| date <= to)
+    }
+
+    // Check whether a chunk's section path satisfies the query
+    pub fn matches_section(&self, section: &[String]) -> bool {
+        match &self.section {
+            Some(wanted) => {
+                let wanted = wanted.to_lowercase();
+                section.iter().any(|heading| heading.to_lowercase().contains(&wanted))
+            }
+            None => true,
+        }
+    }
+
+    // Check whether a chunk's fields satisfy the query
+    pub fn matches_fields(&self, fields: &[(String, String)]) -> bool {
+        match &self.fields {
+            Some(wanted) => wanted.iter().all(|(key, value)| {
+                fields.iter().any(|(field, field_value)| field == key && field_value.eq_ignore_ascii_case(value))
+            }),
+            None => true,
+        }
+    }
+
+    // Whether the query has conditions on the chunks themselves, not only on their documents
+    fn filters_chunks(&self) -> bool {
+        self.section.is_some() || self.fields.is_some()
+    }
 }
 
 impl Storable for Collection {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         let mut bytes = vec![];
         ciborium::ser::into_writer(self, &mut bytes).unwrap();
         Cow::Owned(bytes)
@@ -58,39 +538,59 @@ impl Storable for Collection {
 }
 
 impl Collection {
-    pub fn new(keys: Vec<Vector>, values: Vec<String>, dimension: usize) -> Self {
+    pub fn new(dimension: usize, metric: Metric, index_config: IndexConfig, created_at: u64) -> Self {
         Collection {
-            keys: keys.clone(),
-            values: values.clone(),
-            inner: generate_index(keys, values),
+            next_id: 0,
             dimension,
+            metric,
+            index_config,
+            chunk_config: ChunkConfig::default(),
+            lexical: LexicalStats::default(),
             metadata: Metadata {
                 count: 0,
-                created_at: ic_cdk::api::time(),
+                created_at,
                 docs: HashMap::new(),
+                doc_chunks: HashMap::new(),
             },
         }
     }
 
-    // Method baru untuk mencari dokumen berdasarkan metadata
+    /// An empty index of the configured kind
+    pub fn new_index(&self) -> Index {
+        Index::new(self.metric, &self.index_config)
+    }
+
+    fn points<'s>(&self, store: &'s CollectionStore) -> IndexPoints<'s> {
+        IndexPoints {
+            name: store.name,
+            vectors: store.vectors,
+            codes: store.codes,
+            quantization: self.index_config.quantization,
+            dimension: self.dimension,
+        }
+    }
+
+    // The points and the index entries, so that inserts can read the one while writing the other
+    fn points_and_entries<'s>(&self, store: &'s mut CollectionStore) -> (IndexPoints<'s>, IndexEntries<'s>) {
+        let points = IndexPoints {
+            name: store.name,
+            vectors: store.vectors,
+            codes: store.codes,
+            quantization: self.index_config.quantization,
+            dimension: self.dimension,
+        };
+        let entries = IndexEntries { name: store.name, nodes: store.nodes, lists: store.lists, flat_ids: store.flat_ids };
+        (points, entries)
+    }
+
+    // Find the documents whose metadata matches the query
+    #[allow(dead_code)]
     pub fn find(&self, query: CollectionQuery) -> Vec<&DocMetadata> {
         let mut results = Vec::new();
 
-        for (file_name, doc_metadata) in &self.metadata.docs {
+        for doc_metadata in self.metadata.docs.values() {
             // Check if all the specified query conditions match
-            if query.title.is_some() && &doc_metadata.title != query.title.as_ref().unwrap() {
-                continue;
-            }
-            if query.file_name.is_some() && file_name != query.file_name.as_ref().unwrap() {
-                continue;
-            }
-            if query.file_type.is_some() && doc_metadata.file_type.as_ref() != query.file_type.as_ref() {
-                continue;
-            }
-            if query.date_from.is_some() && doc_metadata.created_at < query.date_from.unwrap() {
-                continue;
-            }
-            if query.date_to.is_some() && doc_metadata.created_at > query.date_to.unwrap() {
+            if !query.matches_document(doc_metadata) || !query.matches_date(doc_metadata.created_at) {
                 continue;
             }
 
@@ -103,50 +603,341 @@ impl Collection {
 
     pub fn append(
         &mut self,
+        index: &mut Index,
+        store: &mut CollectionStore,
         keys: &mut Vec<Vector>,
-        values: &mut Vec<String>,
-        file_name: String,
-        title: String,
-        file_type: String,
-        file_size: u64,
-        created_at: u64,
+        values: &mut Vec<Chunk>,
+        doc: DocMetadata,
     ) -> Result<(), String> {
-        let f_name = file_name.clone();
-        self.keys.append(keys);
-        self.values.append(values);
-        let docs_metadata = DocMetadata {
-            title,
-            file_name,
-            file_type: Some(file_type),
-            file_size,
-            created_at,
-        };
-        self.metadata.docs.insert(f_name, docs_metadata);
+        // Re-uploading a file replaces the chunks of the previous version
+        if self.metadata.docs.contains_key(&doc.file_name) {
+            self.remove(index, store, &doc.file_name)?;
+        }
+
+        // Store every new chunk and link it into the existing graph
+        for (key, value) in keys.drain(..).zip(values.drain(..)) {
+            let id = self.next_id;
+            self.next_id += 1;
+            let key = self.metric.prepare(key);
+            // Quantized collections search their codes and rescore with the vector
+            let quantization = self.index_config.quantization;
+            if quantization != Quantization::None {
+                store.codes.insert(PointKey::new(store.name, id), quantization.encode(key.as_slice()));
+            }
+            store.vectors.insert(PointKey::new(store.name, id), key);
+            store.doc_chunks.insert(DocChunkKey::new(store.name, &doc.file_name, id), DocChunk { date: value.date });
+            lexical::index_chunk(store.postings, &mut self.lexical, store.name, id, &value.text);
+            store.chunks.insert(PointKey::new(store.name, id), value);
+            let (points, mut entries) = self.points_and_entries(store);
+            index.insert(id, &points, &mut entries);
+        }
+
+        self.metadata.docs.insert(doc.file_name.clone(), doc);
         self.metadata.count += 1;
 
         Ok(())
     }
 
-    pub fn query(&self, key: &Vector, search: &mut Search, limit: i32) -> Vec<(f32, String)> {
-        let mut res: Vec<(f32, String)> = vec![];
-        let mut iter = self.inner.search(key, search);
-        for _ in 0..limit {
-            match iter.next() {
-                Some(v) => res.push((v.point.cos_sim(key), (*v.value).clone())),
-                None => break,
+    pub fn query(
+        &self,
+        index: &Index,
+        store: &CollectionStore,
+        key: &Vector,
+        params: &SearchParams,
+        limit: usize,
+        filter: Option<&CollectionQuery>,
+    ) -> Vec<SearchResult> {
+        let budget = params.budget(&self.index_config, limit);
+        let ranked = self.vector_ranking(index, store, key, &budget, params.candidates(&budget, limit), filter);
+        self.select(store, ranked, params, limit)
+    }
+
+    pub fn keyword_query(
+        &self,
+        store: &CollectionStore,
+        text: &str,
+        params: &SearchParams,
+        limit: usize,
+        filter: Option<&CollectionQuery>,
+    ) -> Vec<SearchResult> {
+        let budget = params.budget(&self.index_config, limit);
+        let ranked = self.keyword_ranking(store, text, params.candidates(&budget, limit), filter);
+        self.select(store, ranked, params, limit)
+    }
+
+    // Fuse the vector and keyword rankings of up to `budget.ef` candidates each,
+    // a chunk found by only one of them still gets that one's share of the score
+    pub fn hybrid_query(
+        &self,
+        index: &Index,
+        store: &CollectionStore,
+        query: &HybridQuery,
+        params: &SearchParams,
+        limit: usize,
+        filter: Option<&CollectionQuery>,
+    ) -> Vec<SearchResult> {
+        let budget = &params.budget(&self.index_config, limit);
+        let vector = self.vector_ranking(index, store, &query.vector, budget, budget.ef, filter);
+        let keyword = self.keyword_ranking(store, query.text, budget.ef, filter);
+
+        let vector_ids: Vec<u32> = vector.iter().map(|(id, _)| *id).collect();
+        let keyword_ids: Vec<u32> = keyword.iter().map(|(id, _)| *id).collect();
+        let mut results: HashMap<u32, SearchResult> = vector.into_iter().chain(keyword).collect();
+
+        let mut res = vec![];
+        for (score, id) in lexical::fuse(&vector_ids, &keyword_ids, query.keyword_weight).into_iter().take(params.candidates(budget, limit)) {
+            if let Some(mut result) = results.remove(&id) {
+                result.score = score;
+                res.push((id, result));
+            }
+        }
+        self.select(store, res, params, limit)
+    }
+
+    // Pick up to `limit` of the ranked candidates: those scoring under
+    // `min_score` are dropped, and a document at its cap gets no more results.
+    // With `mmr_lambda` set each pick trades relevance against similarity to
+    // the chunks already picked, otherwise the ranking order is kept.
+    fn select(&self, store: &CollectionStore, ranked: Vec<(u32, SearchResult)>, params: &SearchParams, limit: usize) -> Vec<SearchResult> {
+        let mut candidates: Vec<(u32, SearchResult)> = match params.min_score {
+            Some(min_score) => ranked.into_iter().filter(|(_, result)| result.score >= min_score).collect(),
+            None => ranked,
+        };
+        let cap = params.max_per_document.unwrap_or(usize::MAX);
+        let mut per_document: HashMap<String, usize> = HashMap::new();
+        let mut res = vec![];
+
+        let lambda = match params.mmr_lambda {
+            Some(lambda) => lambda,
+            None => {
+                for (_, result) in candidates {
+                    if res.len() >= limit {
+                        break;
+                    }
+                    let count = per_document.entry(result.metadata.file_name.clone()).or_default();
+                    if *count < cap {
+                        *count += 1;
+                        res.push(result);
+                    }
+                }
+                return res;
+            }
+        };
+
+        // Scores of every mode brought to [0, 1] so they weigh like similarities:
+        // relative to the best one, or spread over the range when some are negative
+        let (low, high) = candidates.iter().fold((f32::MAX, f32::MIN), |(low, high), (_, result)| (low.min(result.score), high.max(result.score)));
+        let relevance = |score: f32| match (low >= 0.0, high - low) {
+            (true, _) if high > 0.0 => score / high,
+            (false, range) if range > f32::EPSILON => (score - low) / range,
+            _ => 1.0,
+        };
+        // Chunks are compared by the cosine similarity of their embeddings
+        let points = self.points(store);
+        let mut vectors: Vec<Vector> = candidates.iter().map(|(id, _)| points.vector(*id).normalized()).collect();
+        let mut redundancy: Vec<f32> = vec![0.0; candidates.len()];
+
+        while res.len() < limit && !candidates.is_empty() {
+            // Ties go to the higher ranked candidate
+            let mut best = (f32::MIN, 0);
+            for (i, (_, result)) in candidates.iter().enumerate() {
+                let value = lambda * relevance(result.score) - (1.0 - lambda) * redundancy[i];
+                if value > best.0 {
+                    best = (value, i);
+                }
+            }
+
+            let (_, result) = candidates.remove(best.1);
+            let picked = vectors.remove(best.1);
+            redundancy.remove(best.1);
+
+            let count = per_document.entry(result.metadata.file_name.clone()).or_default();
+            *count += 1;
+            if *count >= cap {
+                // Nothing more of this document can be picked
+                let file_name = result.metadata.file_name.clone();
+                for i in (0..candidates.len()).rev() {
+                    if candidates[i].1.metadata.file_name == file_name {
+                        candidates.remove(i);
+                        vectors.remove(i);
+                        redundancy.remove(i);
+                    }
+                }
+            }
+            for (vector, redundancy) in vectors.iter().zip(redundancy.iter_mut()) {
+                *redundancy = redundancy.max(vector.dot(&picked));
+            }
+            res.push(result);
+        }
+        res
+    }
+
+    fn vector_ranking(
+        &self,
+        index: &Index,
+        store: &CollectionStore,
+        key: &Vector,
+        budget: &SearchBudget,
+        limit: usize,
+        filter: Option<&CollectionQuery>,
+    ) -> Vec<(u32, SearchResult)> {
+        let key = &self.metric.prepare(key.clone());
+        let points = self.points(store);
+        let found = match self.allowed_ids(store, filter) {
+            None => self.rescore(index, &points, key, index.search(key, budget.ef, budget, &points, store)),
+            Some(ids) if ids.len() <= EXACT_FILTER_LIMIT => {
+                let mut found: Vec<(f32, u32)> = ids.into_iter().map(|id| (self.metric.distance(key, &points.vector(id)), id)).collect();
+                found.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+                found.truncate(budget.ef);
+                found
             }
+            Some(ids) => self.rescore(index, &points, key, index.search_filtered(key, budget.ef, budget, &points, store, &|id| ids.contains(&id))),
+        };
+        let hits = found.into_iter().map(|(distance, id)| (self.metric.score(distance), id));
+        self.ranking(store, hits, limit, filter)
+    }
+
+    // Full precision distances for the candidates found on quantized or PQ codes,
+    // closest first. Other indexes already measured them on the vectors.
+    fn rescore(&self, index: &Index, points: &IndexPoints, key: &Vector, mut found: Vec<(f32, u32)>) -> Vec<(f32, u32)> {
+        if !index.is_approximate(self.index_config.quantization) {
+            return found;
+        }
+        for (distance, id) in found.iter_mut() {
+            *distance = self.metric.distance(key, &points.vector(*id));
+        }
+        found.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        found
+    }
+
+    fn keyword_ranking(&self, store: &CollectionStore, text: &str, limit: usize, filter: Option<&CollectionQuery>) -> Vec<(u32, SearchResult)> {
+        let mut hits = lexical::search(store.postings, &self.lexical, store.name, text);
+        if let Some(ids) = self.allowed_ids(store, filter) {
+            hits.retain(|(_, id)| ids.contains(id));
+        }
+        self.ranking(store, hits, limit, filter)
+    }
+
+    // Chunk ids of the documents matching the filter, `None` when every chunk is allowed.
+    // Section and field conditions are checked on the chunks themselves, which have to be read.
+    fn allowed_ids(&self, store: &CollectionStore, filter: Option<&CollectionQuery>) -> Option<HashSet<u32>> {
+        let query = filter?;
+        let ids = self
+            .metadata
+            .docs
+            .values()
+            .filter(|doc_metadata| query.matches_document(doc_metadata))
+            .flat_map(|doc_metadata| {
+                // A chunk's own date, such as its email's, else its document's upload time
+                let chunks = store.doc_chunks(&doc_metadata.file_name).into_iter();
+                chunks.filter(|(_, date)| query.matches_date(date.unwrap_or(doc_metadata.created_at)))
+            })
+            .map(|(id, _)| id)
+            .filter(|id| match query.filters_chunks() {
+                true => store
+                    .chunks
+                    .get(&PointKey::new(store.name, *id))
+                    .is_some_and(|chunk| query.matches_section(&chunk.citation.section) && query.matches_fields(&chunk.fields)),
+                false => true,
+            })
+            .collect();
+        Some(ids)
+    }
+
+    // The first `limit` scored hits still belonging to a document that matches the filter
+    fn ranking(
+        &self,
+        store: &CollectionStore,
+        hits: impl IntoIterator<Item = (f32, u32)>,
+        limit: usize,
+        filter: Option<&CollectionQuery>,
+    ) -> Vec<(u32, SearchResult)> {
+        let mut res = vec![];
+        for (score, id) in hits {
+            if res.len() >= limit {
+                break;
+            }
+
+            // Skip chunks whose document is gone or doesn't match the filter
+            let chunk = match store.chunks.get(&PointKey::new(store.name, id)) {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            let doc_metadata = match self.metadata.docs.get(&chunk.file_name) {
+                Some(doc_metadata) => doc_metadata,
+                None => continue,
+            };
+            if let Some(query) = filter {
+                if !query.matches_document(doc_metadata)
+                    || !query.matches_date(chunk.date.unwrap_or(doc_metadata.created_at))
+                    || !query.matches_section(&chunk.citation.section)
+                    || !query.matches_fields(&chunk.fields)
+                {
+                    continue;
+                }
+            }
+
+            res.push((
+                id,
+                SearchResult {
+                    score,
+                    text: chunk.text,
+                    metadata: doc_metadata.clone(),
+                    citation: chunk.citation,
+                    fields: chunk.fields,
+                },
+            ));
         }
 
         res
     }
-    pub fn build_index(&mut self) {
-        self.inner = generate_index(self.keys.clone(), self.values.clone())
+
+    // Index the chunks of a collection created before keyword search existed
+    pub fn build_lexical_index(&mut self, store: &mut CollectionStore) {
+        self.lexical = LexicalStats::default();
+        for id in store.chunk_ids() {
+            if let Some(chunk) = store.chunks.get(&PointKey::new(store.name, id)) {
+                lexical::index_chunk(store.postings, &mut self.lexical, store.name, id, &chunk.text);
+            }
+        }
+    }
+
+    // Compaction: drop the vectors of removed chunks and rebuild the index from
+    // the live ones only. Inserts and removals never need this. Only a graph
+    // keeps removed points, the other indexes forget them right away.
+    pub fn build_index(&self, store: &mut CollectionStore) -> Index {
+        for id in store.clear_index() {
+            store.remove_point(id);
+        }
+
+        let mut ids = store.chunk_ids();
+        ids.sort();
+        let (points, mut entries) = self.points_and_entries(store);
+        Index::build(self.metric, &self.index_config, ids, &points, &mut entries)
     }
 
     // Method to remove all vectors associated with a file
-    pub fn remove(&mut self, file_name: &String) -> Result<(), String> {
+    pub fn remove(&mut self, index: &mut Index, store: &mut CollectionStore, file_name: &String) -> Result<(), String> {
         // Remove from metadata
-        self.metadata.docs.remove(file_name);
+        if self.metadata.docs.remove(file_name).is_some() {
+            self.metadata.count -= 1;
+        }
+
+        // Drop the document's chunk texts and tombstone its points in the graph.
+        // The vectors stay until the next rebuild since the graph still routes
+        // through them, the other indexes have no such links and let them go now.
+        for (id, _) in store.doc_chunks(file_name) {
+            store.doc_chunks.remove(&DocChunkKey::new(store.name, file_name, id));
+            if let Some(chunk) = store.chunks.remove(&PointKey::new(store.name, id)) {
+                lexical::remove_chunk(store.postings, &mut self.lexical, store.name, id, &chunk.text);
+            }
+            let (points, mut entries) = self.points_and_entries(store);
+            index.remove(id, &points, &mut entries);
+            if !matches!(index, Index::Hnsw(_)) {
+                store.remove_point(id);
+            }
+        }
+
         Ok(())
     }
 }
@@ -1,34 +1,67 @@
-use super::collection::{Collection, DocMetadata, CollectionQuery};
+use super::collection::{Chunk, Citation, Collection, CollectionStore, DocChunk, DocChunkKey, DocMetadata, CollectionQuery, HybridQuery, PointKey, SearchParams, SearchResult};
 use super::error::Error;
-use super::index::Vector;
-use instant_distance::Search;
-use serde::{Deserialize, Serialize};
-use std::{cell::RefCell, collections::HashMap};
+use super::index::{Index, IndexConfig, Metric, Node, Vector};
+use super::ivf::List;
+use super::lexical::{Posting, TermKey};
+use super::memory::{get_chunk_memory, get_code_memory, get_doc_chunk_memory, get_flat_id_memory, get_graph_memory, get_index_memory, get_lexical_memory, get_list_memory, get_stable_btree_memory, get_vector_memory, Memory};
+use crate::chunker::{ChunkConfig, TextChunk};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
 
 thread_local! {
     pub static DB: RefCell<Database> = RefCell::new(Database::new())
 }
 
-#[derive(Serialize, Deserialize)]
+/// All collections live in stable memory, so nothing has to be serialized
+/// across upgrades and every operation only touches the entries it needs.
 pub struct Database {
-    pub collections: HashMap<String, Collection>,
+    pub collections: StableBTreeMap<String, Collection, Memory>,
+    indexes: StableBTreeMap<String, Index, Memory>,
+    vectors: StableBTreeMap<PointKey, Vector, Memory>,
+    // Quantized codes of the collections searching those, their vectors only rescore
+    codes: StableBTreeMap<PointKey, Vec<u8>, Memory>,
+    // Graph nodes of the collections searching an HNSW index
+    nodes: StableBTreeMap<PointKey, Node, Memory>,
+    // Lists of the collections searching an IVF-PQ index, keyed by collection and list
+    lists: StableBTreeMap<PointKey, List, Memory>,
+    // Ids of the points of the collections searching a flat index
+    flat_ids: StableBTreeMap<PointKey, (), Memory>,
+    chunks: StableBTreeMap<PointKey, Chunk, Memory>,
+    // Ids of every document's chunks, with the dates of those having their own
+    doc_chunks: StableBTreeMap<DocChunkKey, DocChunk, Memory>,
+    postings: StableBTreeMap<TermKey, Posting, Memory>,
 }
 
 impl Database {
     pub fn new() -> Self {
         Self {
-            collections: HashMap::new(),
+            collections: StableBTreeMap::init(get_stable_btree_memory()),
+            indexes: StableBTreeMap::init(get_index_memory()),
+            vectors: StableBTreeMap::init(get_vector_memory()),
+            codes: StableBTreeMap::init(get_code_memory()),
+            nodes: StableBTreeMap::init(get_graph_memory()),
+            lists: StableBTreeMap::init(get_list_memory()),
+            flat_ids: StableBTreeMap::init(get_flat_id_memory()),
+            chunks: StableBTreeMap::init(get_chunk_memory()),
+            doc_chunks: StableBTreeMap::init(get_doc_chunk_memory()),
+            postings: StableBTreeMap::init(get_lexical_memory()),
         }
     }
 
-    pub fn create_collection(&mut self, name: String, dimension: usize) -> Result<(), Error> {
+    pub fn create_collection(
+        &mut self,
+        name: String,
+        dimension: usize,
+        metric: Metric,
+        index_config: IndexConfig,
+        created_at: u64,
+    ) -> Result<(), Error> {
         if self.collections.contains_key(&name) {
             return Err(Error::UniqueViolation);
         }
-        let keys: Vec<Vector> = vec![];
-        let values: Vec<String> = vec![];
-
-        let collection: Collection = Collection::new(keys, values, dimension);
+        index_config.validate()?;
+        let collection: Collection = Collection::new(dimension, metric, index_config, created_at);
+        self.indexes.insert(name.clone(), collection.new_index());
         self.collections.insert(name, collection);
         Ok(())
     }
@@ -37,67 +70,399 @@ impl Database {
         &mut self,
         collection_name: &String,
         keys: Vec<Vec<f32>>,
-        values: Vec<String>,
-        file_name: String,
-        title: String,
-        file_type: String,
-        file_size: u64,
-        created_at: u64,
+        values: Vec<TextChunk>,
+        doc: DocMetadata,
     ) -> Result<(), Error> {
-        let collection = self.collections.get_mut(collection_name).ok_or(Error::NotFound)?;
+        let mut collection = self.collections.get(collection_name).ok_or(Error::NotFound)?;
+
+        // Every key needs a value and must fit the collection's dimension
+        if keys.len() != values.len() || keys.iter().any(|key| key.len() != collection.dimension) {
+            return Err(Error::DimensionMismatch);
+        }
+
+        let mut index = self.indexes.get(collection_name).unwrap_or_else(|| collection.new_index());
 
         let mut points: Vec<Vector> = vec![];
-        let mut _values: Vec<String> = vec![];
+        let mut _values: Vec<Chunk> = vec![];
 
-        for i in 0..keys.len() {
-            let key = &keys[i];
-            let point = Vector::from((*key).clone());
-            points.push(point);
-            _values.push(values[i].clone());
+        for (i, (key, value)) in keys.into_iter().zip(values).enumerate() {
+            points.push(Vector::from(key));
+            _values.push(Chunk {
+                file_name: doc.file_name.clone(),
+                text: value.text,
+                citation: Citation {
+                    chunk_index: i as u32,
+                    start: value.start,
+                    end: value.end,
+                    page: value.page,
+                    section: value.section,
+                    sheet: value.sheet,
+                    rows: value.rows,
+                    language: value.language,
+                    symbol: value.symbol,
+                    lines: value.lines,
+                },
+                fields: value.fields,
+                date: value.date,
+            });
         }
 
-        let _ = collection.append(&mut points, &mut _values, file_name, title, file_type, file_size, created_at);
+        let mut store = CollectionStore {
+            name: collection_name,
+            vectors: &mut self.vectors,
+            codes: &mut self.codes,
+            nodes: &mut self.nodes,
+            lists: &mut self.lists,
+            flat_ids: &mut self.flat_ids,
+            chunks: &mut self.chunks,
+            doc_chunks: &mut self.doc_chunks,
+            postings: &mut self.postings,
+        };
+        collection
+            .append(&mut index, &mut store, &mut points, &mut _values, doc)
+            .map_err(|_| Error::DBError)?;
+
+        self.collections.insert(collection_name.clone(), collection);
+        self.indexes.insert(collection_name.clone(), index);
         Ok(())
     }
 
-    pub fn build_index(&mut self, name: &String) -> Result<(), Error> {
-        let collection = self.collections.get_mut(name).ok_or(Error::NotFound)?;
-        collection.build_index();
-        Ok(())
+    /// Rebuild a collection's index from scratch, dropping tombstoned points.
+    /// Uploads and deletions update the index in place, this is only for compaction.
+    /// Returns the number of removed points reclaimed.
+    pub fn build_index(&mut self, name: &String) -> Result<usize, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        let tombstones = self.indexes.get(name).map_or(0, |index| index.tombstones());
+        let mut store = CollectionStore {
+            name,
+            vectors: &mut self.vectors,
+            codes: &mut self.codes,
+            nodes: &mut self.nodes,
+            lists: &mut self.lists,
+            flat_ids: &mut self.flat_ids,
+            chunks: &mut self.chunks,
+            doc_chunks: &mut self.doc_chunks,
+            postings: &mut self.postings,
+        };
+        let index = collection.build_index(&mut store);
+
+        self.indexes.insert(name.clone(), index);
+        Ok(tombstones)
     }
 
     pub fn query(
         &mut self,
         name: &String,
         q: Vec<f32>,
-        limit: i32,
-    ) -> Result<Vec<(f32, String)>, String> {
+        limit: usize,
+        filter: Option<CollectionQuery>,
+        params: Option<SearchParams>,
+    ) -> Result<Vec<SearchResult>, Error> {
         let collection = match self.collections.get(name) {
             Some(value) => value,
-            None => return Err(Error::NotFound.to_string()),
+            None => return Err(Error::NotFound),
         };
+        let params = params.unwrap_or_default();
+        params.validate()?;
 
         if q.len() != collection.dimension {
-            return Err(String::from("query malformed"));
+            return Err(Error::DimensionMismatch);
         }
 
-        let mut search = Search::default();
+        let index = self.indexes.get(name).unwrap_or_else(|| collection.new_index());
+        let store = CollectionStore {
+            name,
+            vectors: &mut self.vectors,
+            codes: &mut self.codes,
+            nodes: &mut self.nodes,
+            lists: &mut self.lists,
+            flat_ids: &mut self.flat_ids,
+            chunks: &mut self.chunks,
+            doc_chunks: &mut self.doc_chunks,
+            postings: &mut self.postings,
+        };
         let v = Vector::from(q);
-        let result = collection.query(&v, &mut search, limit);
+        let result = collection.query(&index, &store, &v, &params, limit, filter.as_ref());
 
         Ok(result)
     }
 
+    pub fn keyword_query(
+        &mut self,
+        name: &String,
+        text: &str,
+        limit: usize,
+        filter: Option<CollectionQuery>,
+        params: Option<SearchParams>,
+    ) -> Result<Vec<SearchResult>, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        let params = params.unwrap_or_default();
+        params.validate()?;
+
+        let store = CollectionStore {
+            name,
+            vectors: &mut self.vectors,
+            codes: &mut self.codes,
+            nodes: &mut self.nodes,
+            lists: &mut self.lists,
+            flat_ids: &mut self.flat_ids,
+            chunks: &mut self.chunks,
+            doc_chunks: &mut self.doc_chunks,
+            postings: &mut self.postings,
+        };
+        Ok(collection.keyword_query(&store, text, &params, limit, filter.as_ref()))
+    }
+
+    pub fn hybrid_query(
+        &mut self,
+        name: &String,
+        query: HybridQuery,
+        limit: usize,
+        filter: Option<CollectionQuery>,
+        params: Option<SearchParams>,
+    ) -> Result<Vec<SearchResult>, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        let params = params.unwrap_or_default();
+        params.validate()?;
+
+        if query.vector.dimension() != collection.dimension {
+            return Err(Error::DimensionMismatch);
+        }
+
+        let index = self.indexes.get(name).unwrap_or_else(|| collection.new_index());
+        let store = CollectionStore {
+            name,
+            vectors: &mut self.vectors,
+            codes: &mut self.codes,
+            nodes: &mut self.nodes,
+            lists: &mut self.lists,
+            flat_ids: &mut self.flat_ids,
+            chunks: &mut self.chunks,
+            doc_chunks: &mut self.doc_chunks,
+            postings: &mut self.postings,
+        };
+        let result = collection.hybrid_query(&index, &store, &query, &params, limit, filter.as_ref());
+
+        Ok(result)
+    }
+
+    /// Normalise the vectors of cosine collections stored before vectors were
+    /// normalised on insert, and rebuild their graphs for the cosine distance.
+    /// Returns the names of the collections migrated.
+    pub fn migrate_cosine_vectors(&mut self) -> Vec<String> {
+        let names: Vec<String> = self
+            .collections
+            .iter()
+            .filter(|(_, collection)| collection.metric == Metric::Cosine)
+            .map(|(name, _)| name)
+            .collect();
+
+        let mut migrated = vec![];
+        for name in names {
+            // Every vector is checked, an upload cut short may have left some of them
+            // normalised and others not. Zero vectors have no direction to keep.
+            let range = PointKey::new(&name, 0)..=PointKey::new(&name, u32::MAX);
+            let points: Vec<(PointKey, Vector)> = self
+                .vectors
+                .range(range)
+                .filter(|(_, vector)| vector.norm() != 0.0 && (vector.norm() - 1.0).abs() >= 1e-3)
+                .collect();
+            if points.is_empty() {
+                continue;
+            }
+
+            for (key, vector) in points {
+                self.vectors.insert(key, vector.normalized());
+            }
+            if self.build_index(&name).is_ok() {
+                migrated.push(name);
+            }
+        }
+
+        migrated
+    }
+
+    /// Move the nodes of graphs stored whole, before nodes had their own map,
+    /// to the node map. Returns the names of the collections migrated.
+    pub fn migrate_graph_nodes(&mut self) -> Vec<String> {
+        let indexes: Vec<(String, Index)> = self.indexes.iter().collect();
+
+        let mut migrated = vec![];
+        for (name, mut index) in indexes {
+            let nodes = index.take_nodes();
+            if nodes.is_empty() {
+                continue;
+            }
+            for (id, node) in nodes {
+                self.nodes.insert(PointKey::new(&name, id), node);
+            }
+            self.indexes.insert(name.clone(), index);
+            migrated.push(name);
+        }
+
+        migrated
+    }
+
+    /// Move the ids of flat indexes stored whole, before ids had their own map,
+    /// to the id map. Returns the names of the collections migrated.
+    pub fn migrate_flat_ids(&mut self) -> Vec<String> {
+        let indexes: Vec<(String, Index)> = self.indexes.iter().collect();
+
+        let mut migrated = vec![];
+        for (name, mut index) in indexes {
+            let ids = index.take_ids();
+            if ids.is_empty() {
+                continue;
+            }
+            for id in ids {
+                self.flat_ids.insert(PointKey::new(&name, id), ());
+            }
+            self.indexes.insert(name.clone(), index);
+            migrated.push(name);
+        }
+
+        migrated
+    }
+
+    /// Move the chunk ids of every document out of collections stored before
+    /// they had their own map. Returns the names of the collections migrated.
+    pub fn migrate_doc_chunks(&mut self) -> Vec<String> {
+        let names: Vec<String> = self
+            .collections
+            .iter()
+            .filter(|(_, collection)| !collection.metadata.doc_chunks.is_empty())
+            .map(|(name, _)| name)
+            .collect();
+
+        for name in &names {
+            let mut collection = self.collections.get(name).unwrap();
+            for (file_name, ids) in std::mem::take(&mut collection.metadata.doc_chunks) {
+                for id in ids {
+                    let date = self.chunks.get(&PointKey::new(name, id)).and_then(|chunk| chunk.date);
+                    self.doc_chunks.insert(DocChunkKey::new(name, &file_name, id), DocChunk { date });
+                }
+            }
+            self.collections.insert(name.clone(), collection);
+        }
+
+        names
+    }
+
+    /// Build the inverted index of collections whose chunks were stored before
+    /// keyword search existed. Returns the names of the collections indexed.
+    pub fn migrate_lexical_index(&mut self) -> Vec<String> {
+        let names: Vec<String> = self
+            .collections
+            .iter()
+            .filter(|(_, collection)| collection.lexical.chunks == 0 && !collection.metadata.docs.is_empty())
+            .map(|(name, _)| name)
+            .collect();
+
+        for name in &names {
+            let mut collection = self.collections.get(name).unwrap();
+            let mut store = CollectionStore {
+                name,
+                vectors: &mut self.vectors,
+                codes: &mut self.codes,
+                nodes: &mut self.nodes,
+                lists: &mut self.lists,
+                flat_ids: &mut self.flat_ids,
+                chunks: &mut self.chunks,
+                doc_chunks: &mut self.doc_chunks,
+                postings: &mut self.postings,
+            };
+            collection.build_lexical_index(&mut store);
+            self.collections.insert(name.clone(), collection);
+        }
+
+        names
+    }
+
+    /// Record `dimension` on every collection created with another one.
+    /// Collections already holding vectors of a different length can't be fixed
+    /// this way, their names are returned so the documents can be re-uploaded.
+    pub fn migrate_dimension(&mut self, dimension: usize) -> Vec<String> {
+        let names: Vec<String> = self
+            .collections
+            .iter()
+            .filter(|(_, collection)| collection.dimension != dimension)
+            .map(|(name, _)| name)
+            .collect();
+
+        let mut stale = vec![];
+        for name in names {
+            let range = PointKey::new(&name, 0)..=PointKey::new(&name, u32::MAX);
+            if !self.vectors.range(range).all(|(_, vector)| vector.dimension() == dimension) {
+                stale.push(name);
+                continue;
+            }
+
+            let mut collection = self.collections.get(&name).unwrap();
+            collection.dimension = dimension;
+            self.collections.insert(name, collection);
+        }
+
+        stale
+    }
+
+    #[allow(dead_code)]
     pub fn delete_collection(&mut self, name: &String) -> Result<(), Error> {
-        if let Some(_) = self.collections.remove(name) {
-            Ok(())
-        } else {
-            Err(Error::NotFound)
+        if self.collections.remove(name).is_none() {
+            return Err(Error::NotFound);
+        }
+        self.indexes.remove(name);
+
+        // Drop every vector, code, graph node, list, flat index id, chunk text and document chunk stored under the collection
+        let range = PointKey::new(name, 0)..=PointKey::new(name, u32::MAX);
+        let keys: Vec<PointKey> = self.vectors.range(range.clone()).map(|(key, _)| key).collect();
+        for key in keys {
+            self.vectors.remove(&key);
         }
+        let keys: Vec<PointKey> = self.codes.range(range.clone()).map(|(key, _)| key).collect();
+        for key in keys {
+            self.codes.remove(&key);
+        }
+        let keys: Vec<PointKey> = self.nodes.range(range.clone()).map(|(key, _)| key).collect();
+        for key in keys {
+            self.nodes.remove(&key);
+        }
+        let keys: Vec<PointKey> = self.lists.range(range.clone()).map(|(key, _)| key).collect();
+        for key in keys {
+            self.lists.remove(&key);
+        }
+        let keys: Vec<PointKey> = self.flat_ids.range(range.clone()).map(|(key, _)| key).collect();
+        for key in keys {
+            self.flat_ids.remove(&key);
+        }
+        let keys: Vec<PointKey> = self.chunks.range(range).map(|(key, _)| key).collect();
+        for key in keys {
+            self.chunks.remove(&key);
+        }
+        let keys: Vec<DocChunkKey> = self
+            .doc_chunks
+            .range(DocChunkKey::new(name, "", 0)..)
+            .take_while(|(key, _)| &key.collection == name)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.doc_chunks.remove(&key);
+        }
+        let keys: Vec<TermKey> = self
+            .postings
+            .range(TermKey::new(name, "", 0)..)
+            .take_while(|(key, _)| &key.collection == name)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.postings.remove(&key);
+        }
+
+        Ok(())
     }
 
+    #[allow(dead_code)]
     pub fn get_all_collections(&self) -> Vec<String> {
-        self.collections.iter().map(|(id, _)| id.clone()).collect()
+        self.collections.iter().map(|(id, _)| id).collect()
     }
 
     pub fn get_docs(&mut self, index_name: &String) -> Result<Vec<DocMetadata>, Error> {
@@ -113,9 +478,28 @@ impl Database {
         Ok(docs)
     }
 
-    pub fn get_docs_by_query(&mut self, name: &String, query: CollectionQuery) -> Result<Vec<&DocMetadata>, Error> {
-        let collection = self.collections.get_mut(name).ok_or(Error::NotFound)?;
-        let docs = collection.find(query);
+    pub fn get_index_config(&self, name: &String) -> Result<IndexConfig, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        Ok(collection.index_config)
+    }
+
+    pub fn get_chunk_config(&self, name: &String) -> Result<ChunkConfig, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        Ok(collection.chunk_config)
+    }
+
+    pub fn set_chunk_config(&mut self, name: &String, config: ChunkConfig) -> Result<(), Error> {
+        config.validate()?;
+        let mut collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        collection.chunk_config = config;
+        self.collections.insert(name.clone(), collection);
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn get_docs_by_query(&mut self, name: &String, query: CollectionQuery) -> Result<Vec<DocMetadata>, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        let docs = collection.find(query).into_iter().cloned().collect();
         Ok(docs)
     }
 
@@ -124,38 +508,88 @@ impl Database {
         name: &String,
         file_name: &String,
     ) -> Result<(), Error> {
-        let collection = self.collections.get_mut(&name.clone()).ok_or(Error::NotFound)?;
+        let mut collection = self.collections.get(name).ok_or(Error::NotFound)?;
         
         // Check if the file exists in the collection
         if !collection.metadata.docs.contains_key(file_name) {
             return Err(Error::NotFound);
         }
 
-        collection.remove(file_name);
+        let mut index = self.indexes.get(name).unwrap_or_else(|| collection.new_index());
+        let mut store = CollectionStore {
+            name,
+            vectors: &mut self.vectors,
+            codes: &mut self.codes,
+            nodes: &mut self.nodes,
+            lists: &mut self.lists,
+            flat_ids: &mut self.flat_ids,
+            chunks: &mut self.chunks,
+            doc_chunks: &mut self.doc_chunks,
+            postings: &mut self.postings,
+        };
+        // Tombstones the document's points, `build_index` reclaims them later
+        collection.remove(&mut index, &mut store, file_name).map_err(|_| Error::DBError)?;
 
-        // Rebuild the index
-        collection.build_index();
-        
+        self.collections.insert(name.clone(), collection);
+        self.indexes.insert(name.clone(), index);
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Database, Error, CollectionQuery};
+    use super::{Citation, Database, DocMetadata, Error, CollectionQuery, HybridQuery, IndexConfig, Memory, Metric, PointKey, SearchParams, Vector};
+    use crate::chunker::{chunk_document, ChunkConfig, TextChunk};
+    use crate::extractor::email_file::extract_text_from_mbox;
+    use crate::vdb::index::{Index, IndexKind};
+    use crate::vdb::quantization::Quantization;
+    use ic_stable_structures::{StableBTreeMap, Storable};
+
+    // Bytes taken by the keys and values of a map
+    fn stored_bytes<K: Storable + Ord + Clone, V: Storable>(map: &StableBTreeMap<K, V, Memory>) -> usize {
+        map.iter().map(|(key, value)| key.to_bytes().len() + value.to_bytes().len()).sum()
+    }
+
+    fn document(file_name: String, title: String, file_type: String, file_size: u64, created_at: u64) -> DocMetadata {
+        DocMetadata { title, file_name, file_type: Some(file_type), file_size, created_at, author: None, creation_date: None }
+    }
+
+    fn hybrid(vector: Vec<f32>, text: &str, keyword_weight: f32) -> HybridQuery<'_> {
+        HybridQuery { vector: Vector::from(vector), text, keyword_weight }
+    }
+
+    fn chunks(texts: &[&str]) -> Vec<TextChunk> {
+        texts
+            .iter()
+            .map(|text| TextChunk {
+                text: text.to_string(),
+                start: 0,
+                end: text.chars().count(),
+                page: None,
+                section: vec![],
+                sheet: None,
+                rows: None,
+                language: None,
+                symbol: None,
+                lines: None,
+                fields: vec![],
+                date: None,
+            })
+            .collect()
+    }
 
     #[test]
     fn create_collection() {
         let mut db: Database = Database::new();
-        let result = db.create_collection("test".to_string(), 3);
+        let result = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
         assert!(result.is_ok())
     }
 
     #[test]
     fn create_duplicate_collection() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
-        let result = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+        let result = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
         let expected = Err(Error::UniqueViolation);
         assert_eq!(result, expected);
     }
@@ -163,7 +597,7 @@ mod tests {
     #[test]
     fn delete_existing_collection() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
         assert_eq!(db.delete_collection(&"test".to_string()), Ok(()))
     }
 
@@ -179,110 +613,94 @@ mod tests {
     #[test]
     fn build_index() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
         let keys: Vec<Vec<f32>> = vec![
             vec![10.0, 12.0, 4.5],
             vec![10.0, 11.0, 10.5],
             vec![10.0, 20.5, 15.0],
         ];
-        let values: Vec<String> = vec!["red".to_string(), "green".to_string(), "blue".to_string()];
+        let values = chunks(&["red", "green", "blue"]);
         let _ = db.insert_into_collection(
             &"test".to_string(),
             keys,
             values,
-            "test_file.txt".to_string(),
-            "Test Document".to_string(),
-            "text".to_string(),
-            1024,
-            1234567890,
+            document("test_file.txt".to_string(), "Test Document".to_string(), "text".to_string(), 1024, 1234567890),
         );
         let result = db.build_index(&"test".to_string());
-        assert_eq!(result, Ok(()));
+        assert_eq!(result, Ok(0));
     }
 
     #[test]
     fn append_and_build_index() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
 
         let keys: Vec<Vec<f32>> = vec![
             vec![10.0, 12.0, 4.5],
             vec![10.0, 11.0, 10.5],
             vec![10.0, 20.5, 15.0],
         ];
-        let values: Vec<String> = vec!["red".to_string(), "green".to_string(), "blue".to_string()];
+        let values = chunks(&["red", "green", "blue"]);
         let _ = db.insert_into_collection(
             &"test".to_string(),
             keys,
             values,
-            "test_file1.txt".to_string(),
-            "Test Document 1".to_string(),
-            "text".to_string(),
-            1024,
-            1234567890,
+            document("test_file1.txt".to_string(), "Test Document 1".to_string(), "text".to_string(), 1024, 1234567890),
         );
         let _ = db.build_index(&"test".to_string());
 
         let keys: Vec<Vec<f32>> = vec![vec![20.0, 20.5, 15.0]];
-        let values: Vec<String> = vec!["black".to_string()];
+        let values = chunks(&["black"]);
         let _ = db.insert_into_collection(
             &"test".to_string(),
             keys,
             values,
-            "test_file2.txt".to_string(),
-            "Test Document 2".to_string(),
-            "text".to_string(),
-            2048,
-            1234567891,
+            document("test_file2.txt".to_string(), "Test Document 2".to_string(), "text".to_string(), 2048, 1234567891),
         );
         let result = db.build_index(&"test".to_string());
-        assert_eq!(result, Ok(()));
+        assert_eq!(result, Ok(0));
     }
 
     #[test]
     fn delete_collection_with_embeddings() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
         let keys: Vec<Vec<f32>> = vec![
             vec![10.0, 12.0, 4.5],
             vec![10.0, 11.0, 10.5],
             vec![10.0, 20.5, 15.0],
         ];
-        let values: Vec<String> = vec!["red".to_string(), "green".to_string(), "blue".to_string()];
+        let values = chunks(&["red", "green", "blue"]);
         let _ = db.insert_into_collection(
             &"test".to_string(),
             keys,
             values,
-            "test_file.txt".to_string(),
-            "Test Document".to_string(),
-            "text".to_string(),
-            1024,
-            1234567890,
+            document("test_file.txt".to_string(), "Test Document".to_string(), "text".to_string(), 1024, 1234567890),
         );
         let _ = db.build_index(&"test".to_string());
         assert_eq!(db.delete_collection(&"test".to_string()), Ok(()));
+        assert_eq!(db.vectors.len(), 0);
+        assert_eq!(db.chunks.len(), 0);
+        assert_eq!(db.postings.len(), 0);
+        assert!(!db.indexes.contains_key(&"test".to_string()));
     }
 
     #[test]
     fn insert_into_collection_dimensions_mismatch_keys_values() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
 
         let keys: Vec<Vec<f32>> = vec![
             vec![10.0, 12.0, 4.5],
             vec![10.0, 11.0, 10.5],
             vec![10.0, 20.5, 15.0],
         ];
-        let values: Vec<String> = vec!["red".to_string(), "green".to_string()];
+        let values = chunks(&["red", "green"]);
         let result = db.insert_into_collection(
             &"test".to_string(),
             keys,
             values,
-            "test_file.txt".to_string(),
-            "Test Document".to_string(),
-            "text".to_string(),
-            1024,
-            1234567890,
+            document("test_file.txt".to_string(), "Test Document".to_string(), "text".to_string(), 1024, 1234567890),
         );
 
         assert_eq!(result, Err(Error::DimensionMismatch));
@@ -291,20 +709,16 @@ mod tests {
     #[test]
     fn test_query_documents() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
         
         // Insert test documents
         let keys: Vec<Vec<f32>> = vec![vec![10.0, 12.0, 4.5]];
-        let values: Vec<String> = vec!["content1".to_string()];
+        let values = chunks(&["content1"]);
         let _ = db.insert_into_collection(
             &"test".to_string(),
             keys,
             values,
-            "doc1.pdf".to_string(),
-            "PDF Document".to_string(),
-            "pdf".to_string(),
-            1024,
-            1234567890,
+            document("doc1.pdf".to_string(), "PDF Document".to_string(), "pdf".to_string(), 1024, 1234567890),
         );
 
         // Test query by file type
@@ -314,6 +728,8 @@ mod tests {
             file_type: Some("pdf".to_string()),
             date_from: None,
             date_to: None,
+            section: None,
+            fields: None,
         };
 
         let results = db.get_docs_by_query(&"test".to_string(), query).unwrap();
@@ -324,33 +740,25 @@ mod tests {
     #[test]
     fn test_query_documents_by_date_range() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
         
         // Insert documents with different dates
         let keys1: Vec<Vec<f32>> = vec![vec![10.0, 12.0, 4.5]];
-        let values1: Vec<String> = vec!["content1".to_string()];
+        let values1 = chunks(&["content1"]);
         let _ = db.insert_into_collection(
             &"test".to_string(),
             keys1,
             values1,
-            "doc1.txt".to_string(),
-            "Old Document".to_string(),
-            "text".to_string(),
-            1024,
-            1000000, // Older timestamp
+            document("doc1.txt".to_string(), "Old Document".to_string(), "text".to_string(), 1024, 1000000), // Older timestamp
         );
 
         let keys2: Vec<Vec<f32>> = vec![vec![11.0, 13.0, 5.5]];
-        let values2: Vec<String> = vec!["content2".to_string()];
+        let values2 = chunks(&["content2"]);
         let _ = db.insert_into_collection(
             &"test".to_string(),
             keys2,
             values2,
-            "doc2.txt".to_string(),
-            "New Document".to_string(),
-            "text".to_string(),
-            2048,
-            2000000, // Newer timestamp
+            document("doc2.txt".to_string(), "New Document".to_string(), "text".to_string(), 2048, 2000000), // Newer timestamp
         );
 
         // Query documents within date range
@@ -360,6 +768,8 @@ mod tests {
             file_type: None,
             date_from: Some(1500000),
             date_to: Some(2500000),
+            section: None,
+            fields: None,
         };
 
         let results = db.get_docs_by_query(&"test".to_string(), query).unwrap();
@@ -370,21 +780,17 @@ mod tests {
     #[test]
     fn test_remove_document() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
         
         // Insert a test document
         let keys: Vec<Vec<f32>> = vec![vec![10.0, 12.0, 4.5]];
-        let values: Vec<String> = vec!["content".to_string()];
+        let values = chunks(&["content"]);
         let filename = "test_doc.txt".to_string();
         let _ = db.insert_into_collection(
             &"test".to_string(),
             keys,
             values,
-            filename.clone(),
-            "Test Document".to_string(),
-            "text".to_string(),
-            1024,
-            1234567890,
+            document(filename.clone(), "Test Document".to_string(), "text".to_string(), 1024, 1234567890),
         );
 
         // Remove the document
@@ -399,20 +805,16 @@ mod tests {
     #[test]
     fn test_query_by_title() {
         let mut db: Database = Database::new();
-        let _ = db.create_collection("test".to_string(), 3);
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
         
         // Insert test document
         let keys: Vec<Vec<f32>> = vec![vec![10.0, 12.0, 4.5]];
-        let values: Vec<String> = vec!["content".to_string()];
+        let values = chunks(&["content"]);
         let _ = db.insert_into_collection(
             &"test".to_string(),
             keys,
             values,
-            "doc.txt".to_string(),
-            "Unique Title".to_string(),
-            "text".to_string(),
-            1024,
-            1234567890,
+            document("doc.txt".to_string(), "Unique Title".to_string(), "text".to_string(), 1024, 1234567890),
         );
 
         // Query by title
@@ -422,10 +824,972 @@ mod tests {
             file_type: None,
             date_from: None,
             date_to: None,
+            section: None,
+            fields: None,
         };
 
         let results = db.get_docs_by_query(&"test".to_string(), query).unwrap();
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].title, "Unique Title");
     }
+
+    #[test]
+    fn test_vector_search() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let keys: Vec<Vec<f32>> = vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]];
+        let values = chunks(&["about apples", "about pears"]);
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            keys,
+            values,
+            document("fruits.txt".to_string(), "Fruits".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        let _ = db.build_index(&"test".to_string());
+
+        let results = db.query(&"test".to_string(), vec![0.9, 0.1, 0.0], 1, None, None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "about apples");
+        assert_eq!(results[0].metadata.file_name, "fruits.txt");
+        assert!(results[0].score > 0.9);
+    }
+
+    #[test]
+    fn test_search_results_carry_citation() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let values = vec![
+            TextChunk {
+                text: "intro".to_string(),
+                start: 0,
+                end: 5,
+                page: Some(1),
+                section: vec![],
+                sheet: None,
+                rows: None,
+                language: None,
+                symbol: None,
+                lines: None,
+                fields: vec![],
+                date: None,
+            },
+            TextChunk {
+                text: "findings".to_string(),
+                start: 120,
+                end: 128,
+                page: Some(12),
+                section: vec!["Results".to_string()],
+                sheet: None,
+                rows: None,
+                language: None,
+                symbol: None,
+                lines: None,
+                fields: vec![],
+                date: None,
+            },
+        ];
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]],
+            values,
+            document("report.pdf".to_string(), "Report".to_string(), "pdf".to_string(), 1024, 1234567890),
+        );
+
+        let results = db.query(&"test".to_string(), vec![0.1, 0.9, 0.0], 1, None, None).unwrap();
+        assert_eq!(results[0].metadata.file_name, "report.pdf");
+        assert_eq!(
+            results[0].citation,
+            Citation {
+                chunk_index: 1,
+                start: 120,
+                end: 128,
+                page: Some(12),
+                section: vec!["Results".to_string()],
+                sheet: None,
+                rows: None,
+                language: None,
+                symbol: None,
+                lines: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_section_filter() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let chunk = |text: &str, section: &[&str]| TextChunk {
+            text: text.to_string(),
+            start: 0,
+            end: text.len(),
+            page: None,
+            section: section.iter().map(|title| title.to_string()).collect(),
+            sheet: None,
+            rows: None,
+            language: None,
+            symbol: None,
+            lines: None,
+            fields: vec![],
+            date: None,
+        };
+        let values = vec![
+            chunk("overview", &["Guide"]),
+            chunk("apt get", &["Guide", "Install", "Linux"]),
+            chunk("brew", &["Guide", "Install", "macOS"]),
+        ];
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0], vec![0.9, 0.1, 0.0], vec![0.8, 0.2, 0.0]],
+            values,
+            document("guide.md".to_string(), "Guide".to_string(), "markdown".to_string(), 1024, 1234567890),
+        );
+
+        let filter = |section: &str| CollectionQuery {
+            title: None,
+            file_name: None,
+            file_type: None,
+            date_from: None,
+            date_to: None,
+            section: Some(section.to_string()),
+            fields: None,
+        };
+        let results = db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 5, Some(filter("install")), None).unwrap();
+        assert_eq!(results.iter().map(|r| r.text.as_str()).collect::<Vec<_>>(), vec!["apt get", "brew"]);
+        assert_eq!(results[1].citation.section, vec!["Guide", "Install", "macOS"]);
+
+        let results = db.keyword_query(&"test".to_string(), "brew", 5, Some(filter("linux")), None).unwrap();
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn test_field_filter() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let chunk = |text: &str, fields: &[(&str, &str)]| TextChunk {
+            text: text.to_string(),
+            start: 0,
+            end: text.len(),
+            page: None,
+            section: vec![],
+            sheet: None,
+            rows: None,
+            language: None,
+            symbol: None,
+            lines: None,
+            fields: fields.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            date: None,
+        };
+        let values = vec![
+            chunk("login loops", &[("status", "open"), ("tags", "auth")]),
+            chunk("export fixed", &[("status", "closed"), ("tags", "auth")]),
+            chunk("slow page", &[("status", "open")]),
+        ];
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0], vec![0.9, 0.1, 0.0], vec![0.8, 0.2, 0.0]],
+            values,
+            document("tickets.jsonl".to_string(), "Tickets".to_string(), "jsonl".to_string(), 1024, 1234567890),
+        );
+
+        let filter = |fields: &[(&str, &str)]| CollectionQuery {
+            title: None,
+            file_name: None,
+            file_type: None,
+            date_from: None,
+            date_to: None,
+            section: None,
+            fields: Some(fields.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()),
+        };
+        let results = db
+            .query(&"test".to_string(), vec![1.0, 0.0, 0.0], 5, Some(filter(&[("status", "OPEN")])), None)
+            .unwrap();
+        assert_eq!(results.iter().map(|r| r.text.as_str()).collect::<Vec<_>>(), vec!["login loops", "slow page"]);
+        assert_eq!(results[0].fields[1], ("tags".to_string(), "auth".to_string()));
+
+        let results = db
+            .keyword_query(&"test".to_string(), "export login", 5, Some(filter(&[("tags", "auth"), ("status", "closed")])), None)
+            .unwrap();
+        assert_eq!(results.iter().map(|r| r.text.as_str()).collect::<Vec<_>>(), vec!["export fixed"]);
+    }
+
+    #[test]
+    fn test_email_filters() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let mbox = "From jane@example.com Mon Jan 15 10:30:00 2024\n\
+From: Jane <jane@example.com>\n\
+Subject: Printer\n\
+Date: Mon, 15 Jan 2024 10:30:00 +0000\n\
+\n\
+The printer is broken.\n\
+\n\
+From bob@example.com Tue Jan 16 08:00:00 2024\n\
+From: Bob <bob@example.com>\n\
+Subject: Re: Printer\n\
+Date: Tue, 16 Jan 2024 08:00:00 +0000\n\
+\n\
+Fixed, use tray 2.\n\
+\n\
+From carol@example.com Tue Jan 16 09:00:00 2024\n\
+From: carol@example.com\n\
+Subject: Lunch\n\
+Date: Tue, 16 Jan 2024 09:00:00 +0000\n\
+\n\
+Pizza at noon?\n";
+        let extracted = extract_text_from_mbox(mbox.as_bytes()).unwrap();
+        let values = chunk_document(&extracted, &ChunkConfig::default()).unwrap();
+        assert_eq!(values.len(), 3);
+        // Uploaded long after the messages were sent
+        let mut doc = document("support.mbox".to_string(), "Printer".to_string(), "mbox".to_string(), 1024, 1_800_000_000_000);
+        doc.creation_date = extracted.info.creation_date;
+        db.insert_into_collection(&"test".to_string(), vec![vec![1.0, 0.0, 0.0], vec![0.9, 0.1, 0.0], vec![0.8, 0.2, 0.0]], values, doc).unwrap();
+
+        let filter = |fields: &[(&str, &str)], date_from: Option<u64>, date_to: Option<u64>| CollectionQuery {
+            title: None,
+            file_name: None,
+            file_type: None,
+            date_from,
+            date_to,
+            section: None,
+            fields: (!fields.is_empty()).then(|| fields.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()),
+        };
+        let texts = |db: &mut Database, filter: CollectionQuery| -> Vec<String> {
+            let results = db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 5, Some(filter), None).unwrap();
+            results.into_iter().map(|result| result.text).collect()
+        };
+        let jan_16 = 1_705_363_200_000;
+
+        assert_eq!(texts(&mut db, filter(&[("from", "BOB@example.com")], None, None)).len(), 1);
+        assert_eq!(texts(&mut db, filter(&[("subject", "re: printer")], None, None))[0], "From: Bob\nSubject: Re: Printer\nFixed, use tray 2.");
+        // Every message is filtered on the day it was sent, not on the upload
+        assert_eq!(texts(&mut db, filter(&[], Some(jan_16), None)).len(), 2);
+        assert_eq!(texts(&mut db, filter(&[], None, Some(jan_16 - 1))), vec!["From: Jane\nSubject: Printer\nThe printer is broken."]);
+        assert_eq!(texts(&mut db, filter(&[("subject", "Lunch")], Some(jan_16), Some(jan_16 + 86_400_000))).len(), 1);
+        assert!(texts(&mut db, filter(&[("from", "jane@example.com")], Some(jan_16), None)).is_empty());
+        assert!(texts(&mut db, filter(&[], Some(1_800_000_000_000), None)).is_empty());
+
+        // Documents without dated chunks are filtered on their upload, whatever date they give themselves
+        let mut doc = document("memo.txt".to_string(), "Memo".to_string(), "text".to_string(), 1024, 1_800_000_000_000);
+        doc.creation_date = Some(jan_16);
+        db.insert_into_collection(&"test".to_string(), vec![vec![0.0, 0.0, 1.0]], chunks(&["memo"]), doc).unwrap();
+        assert!(texts(&mut db, filter(&[], Some(jan_16), Some(jan_16))).is_empty());
+        assert_eq!(texts(&mut db, filter(&[], Some(1_800_000_000_000), None)), vec!["memo"]);
+    }
+
+    #[test]
+    fn test_vector_search_with_filter() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0]],
+            chunks(&["pdf content"]),
+            document("doc.pdf".to_string(), "PDF Document".to_string(), "pdf".to_string(), 1024, 1234567890),
+        );
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![0.9, 0.1, 0.0]],
+            chunks(&["text content"]),
+            document("doc.txt".to_string(), "Text Document".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        let _ = db.build_index(&"test".to_string());
+
+        let query = CollectionQuery {
+            title: None,
+            file_name: None,
+            file_type: Some("text".to_string()),
+            date_from: None,
+            date_to: None,
+            section: None,
+            fields: None,
+        };
+
+        let results = db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 5, Some(query), None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].metadata.file_name, "doc.txt");
+    }
+
+    #[test]
+    fn test_search_with_each_metric() {
+        let keys = vec![vec![1.0, 0.0, 0.0], vec![4.0, 1.0, 0.0]];
+        let query = vec![2.0, 0.0, 0.0];
+        let mut top = vec![];
+
+        for metric in [Metric::Cosine, Metric::DotProduct, Metric::L2] {
+            // Every database of a thread shares its stable memory
+            let name = format!("test_{:?}", metric);
+            let mut db: Database = Database::new();
+            db.create_collection(name.clone(), 3, metric, IndexConfig::default(), 0).unwrap();
+            db.insert_into_collection(
+                &name,
+                keys.clone(),
+                chunks(&["short", "long"]),
+                document("doc.txt".to_string(), "Doc".to_string(), "text".to_string(), 1024, 1234567890),
+            )
+            .unwrap();
+
+            let results = db.query(&name, query.clone(), 2, None, None).unwrap();
+            assert!(results[0].score >= results[1].score);
+            top.push((results[0].text.clone(), results[0].score));
+        }
+
+        // Same direction wins for cosine, the longer vector for dot product, the nearer one for L2
+        assert_eq!(top[0].0, "short");
+        assert!((top[0].1 - 1.0).abs() < 1e-6);
+        assert_eq!(top[1], ("long".to_string(), 8.0));
+        assert_eq!(top[2], ("short".to_string(), -1.0));
+    }
+
+    #[test]
+    fn test_migrate_cosine_vectors() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![3.0, 4.0, 0.0], vec![0.0, 4.0, 3.0]],
+            chunks(&["content", "more"]),
+            document("doc.txt".to_string(), "Doc".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        assert!(db.migrate_cosine_vectors().is_empty());
+
+        // Simulate a vector stored before normalisation on insert, behind one that is
+        let key = PointKey::new("test", 1);
+        db.vectors.insert(key.clone(), Vector::from(vec![3.0, 4.0, 0.0]));
+
+        assert_eq!(db.migrate_cosine_vectors(), vec!["test".to_string()]);
+        assert!((db.vectors.get(&key).unwrap().norm() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_selective_filter_fills_the_limit() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        // Hundreds of old text chunks right next to the query...
+        let keys: Vec<Vec<f32>> = (0..300).map(|i| vec![1.0, i as f32 * 0.001, 0.0]).collect();
+        let texts: Vec<String> = (0..300).map(|i| format!("note {}", i)).collect();
+        let values = chunks(&texts.iter().map(|t| t.as_str()).collect::<Vec<_>>());
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            keys,
+            values,
+            document("notes.txt".to_string(), "Notes".to_string(), "text".to_string(), 1024, 1_000),
+        );
+        // ...and this month's PDF far away from it
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![0.0, 0.0, 1.0], vec![0.0, 0.1, 1.0], vec![0.1, 0.0, 1.0]],
+            chunks(&["report 1", "report 2", "report 3"]),
+            document("report.pdf".to_string(), "Report".to_string(), "pdf".to_string(), 1024, 5_000),
+        );
+
+        let query = CollectionQuery {
+            title: None,
+            file_name: None,
+            file_type: Some("pdf".to_string()),
+            date_from: Some(4_000),
+            date_to: None,
+            section: None,
+            fields: None,
+        };
+        let results = db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 3, Some(query.clone()), None).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|r| r.metadata.file_name == "report.pdf"));
+        assert!(results.windows(2).all(|pair| pair[0].score >= pair[1].score));
+
+        let results = db.hybrid_query(&"test".to_string(), hybrid(vec![1.0, 0.0, 0.0], "note", 0.5), 3, Some(query), None).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|r| r.metadata.file_name == "report.pdf"));
+    }
+
+    #[test]
+    fn test_keyword_search() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]],
+            chunks(&["Replace filter FX-9000 every month", "General maintenance advice"]),
+            document("manual.txt".to_string(), "Manual".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+
+        let results = db.keyword_query(&"test".to_string(), "fx-9000", 5, None, None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "Replace filter FX-9000 every month");
+        assert!(results[0].score > 0.0);
+
+        let filter = CollectionQuery {
+            title: None,
+            file_name: Some("other.txt".to_string()),
+            file_type: None,
+            date_from: None,
+            date_to: None,
+            section: None,
+            fields: None,
+        };
+        let results = db.keyword_query(&"test".to_string(), "fx-9000", 5, Some(filter), None).unwrap();
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn test_hybrid_search() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0], vec![0.0, 0.0, 1.0]],
+            chunks(&["semantically close", "mentions part ZX-81", "unrelated"]),
+            document("parts.txt".to_string(), "Parts".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        let name = "test".to_string();
+        let q = vec![0.9, 0.1, 0.0];
+
+        // The vector ranking wins with no keyword weight, the keyword one with full weight
+        let results = db.hybrid_query(&name, hybrid(q.clone(), "ZX-81", 0.0), 1, None, None).unwrap();
+        assert_eq!(results[0].text, "semantically close");
+        let results = db.hybrid_query(&name, hybrid(q.clone(), "ZX-81", 1.0), 1, None, None).unwrap();
+        assert_eq!(results[0].text, "mentions part ZX-81");
+
+        // Balanced, a chunk ranked high by both lists comes first
+        let results = db.hybrid_query(&name, hybrid(vec![0.1, 0.9, 0.0], "ZX-81", 0.5), 3, None, None).unwrap();
+        assert_eq!(results[0].text, "mentions part ZX-81");
+        assert!(results.windows(2).all(|pair| pair[0].score >= pair[1].score));
+
+        assert_eq!(db.hybrid_query(&name, hybrid(vec![1.0], "ZX-81", 0.5), 1, None, None), Err(Error::DimensionMismatch));
+    }
+
+    #[test]
+    fn test_migrate_flat_ids() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]],
+            chunks(&["first", "second"]),
+            document("doc.txt".to_string(), "Doc".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        assert!(db.migrate_flat_ids().is_empty());
+
+        // Simulate a flat index stored with its ids
+        #[derive(serde::Serialize)]
+        enum Stored {
+            Flat { metric: Metric, ids: Vec<u32> },
+        }
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&Stored::Flat { metric: Metric::Cosine, ids: vec![0, 1] }, &mut bytes).unwrap();
+        db.indexes.insert("test".to_string(), Index::from_bytes(bytes.into()));
+        let keys: Vec<_> = db.flat_ids.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            db.flat_ids.remove(&key);
+        }
+        assert!(db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 5, None, None).unwrap().is_empty());
+
+        assert_eq!(db.migrate_flat_ids(), vec!["test".to_string()]);
+        assert_eq!(db.flat_ids.len(), 2);
+        assert_eq!(db.indexes.get(&"test".to_string()).unwrap().len(), 2);
+        assert_eq!(db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 5, None, None).unwrap()[0].text, "first");
+        assert!(db.migrate_flat_ids().is_empty());
+    }
+
+    #[test]
+    fn test_migrate_doc_chunks() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0]],
+            chunks(&["first moved", "second moved"]),
+            document("doc.txt".to_string(), "Doc".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        assert!(db.migrate_doc_chunks().is_empty());
+
+        // Simulate a collection keeping its documents' chunk ids in its own record
+        let keys: Vec<_> = db.doc_chunks.iter().map(|(key, _)| key).collect();
+        let mut collection = db.collections.get(&"test".to_string()).unwrap();
+        for key in keys {
+            collection.metadata.doc_chunks.entry(key.file_name.clone()).or_default().push(key.id);
+            db.doc_chunks.remove(&key);
+        }
+        db.collections.insert("test".to_string(), collection);
+
+        assert_eq!(db.migrate_doc_chunks(), vec!["test".to_string()]);
+        assert_eq!(db.doc_chunks.len(), 2);
+        assert!(db.migrate_doc_chunks().is_empty());
+
+        // Removing the document finds its chunks again
+        db.remove_document_from_collection(&"test".to_string(), &"doc.txt".to_string()).unwrap();
+        assert!(db.keyword_query(&"test".to_string(), "moved", 5, None, None).unwrap().is_empty());
+        assert_eq!(db.doc_chunks.len(), 0);
+    }
+
+    #[test]
+    fn test_migrate_lexical_index() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0]],
+            chunks(&["indexed later"]),
+            document("doc.txt".to_string(), "Doc".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+
+        // Simulate a collection stored before keyword search existed
+        let keys: Vec<_> = db.postings.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            db.postings.remove(&key);
+        }
+        let mut collection = db.collections.get(&"test".to_string()).unwrap();
+        collection.lexical = Default::default();
+        db.collections.insert("test".to_string(), collection);
+        assert!(db.keyword_query(&"test".to_string(), "indexed", 5, None, None).unwrap().is_empty());
+
+        assert_eq!(db.migrate_lexical_index(), vec!["test".to_string()]);
+        assert_eq!(db.keyword_query(&"test".to_string(), "indexed", 5, None, None).unwrap().len(), 1);
+        assert!(db.migrate_lexical_index().is_empty());
+    }
+
+    #[test]
+    fn test_vector_search_dimension_mismatch() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let result = db.query(&"test".to_string(), vec![1.0, 0.0], 5, None, None);
+        assert_eq!(result, Err(Error::DimensionMismatch));
+    }
+
+    #[test]
+    fn test_removed_document_is_not_retrievable() {
+        let mut db: Database = Database::new();
+        let config = IndexConfig { kind: IndexKind::Hnsw, ..Default::default() };
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, config, 0);
+
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![1.0, 0.0, 0.0], vec![0.9, 0.1, 0.0]],
+            chunks(&["removed chunk 1", "removed chunk 2"]),
+            document("removed.txt".to_string(), "Removed Document".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![0.0, 1.0, 0.0]],
+            chunks(&["kept chunk"]),
+            document("kept.txt".to_string(), "Kept Document".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        let _ = db.build_index(&"test".to_string());
+
+        let result = db.remove_document_from_collection(&"test".to_string(), &"removed.txt".to_string());
+        assert!(result.is_ok());
+        assert_eq!(db.collections.get(&"test".to_string()).unwrap().metadata.count, 1);
+        assert_eq!(db.indexes.get(&"test".to_string()).unwrap().tombstones(), 2);
+        assert_eq!(db.vectors.len(), 3);
+        assert_eq!(db.chunks.len(), 1);
+        assert_eq!(db.postings.len(), 2);
+
+        // Even the query closest to the removed vectors only finds the kept document
+        let results = db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 10, None, None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "kept chunk");
+
+        // The removed vectors don't come back when the index is rebuilt
+        assert_eq!(db.build_index(&"test".to_string()), Ok(2));
+        assert_eq!(db.indexes.get(&"test".to_string()).unwrap().tombstones(), 0);
+        assert_eq!(db.vectors.len(), 1);
+        let results = db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 10, None, None).unwrap();
+        assert_eq!(results.len(), 1);
+    }
+
+    #[test]
+    fn test_flat_index_drops_removed_vectors() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+        for (file_name, key) in [("a.txt", vec![1.0, 0.0, 0.0]), ("b.txt", vec![0.0, 1.0, 0.0])] {
+            let _ = db.insert_into_collection(
+                &"test".to_string(),
+                vec![key],
+                chunks(&[file_name]),
+                document(file_name.to_string(), file_name.to_string(), "text".to_string(), 1024, 1234567890),
+            );
+        }
+        assert!(matches!(db.indexes.get(&"test".to_string()).unwrap(), Index::Flat(_)));
+
+        let _ = db.remove_document_from_collection(&"test".to_string(), &"a.txt".to_string());
+        assert_eq!(db.indexes.get(&"test".to_string()).unwrap().len(), 1);
+        assert_eq!(db.flat_ids.len(), 1);
+        assert_eq!(db.vectors.len(), 1);
+
+        let results = db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 10, None, None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "b.txt");
+    }
+
+    #[test]
+    fn test_flat_index_is_promoted_past_the_threshold() {
+        let mut db: Database = Database::new();
+        let config = IndexConfig { kind: IndexKind::Flat, promote_threshold: 4, ..Default::default() };
+        let _ = db.create_collection("test".to_string(), 3, Metric::L2, config, 0);
+
+        let upload = |db: &mut Database, file_name: &str, count: usize| {
+            let keys = (0..count).map(|i| vec![i as f32, 1.0, 0.0]).collect();
+            let texts: Vec<String> = (0..count).map(|i| format!("{} {}", file_name, i)).collect();
+            let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+            let _ = db.insert_into_collection(
+                &"test".to_string(),
+                keys,
+                chunks(&texts),
+                document(file_name.to_string(), file_name.to_string(), "text".to_string(), 1024, 1234567890),
+            );
+        };
+
+        upload(&mut db, "small.txt", 4);
+        assert!(matches!(db.indexes.get(&"test".to_string()).unwrap(), Index::Flat(_)));
+
+        // Uploads stay cheap, only compaction builds the graph
+        upload(&mut db, "more.txt", 3);
+        assert!(matches!(db.indexes.get(&"test".to_string()).unwrap(), Index::Flat(_)));
+        assert_eq!(db.flat_ids.len(), 7);
+        assert_eq!(db.build_index(&"test".to_string()), Ok(0));
+        assert!(matches!(db.indexes.get(&"test".to_string()).unwrap(), Index::Hnsw(_)));
+        assert!(db.flat_ids.is_empty());
+        let results = db.query(&"test".to_string(), vec![2.0, 1.0, 0.0], 2, None, None).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| result.text.ends_with(" 2")));
+
+        // Shrinking back under the threshold makes compaction go flat again
+        let _ = db.remove_document_from_collection(&"test".to_string(), &"more.txt".to_string());
+        let _ = db.build_index(&"test".to_string());
+        assert!(matches!(db.indexes.get(&"test".to_string()).unwrap(), Index::Flat(_)));
+        assert_eq!(db.flat_ids.len(), 4);
+        assert_eq!(db.query(&"test".to_string(), vec![2.0, 1.0, 0.0], 1, None, None).unwrap()[0].text, "small.txt 2");
+    }
+
+    #[test]
+    fn test_quantized_search_recall() {
+        let mut state: u64 = 7;
+        let mut random_vector = move || {
+            (0..32)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    ((state >> 33) as f32) / (1u64 << 31) as f32 - 0.5
+                })
+                .collect::<Vec<f32>>()
+        };
+        let keys: Vec<Vec<f32>> = (0..400).map(|_| random_vector()).collect();
+        let queries: Vec<Vec<f32>> = (0..20).map(|_| random_vector()).collect();
+        let texts: Vec<String> = (0..400).map(|i| format!("point {}", i)).collect();
+        let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+
+        let cosine = |a: &[f32], b: &[f32]| {
+            let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+            dot / (a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt())
+        };
+
+        for (quantization, min_recall) in [(Quantization::Int8, 0.95), (Quantization::Binary, 0.9)] {
+            for kind in [IndexKind::Flat, IndexKind::Hnsw] {
+                let mut db: Database = Database::new();
+                let config = IndexConfig { kind, quantization, ..Default::default() };
+                let _ = db.create_collection("test".to_string(), 32, Metric::Cosine, config, 0);
+                let _ = db.insert_into_collection(
+                    &"test".to_string(),
+                    keys.clone(),
+                    chunks(&texts),
+                    document("points.txt".to_string(), "Points".to_string(), "text".to_string(), 1024, 1234567890),
+                );
+                assert_eq!(db.codes.len(), 400);
+                assert_eq!(db.vectors.len(), 400);
+
+                let mut hits = 0;
+                for query in &queries {
+                    let mut expected: Vec<(f32, usize)> = keys.iter().enumerate().map(|(i, key)| (cosine(query, key), i)).collect();
+                    expected.sort_by(|a, b| b.0.total_cmp(&a.0));
+                    let expected: Vec<&str> = expected.iter().take(10).map(|(_, i)| texts[*i]).collect();
+
+                    let results = db.query(&"test".to_string(), query.clone(), 10, None, None).unwrap();
+                    // Candidates are rescored on the full precision vectors
+                    for result in &results {
+                        let key = &keys[texts.iter().position(|t| *t == result.text).unwrap()];
+                        assert!((result.score - cosine(query, key)).abs() < 1e-5);
+                    }
+                    hits += results.iter().filter(|result| expected.contains(&result.text.as_str())).count();
+                }
+                let recall = hits as f32 / 200.0;
+                assert!(recall >= min_recall, "{:?} {:?} recall {}", quantization, kind, recall);
+
+                let _ = db.delete_collection(&"test".to_string());
+                assert_eq!(db.codes.len(), 0);
+                assert_eq!(db.vectors.len(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantized_indexes_take_less_memory() {
+        let mut state: u64 = 11;
+        let keys: Vec<Vec<f32>> = (0..200)
+            .map(|_| {
+                (0..256)
+                    .map(|_| {
+                        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                        ((state >> 33) as f32) / (1u64 << 31) as f32 - 0.5
+                    })
+                    .collect()
+            })
+            .collect();
+        let texts: Vec<String> = (0..200).map(|i| format!("point {}", i)).collect();
+        let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+
+        let mut sizes = Vec::new();
+        for quantization in [Quantization::None, Quantization::Int8, Quantization::Binary] {
+            let mut db: Database = Database::new();
+            let name = format!("test_{:?}", quantization);
+            let config = IndexConfig { kind: IndexKind::Hnsw, quantization, ..Default::default() };
+            db.create_collection(name.clone(), 256, Metric::Cosine, config, 0).unwrap();
+            db.insert_into_collection(
+                &name,
+                keys.clone(),
+                chunks(&texts),
+                document("points.txt".to_string(), "Points".to_string(), "text".to_string(), 1024, 1234567890),
+            )
+            .unwrap();
+            // The vectors of quantized collections only rescore, their index searches the codes
+            let points = match quantization {
+                Quantization::None => stored_bytes(&db.vectors),
+                _ => stored_bytes(&db.codes),
+            };
+            sizes.push(stored_bytes(&db.indexes) + stored_bytes(&db.nodes) + points);
+            db.delete_collection(&name).unwrap();
+        }
+        // The graph along with the points it compares
+        assert!(sizes[1] * 2 < sizes[0], "{:?}", sizes);
+        assert!(sizes[2] * 2 < sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_ivf_pq_collection() {
+        let mut db: Database = Database::new();
+        let config = IndexConfig { kind: IndexKind::IvfPq, promote_threshold: 10, ..Default::default() };
+        let _ = db.create_collection("test".to_string(), 3, Metric::L2, config, 0);
+
+        let keys: Vec<Vec<f32>> = (0..64).map(|i| vec![(i % 4) as f32 * 10.0, (i / 4) as f32, 0.5]).collect();
+        let texts: Vec<String> = (0..64).map(|i| format!("point {}", i)).collect();
+        let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            keys,
+            chunks(&texts),
+            document("points.txt".to_string(), "Points".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        assert!(matches!(db.indexes.get(&"test".to_string()).unwrap(), Index::Flat(_)));
+        let _ = db.build_index(&"test".to_string());
+        assert!(matches!(db.indexes.get(&"test".to_string()).unwrap(), Index::IvfPq(_)));
+        // The lists are stored apart from the index, one entry each
+        assert_eq!(db.lists.len(), 8);
+        assert_eq!(db.nodes.len(), 0);
+
+        // Rescored on the full vectors, the exact match comes first with its exact score
+        let results = db.query(&"test".to_string(), vec![20.0, 5.0, 0.5], 3, None, None).unwrap();
+        assert_eq!(results[0].text, "point 22");
+        assert_eq!(results[0].score, 0.0);
+
+        // Probing a single list only sees part of the collection
+        let one = SearchParams { nprobe: Some(1), ..Default::default() };
+        let every = SearchParams { nprobe: Some(64), ..Default::default() };
+        let few = db.query(&"test".to_string(), vec![20.0, 5.0, 0.5], 64, None, Some(one)).unwrap();
+        let all = db.query(&"test".to_string(), vec![20.0, 5.0, 0.5], 64, None, Some(every)).unwrap();
+        assert!(few.len() < all.len());
+        assert_eq!(all.len(), 64);
+
+        // Removed points are gone from the lists and the vector store at once
+        let _ = db.remove_document_from_collection(&"test".to_string(), &"points.txt".to_string());
+        assert_eq!(db.indexes.get(&"test".to_string()).unwrap().len(), 0);
+        assert_eq!(db.vectors.len(), 0);
+
+        let _ = db.delete_collection(&"test".to_string());
+        assert_eq!(db.lists.len(), 0);
+    }
+
+    #[test]
+    fn test_hnsw_params_are_per_collection() {
+        let mut db: Database = Database::new();
+        let mut config = IndexConfig { kind: IndexKind::Hnsw, ..Default::default() };
+        config.hnsw.m = 0;
+        let result = db.create_collection("test".to_string(), 3, Metric::Cosine, config.clone(), 0);
+        assert_eq!(result, Err(Error::InvalidInput));
+
+        config.hnsw.m = 4;
+        config.hnsw.ef_search = 8;
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, config.clone(), 0);
+        assert_eq!(db.get_index_config(&"test".to_string()), Ok(config));
+
+        let keys: Vec<Vec<f32>> = (0..20).map(|i| vec![i as f32, 1.0, 0.5]).collect();
+        let texts: Vec<String> = (0..20).map(|i| format!("point {}", i)).collect();
+        let texts: Vec<&str> = texts.iter().map(|t| t.as_str()).collect();
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            keys,
+            chunks(&texts),
+            document("points.txt".to_string(), "Points".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+
+        // The collection's ef_search applies unless the query overrides it
+        let results = db.query(&"test".to_string(), vec![3.0, 1.0, 0.5], 3, None, None).unwrap();
+        assert_eq!(results[0].text, "point 3");
+        let params = SearchParams { ef_search: Some(64), ..Default::default() };
+        let results = db.query(&"test".to_string(), vec![3.0, 1.0, 0.5], 20, None, Some(params)).unwrap();
+        assert_eq!(results.len(), 20);
+    }
+
+    #[test]
+    fn test_result_selection() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+        let name = "test".to_string();
+
+        // Three near copies of one chunk, and a less relevant but different one
+        let _ = db.insert_into_collection(
+            &name,
+            vec![vec![1.0, 0.1, 0.0], vec![1.0, 0.11, 0.0], vec![1.0, 0.12, 0.0]],
+            chunks(&["copy one", "copy two", "copy three"]),
+            document("copies.txt".to_string(), "Copies".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        let _ = db.insert_into_collection(
+            &name,
+            vec![vec![0.6, 0.0, 0.8]],
+            chunks(&["different"]),
+            document("other.txt".to_string(), "Other".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        let q = vec![1.0, 0.0, 0.1];
+
+        let results = db.query(&name, q.clone(), 2, None, None).unwrap();
+        assert!(results.iter().all(|result| result.text.starts_with("copy")));
+
+        // Diversity pulls the other chunk in right after the best copy
+        let params = SearchParams { mmr_lambda: Some(0.5), ..Default::default() };
+        let results = db.query(&name, q.clone(), 2, None, Some(params)).unwrap();
+        assert_eq!(results[0].text, "copy one");
+        assert_eq!(results[1].text, "different");
+
+        // A lambda of 1 keeps the relevance order
+        let params = SearchParams { mmr_lambda: Some(1.0), ..Default::default() };
+        let results = db.query(&name, q.clone(), 4, None, Some(params)).unwrap();
+        assert_eq!(results, db.query(&name, q.clone(), 4, None, None).unwrap());
+
+        let params = SearchParams { max_per_document: Some(1), ..Default::default() };
+        let results = db.query(&name, q.clone(), 4, None, Some(params)).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].text, "copy one");
+        assert_eq!(results[1].text, "different");
+
+        let params = SearchParams { min_score: Some(0.9), ..Default::default() };
+        let results = db.query(&name, q.clone(), 4, None, Some(params.clone())).unwrap();
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|result| result.score >= 0.9));
+        assert_eq!(db.hybrid_query(&name, hybrid(q.clone(), "copy", 0.0), 4, None, Some(params)).unwrap().len(), 0);
+
+        // Keyword searches are capped the same way
+        let params = SearchParams { max_per_document: Some(2), ..Default::default() };
+        assert_eq!(db.keyword_query(&name, "copy", 4, None, Some(params)).unwrap().len(), 2);
+
+        let params = SearchParams { mmr_lambda: Some(1.5), ..Default::default() };
+        assert_eq!(db.query(&name, q.clone(), 4, None, Some(params)), Err(Error::InvalidInput));
+        let params = SearchParams { max_per_document: Some(0), ..Default::default() };
+        assert_eq!(db.keyword_query(&name, "copy", 4, None, Some(params)), Err(Error::InvalidInput));
+    }
+
+    #[test]
+    fn test_reupload_replaces_document_chunks() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        for text in ["old version", "new version"] {
+            let _ = db.insert_into_collection(
+                &"test".to_string(),
+                vec![vec![1.0, 0.0, 0.0]],
+                chunks(&[text]),
+                document("doc.txt".to_string(), "Document".to_string(), "text".to_string(), 1024, 1234567890),
+            );
+        }
+        let _ = db.build_index(&"test".to_string());
+
+        let results = db.query(&"test".to_string(), vec![1.0, 0.0, 0.0], 10, None, None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "new version");
+        assert_eq!(db.collections.get(&"test".to_string()).unwrap().metadata.count, 1);
+    }
+
+    #[test]
+    fn test_insert_without_rebuild() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        for (i, key) in [vec![1.0, 0.0, 0.0], vec![0.0, 1.0, 0.0], vec![0.0, 0.0, 1.0]].into_iter().enumerate() {
+            let _ = db.insert_into_collection(
+                &"test".to_string(),
+                vec![key],
+                chunks(&[&format!("content {}", i)]),
+                document(format!("doc{}.txt", i), format!("Document {}", i), "text".to_string(), 1024, 1234567890),
+            );
+        }
+
+        // Every upload is searchable right away, no build_index call needed
+        let results = db.query(&"test".to_string(), vec![0.0, 0.1, 0.9], 1, None, None).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "content 2");
+    }
+
+    #[test]
+    fn insert_into_collection_dimensions_mismatch_vector_length() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("test".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+
+        let result = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![10.0, 12.0, 4.5], vec![10.0, 11.0]],
+            chunks(&["red", "green"]),
+            document("test_file.txt".to_string(), "Test Document".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+
+        assert_eq!(result, Err(Error::DimensionMismatch));
+        assert_eq!(db.get_docs(&"test".to_string()).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_migrate_dimension() {
+        let mut db: Database = Database::new();
+        let _ = db.create_collection("empty".to_string(), 1000, Metric::Cosine, IndexConfig::default(), 0);
+        let _ = db.create_collection("filled".to_string(), 3, Metric::Cosine, IndexConfig::default(), 0);
+        let _ = db.create_collection("other_model".to_string(), 2, Metric::Cosine, IndexConfig::default(), 0);
+
+        let _ = db.insert_into_collection(
+            &"filled".to_string(),
+            vec![vec![1.0, 0.0, 0.0]],
+            chunks(&["content"]),
+            document("doc.txt".to_string(), "Document".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        let _ = db.insert_into_collection(
+            &"other_model".to_string(),
+            vec![vec![1.0, 0.0]],
+            chunks(&["content"]),
+            document("doc.txt".to_string(), "Document".to_string(), "text".to_string(), 1024, 1234567890),
+        );
+        // Simulate a collection recorded with the wrong dimension
+        let mut filled = db.collections.get(&"filled".to_string()).unwrap();
+        filled.dimension = 1000;
+        db.collections.insert("filled".to_string(), filled);
+
+        let stale = db.migrate_dimension(3);
+        assert_eq!(stale, vec!["other_model".to_string()]);
+        assert_eq!(db.collections.get(&"empty".to_string()).unwrap().dimension, 3);
+        assert_eq!(db.collections.get(&"other_model".to_string()).unwrap().dimension, 2);
+
+        let results = db.query(&"filled".to_string(), vec![1.0, 0.0, 0.0], 1, None, None).unwrap();
+        assert_eq!(results[0].text, "content");
+    }
 }
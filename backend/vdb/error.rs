@@ -1,5 +1,7 @@
 use candid::CandidType;
 
+// Variant names are part of the Candid interface clients decode
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, thiserror::Error, PartialEq, CandidType)]
 pub enum Error {
     #[error("Collection already exists")]
@@ -16,6 +18,10 @@ pub enum Error {
     InvalidInput,
     #[error("file type not supported")]
     FileTypeNotSupported,
+    #[error("the document is encrypted")]
+    EncryptedDocument,
+    #[error("the document has no text layer, it may be scanned images")]
+    NoTextLayer,
     #[error("vector db error")]
     DBError,
     #[error("model error: {0}")]
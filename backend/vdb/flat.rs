@@ -0,0 +1,176 @@
+use super::index::{Metric, Point, PointStore};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Where a flat index keeps the ids of its points, one entry per point, so
+/// that an insert or a removal only writes its own
+pub trait IdStore {
+    fn ids(&self) -> Vec<u32>;
+    // Whether the id was not there yet
+    fn insert_id(&mut self, id: u32) -> bool;
+    // Whether the id was there
+    fn remove_id(&mut self, id: u32) -> bool;
+}
+
+impl IdStore for BTreeSet<u32> {
+    fn ids(&self) -> Vec<u32> {
+        self.iter().copied().collect()
+    }
+
+    fn insert_id(&mut self, id: u32) -> bool {
+        self.insert(id)
+    }
+
+    fn remove_id(&mut self, id: u32) -> bool {
+        self.remove(&id)
+    }
+}
+
+/// Exact nearest neighbour index: every search scans all points.
+///
+/// The ids are kept in an `IdStore`, a scan reads the points from the
+/// `PointStore`, the quantized codes themselves for quantized collections.
+/// Indexes stored before kept a copy of every point here, it is ignored.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Flat {
+    metric: Metric,
+    // Indexes stored before ids had their own map kept them here, see `take_ids`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ids: Vec<u32>,
+    // Points in the index
+    #[serde(default)]
+    count: usize,
+}
+
+impl Flat {
+    pub fn new(metric: Metric) -> Self {
+        Self { metric, ids: vec![], count: 0 }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    #[cfg(test)]
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    /// The ids of an index stored before ids had their own map, to be moved there
+    pub fn take_ids(&mut self) -> Vec<u32> {
+        self.count = self.count.max(self.ids.len());
+        std::mem::take(&mut self.ids)
+    }
+
+    /// Add a point, its vector must already be in the store searched
+    pub fn insert<I: IdStore + ?Sized>(&mut self, id: u32, ids: &mut I) {
+        if ids.insert_id(id) {
+            self.count += 1;
+        }
+    }
+
+    /// Remove a point right away, a flat index has nothing routing through it
+    pub fn remove<I: IdStore + ?Sized>(&mut self, id: u32, ids: &mut I) {
+        if ids.remove_id(id) {
+            self.count -= 1;
+        }
+    }
+
+    /// The `k` nearest points passing `accept`, closest first. The distances
+    /// are only as exact as the points `store` gives.
+    pub fn search<S: PointStore + ?Sized, I: IdStore + ?Sized>(
+        &self,
+        query: &S::Point,
+        k: usize,
+        store: &S,
+        ids: &I,
+        accept: &dyn Fn(u32) -> bool,
+    ) -> Vec<(f32, u32)> {
+        let mut found: Vec<(f32, u32)> = ids
+            .ids()
+            .into_iter()
+            .filter(|id| accept(*id))
+            .map(|id| (query.distance(self.metric, &store.point(id)), id))
+            .collect();
+        found.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        found.truncate(k);
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Flat;
+    use crate::vdb::index::{Metric, Vector};
+    use serde::Serialize;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn search_is_exact() {
+        let mut flat = Flat::new(Metric::L2);
+        let mut points = BTreeMap::new();
+        let mut ids = BTreeSet::new();
+        for (id, x) in [(0, 0.0), (1, 1.0), (2, 2.0), (3, 3.0)] {
+            points.insert(id, Vector::from(vec![x, 0.0]));
+            flat.insert(id, &mut ids);
+        }
+
+        let query = Vector::from(vec![2.2, 0.0]);
+        let found: Vec<u32> = flat.search(&query, 3, &points, &ids, &|_| true).into_iter().map(|(_, id)| id).collect();
+        assert_eq!(found, vec![2, 3, 1]);
+
+        let found: Vec<u32> = flat.search(&query, 3, &points, &ids, &|id| id % 2 == 1).into_iter().map(|(_, id)| id).collect();
+        assert_eq!(found, vec![3, 1]);
+    }
+
+    #[test]
+    fn remove_keeps_other_points_intact() {
+        let mut flat = Flat::new(Metric::DotProduct);
+        let points: BTreeMap<u32, Vector> = (0..4).map(|id| (id, Vector::from(vec![id as f32, 1.0]))).collect();
+        let mut ids = BTreeSet::new();
+        for id in 0..4 {
+            flat.insert(id, &mut ids);
+        }
+        flat.insert(2, &mut ids);
+
+        flat.remove(1, &mut ids);
+        flat.remove(7, &mut ids);
+        assert_eq!(flat.len(), 3);
+
+        let found = flat.search(&Vector::from(vec![1.0, 0.0]), 4, &points, &ids, &|_| true);
+        assert_eq!(found, vec![(-3.0, 3), (-2.0, 2), (0.0, 0)]);
+    }
+
+    #[test]
+    fn roundtrips_through_cbor() {
+        let mut flat = Flat::new(Metric::Cosine);
+        flat.insert(5, &mut BTreeSet::new());
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&flat, &mut bytes).unwrap();
+        let mut decoded: Flat = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded.metric(), Metric::Cosine);
+        assert!(decoded.take_ids().is_empty());
+    }
+
+    #[test]
+    fn stored_copies_of_the_points_are_ignored() {
+        #[derive(Serialize)]
+        struct Stored {
+            metric: Metric,
+            ids: Vec<u32>,
+            #[serde(with = "serde_bytes")]
+            data: Vec<u8>,
+        }
+        let stored = Stored { metric: Metric::L2, ids: vec![3, 4], data: vec![0; 16] };
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&stored, &mut bytes).unwrap();
+        let mut decoded: Flat = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(decoded.take_ids(), vec![3, 4]);
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded.metric(), Metric::L2);
+    }
+}
@@ -1,30 +1,37 @@
-use instant_distance::{Builder, HnswMap, Point};
-use nalgebra::{ComplexField, DVector};
+use super::flat::{Flat, IdStore};
+use super::ivf::{IvfPq, ListStore, DEFAULT_NPROBE};
+use super::error::Error;
+use super::quantization::Quantization;
+use candid::CandidType;
+use ciborium::de;
+use ic_stable_structures::{storable::Bound, Storable};
+use nalgebra::DVector;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
 
-pub fn generate_index(points: Vec<Vector>, values: Vec<String>) -> HnswMap<Vector, String> {
-    Builder::default().build(points, values)
-}
+/// Default max neighbours per node on the upper layers, layer 0 keeps twice as many
+const M: usize = 16;
+/// Default candidate list size used while inserting points
+const EF_CONSTRUCTION: usize = 100;
+/// Default candidate list size used while searching
+pub const EF_SEARCH: usize = 100;
+/// Hard cap on the number of layers a point can be assigned to
+const MAX_LEVEL: usize = 16;
+const DEFAULT_SEED: u64 = 0x5eed_1dea_c0ff_ee00;
+/// Compaction promotes flat indexes to a graph past this many points by default
+pub const PROMOTE_THRESHOLD: u64 = 1000;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Vector {
     data: DVector<f32>,
 }
 
-impl Point for Vector {
-    fn distance(&self, other: &Self) -> f32 {
-        let diff = self.data.clone() - other.data.clone();
-        diff.dot(&diff).norm1()
-    }
-}
-
 impl PartialEq for Vector {
     fn eq(&self, other: &Self) -> bool {
         self.data == other.data
     }
-    fn ne(&self, other: &Self) -> bool {
-        !self.eq(other)
-    }
 }
 
 impl From<Vec<f32>> for Vector {
@@ -34,8 +41,1031 @@ impl From<Vec<f32>> for Vector {
     }
 }
 
+// Stored as raw little endian floats, much cheaper to decode than CBOR
+impl Storable for Vector {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(self.data.len() * 4);
+        for value in self.data.iter() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let values: Vec<f32> = bytes.as_chunks::<4>().0.iter().map(|b| f32::from_le_bytes(*b)).collect();
+        Vector::from(values)
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 impl Vector {
-    pub fn cos_sim(&self, other: &Vector) -> f32 {
-        self.data.dot(&other.data) / (self.data.norm() * other.data.norm())
+    pub fn dimension(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn to_vec(&self) -> Vec<f32> {
+        self.data.iter().copied().collect()
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        self.data.as_slice()
+    }
+
+    pub fn dot(&self, other: &Vector) -> f32 {
+        self.data.dot(&other.data)
+    }
+
+    pub fn squared_l2(&self, other: &Vector) -> f32 {
+        let diff = &self.data - &other.data;
+        diff.dot(&diff)
+    }
+
+    pub fn norm(&self) -> f32 {
+        self.data.norm()
+    }
+
+    /// Unit length copy, a zero vector is returned as is
+    pub fn normalized(&self) -> Vector {
+        let norm = self.data.norm();
+        if norm == 0.0 {
+            return self.clone();
+        }
+        Vector { data: &self.data / norm }
+    }
+}
+
+/// How closeness of two vectors is measured, chosen per collection
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// Cosine similarity, vectors are normalised before they are stored
+    #[default]
+    Cosine,
+    /// Inner product, for models trained with it
+    DotProduct,
+    /// Euclidean distance
+    L2,
+}
+
+impl Metric {
+    /// Used for ranking, lower is closer
+    pub fn distance(&self, a: &Vector, b: &Vector) -> f32 {
+        match self {
+            // Both sides are unit length, see `prepare`
+            Metric::Cosine => 1.0 - a.dot(b),
+            Metric::DotProduct => -a.dot(b),
+            Metric::L2 => a.squared_l2(b),
+        }
+    }
+
+    /// Score reported for a `distance`, higher is closer: the cosine similarity,
+    /// the inner product or the negated euclidean distance
+    pub fn score(&self, distance: f32) -> f32 {
+        match self {
+            Metric::Cosine => 1.0 - distance,
+            Metric::DotProduct => -distance,
+            Metric::L2 => -distance.max(0.0).sqrt(),
+        }
+    }
+
+    /// Bring a stored or query vector into the form `distance` expects
+    pub fn prepare(&self, vector: Vector) -> Vector {
+        match self {
+            Metric::Cosine => vector.normalized(),
+            _ => vector,
+        }
+    }
+}
+
+/// A point in the form an index compares it
+pub trait Point: Clone {
+    /// See `Metric::distance`
+    fn distance(&self, metric: Metric, other: &Self) -> f32;
+}
+
+impl Point for Vector {
+    fn distance(&self, metric: Metric, other: &Self) -> f32 {
+        metric.distance(self, other)
+    }
+}
+
+/// Where the index reads points from, the index itself only stores ids
+pub trait PointStore {
+    type Point: Point;
+
+    /// The point as the index compares it, its quantized code for quantized collections
+    fn point(&self, id: u32) -> Cow<'_, Self::Point>;
+
+    /// A prepared query in the form it is compared to the points
+    fn query(&self, query: &Vector) -> Self::Point;
+
+    /// The most precise vector kept of a point, for scoring and training
+    fn vector(&self, id: u32) -> Cow<'_, Vector>;
+}
+
+impl PointStore for BTreeMap<u32, Vector> {
+    type Point = Vector;
+
+    fn point(&self, id: u32) -> Cow<'_, Vector> {
+        Cow::Borrowed(&self[&id])
     }
-}
\ No newline at end of file
+
+    fn query(&self, query: &Vector) -> Vector {
+        query.clone()
+    }
+
+    fn vector(&self, id: u32) -> Cow<'_, Vector> {
+        self.point(id)
+    }
+}
+
+/// Where a graph keeps its nodes, one entry per point, so that an insert or a
+/// search only reads and writes the nodes it visits
+pub trait NodeStore {
+    fn node(&self, id: u32) -> Option<Cow<'_, Node>>;
+    fn set_node(&mut self, id: u32, node: Node);
+}
+
+impl NodeStore for BTreeMap<u32, Node> {
+    fn node(&self, id: u32) -> Option<Cow<'_, Node>> {
+        self.get(&id).map(Cow::Borrowed)
+    }
+
+    fn set_node(&mut self, id: u32, node: Node) {
+        self.insert(id, node);
+    }
+}
+
+/// Which structure answers the vector searches of a collection
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq, Eq)]
+pub enum IndexKind {
+    /// Exact scan, promoted to a graph by the first compaction after the
+    /// collection outgrows the threshold
+    #[default]
+    Flat,
+    /// Approximate search on an HNSW graph
+    Hnsw,
+    /// Exact scan, promoted to an IVF-PQ index by the first compaction after the
+    /// collection outgrows the threshold. For collections with hundreds of
+    /// thousands of chunks.
+    IvfPq,
+}
+
+/// Build and search parameters of an HNSW graph. Larger values give better
+/// recall for slower inserts and searches.
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub struct HnswParams {
+    // Max neighbours per node on the upper layers, layer 0 keeps twice as many
+    pub m: u32,
+    // Candidate list size used while inserting points
+    pub ef_construction: u32,
+    // Candidate list size used while searching, unless the query sets its own
+    pub ef_search: u32,
+    // Seed of the level assignment, the same inserts always give the same graph
+    pub seed: u64,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: M as u32,
+            ef_construction: EF_CONSTRUCTION as u32,
+            ef_search: EF_SEARCH as u32,
+            seed: DEFAULT_SEED,
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct IndexConfig {
+    pub kind: IndexKind,
+    // Number of points above which compaction promotes a flat index, uploads
+    // never do as building the graph or lists takes work in the collection's size
+    pub promote_threshold: u64,
+    // Form of the vectors the index searches, collections created before
+    // quantization existed search full precision ones
+    #[serde(default)]
+    pub quantization: Quantization,
+    // Graphs of collections created before these were configurable use the defaults
+    #[serde(default)]
+    pub hnsw: HnswParams,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            kind: IndexKind::Flat,
+            promote_threshold: PROMOTE_THRESHOLD,
+            quantization: Quantization::None,
+            hnsw: HnswParams::default(),
+        }
+    }
+}
+
+impl IndexConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        // A single neighbour per node can't make a navigable graph
+        if self.hnsw.m < 2 || self.hnsw.ef_construction == 0 || self.hnsw.ef_search == 0 {
+            return Err(Error::InvalidInput);
+        }
+        Ok(())
+    }
+}
+
+/// How much work an approximate search may do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SearchBudget {
+    // Candidate list size of a graph search
+    pub ef: usize,
+    // Lists scanned by an IVF search
+    pub nprobe: usize,
+}
+
+impl Default for SearchBudget {
+    fn default() -> Self {
+        Self {
+            ef: EF_SEARCH,
+            nprobe: DEFAULT_NPROBE,
+        }
+    }
+}
+
+/// The vector index of a collection, stored in the index map
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Index {
+    Flat(Flat),
+    Hnsw(Hnsw),
+    IvfPq(IvfPq),
+}
+
+// Indexes stored before flat indexes existed are bare graphs
+impl Storable for Index {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        match de::from_reader(bytes.as_ref()) {
+            Ok(index) => index,
+            Err(_) => Index::Hnsw(de::from_reader(bytes.as_ref()).unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Index {
+    pub fn new(metric: Metric, config: &IndexConfig) -> Self {
+        match config.kind {
+            IndexKind::Flat | IndexKind::IvfPq => Index::Flat(Flat::new(metric)),
+            IndexKind::Hnsw => Index::Hnsw(Hnsw::new(metric, config.hnsw)),
+        }
+    }
+
+    /// Build a fresh index over the given points, the promoted kind when a flat
+    /// index would already be past its threshold. A graph's nodes or inverted
+    /// lists must not be in `entries` yet.
+    pub fn build<S: PointStore + ?Sized, N: NodeStore + ListStore + IdStore + ?Sized>(metric: Metric, config: &IndexConfig, ids: Vec<u32>, store: &S, entries: &mut N) -> Self {
+        let promoted = ids.len() as u64 > config.promote_threshold;
+        match config.kind {
+            IndexKind::Hnsw => Index::Hnsw(Hnsw::build(metric, config.hnsw, ids, store, entries)),
+            IndexKind::Flat if promoted => Index::Hnsw(Hnsw::build(metric, config.hnsw, ids, store, entries)),
+            IndexKind::IvfPq if promoted => Index::IvfPq(IvfPq::train(metric, &ids, store, entries)),
+            IndexKind::Flat | IndexKind::IvfPq => {
+                let mut flat = Flat::new(metric);
+                for id in ids {
+                    flat.insert(id, entries);
+                }
+                Index::Flat(flat)
+            }
+        }
+    }
+
+    /// Number of points that can still be returned by a search
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Flat(flat) => flat.len(),
+            Index::Hnsw(hnsw) => hnsw.len(),
+            Index::IvfPq(ivf) => ivf.len(),
+        }
+    }
+
+    /// Removed points a rebuild would reclaim
+    pub fn tombstones(&self) -> usize {
+        match self {
+            Index::Hnsw(hnsw) => hnsw.tombstones(),
+            _ => 0,
+        }
+    }
+
+    /// The nodes of a graph stored before nodes had their own map, to be moved there
+    pub fn take_nodes(&mut self) -> BTreeMap<u32, Node> {
+        match self {
+            Index::Hnsw(hnsw) => hnsw.take_nodes(),
+            _ => BTreeMap::new(),
+        }
+    }
+
+    /// The ids of a flat index stored before ids had their own map, to be moved there
+    pub fn take_ids(&mut self) -> Vec<u32> {
+        match self {
+            Index::Flat(flat) => flat.take_ids(),
+            _ => vec![],
+        }
+    }
+
+    /// Whether search distances are only approximate and need rescoring on
+    /// `PointStore::vector`
+    pub fn is_approximate(&self, quantization: Quantization) -> bool {
+        matches!(self, Index::IvfPq(_)) || quantization != Quantization::None
+    }
+
+    /// Add a point, its vector must already be in `store`
+    pub fn insert<S: PointStore + ?Sized, N: NodeStore + ListStore + IdStore + ?Sized>(&mut self, id: u32, store: &S, entries: &mut N) {
+        match self {
+            Index::Flat(flat) => flat.insert(id, entries),
+            Index::Hnsw(hnsw) => hnsw.insert(id, store, entries),
+            Index::IvfPq(ivf) => ivf.insert(id, store, entries),
+        }
+    }
+
+    /// Take a point out, its vector must still be in `store`
+    pub fn remove<S: PointStore + ?Sized, N: NodeStore + ListStore + IdStore + ?Sized>(&mut self, id: u32, store: &S, entries: &mut N) {
+        match self {
+            Index::Flat(flat) => flat.remove(id, entries),
+            Index::Hnsw(hnsw) => hnsw.remove(id, entries),
+            Index::IvfPq(ivf) => ivf.remove(id, &store.vector(id), entries),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn metric(&self) -> Metric {
+        match self {
+            Index::Flat(flat) => flat.metric(),
+            Index::Hnsw(hnsw) => hnsw.metric(),
+            Index::IvfPq(ivf) => ivf.metric(),
+        }
+    }
+
+    /// `k` nearest points to `query`, closest first, see `Hnsw::search`.
+    /// A flat index is exact and ignores the budget.
+    pub fn search<S: PointStore + ?Sized, N: NodeStore + ListStore + IdStore + ?Sized>(
+        &self,
+        query: &Vector,
+        k: usize,
+        budget: &SearchBudget,
+        store: &S,
+        entries: &N,
+    ) -> Vec<(f32, u32)> {
+        self.search_filtered(query, k, budget, store, entries, &|_| true)
+    }
+
+    pub fn search_filtered<S: PointStore + ?Sized, N: NodeStore + ListStore + IdStore + ?Sized>(
+        &self,
+        query: &Vector,
+        k: usize,
+        budget: &SearchBudget,
+        store: &S,
+        entries: &N,
+        accept: &dyn Fn(u32) -> bool,
+    ) -> Vec<(f32, u32)> {
+        match self {
+            Index::Flat(flat) => flat.search(&store.query(query), k, store, entries, accept),
+            Index::Hnsw(hnsw) => hnsw.search_filtered(&store.query(query), k, budget.ef, store, entries, accept),
+            Index::IvfPq(ivf) => ivf.search(query, k, budget.nprobe, store, entries, accept),
+        }
+    }
+}
+
+/// A (distance, point id) pair ordered by distance
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(f32, u32);
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// A point of a graph, stored under the point's own key
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Node {
+    // One neighbour list per layer, the node lives on layers 0..neighbours.len()
+    neighbours: Vec<Vec<u32>>,
+    deleted: bool,
+}
+
+impl Node {
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+}
+
+impl Storable for Node {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        de::from_reader(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Hierarchical navigable small world graph that grows one point at a time.
+/// The graph only keeps its entry point, the nodes are in a `NodeStore`.
+///
+/// Removed points are only tombstoned: they keep routing searches but are never
+/// returned. `Hnsw::build` rebuilds a compact graph without them.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Hnsw {
+    // Graphs stored before metrics were configurable were all built for cosine collections
+    #[serde(default)]
+    metric: Metric,
+    // Graphs stored before these were configurable were built with the defaults
+    #[serde(default)]
+    params: HnswParams,
+    // Graphs stored before nodes had their own map kept them here, see `take_nodes`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    nodes: BTreeMap<u32, Node>,
+    // Nodes in the graph, tombstoned ones included
+    #[serde(default)]
+    count: usize,
+    entry_point: Option<u32>,
+    tombstones: usize,
+    rng: u64,
+}
+
+impl Default for Hnsw {
+    fn default() -> Self {
+        Self::new(Metric::default(), HnswParams::default())
+    }
+}
+
+impl Storable for Hnsw {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        de::from_reader(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Hnsw {
+    pub fn new(metric: Metric, params: HnswParams) -> Self {
+        Self {
+            metric,
+            params,
+            nodes: BTreeMap::new(),
+            count: 0,
+            entry_point: None,
+            tombstones: 0,
+            rng: params.seed,
+        }
+    }
+
+    /// Build a fresh graph over the given points, writing its nodes to `nodes`
+    pub fn build<S: PointStore + ?Sized, N: NodeStore + ?Sized>(
+        metric: Metric,
+        params: HnswParams,
+        ids: impl IntoIterator<Item = u32>,
+        store: &S,
+        nodes: &mut N,
+    ) -> Self {
+        let mut hnsw = Hnsw::new(metric, params);
+        for id in ids {
+            hnsw.insert(id, store, nodes);
+        }
+        hnsw
+    }
+
+    /// Number of points that can still be returned by a search
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.count - self.tombstones
+    }
+
+    #[cfg(test)]
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    pub fn tombstones(&self) -> usize {
+        self.tombstones
+    }
+
+    /// The nodes of a graph stored before nodes had their own map, to be moved there
+    pub fn take_nodes(&mut self) -> BTreeMap<u32, Node> {
+        self.count = self.count.max(self.nodes.len());
+        std::mem::take(&mut self.nodes)
+    }
+
+    /// Link a new point into the graph, its vector must already be in `store`
+    pub fn insert<S: PointStore + ?Sized, N: NodeStore + ?Sized>(&mut self, id: u32, store: &S, nodes: &mut N) {
+        if nodes.node(id).is_some() {
+            return;
+        }
+
+        let level = self.random_level();
+        let mut node = Node { neighbours: vec![vec![]; level + 1], deleted: false };
+        self.count += 1;
+
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => {
+                nodes.set_node(id, node);
+                self.entry_point = Some(id);
+                return;
+            }
+        };
+        let top_level = self.level(entry_point, nodes);
+
+        let query = store.point(id);
+        let distance = |other: u32| query.distance(self.metric, &store.point(other));
+        let mut entry = vec![Scored(distance(entry_point), entry_point)];
+
+        // Greedy descent through the layers above the new point
+        for layer in (level + 1..=top_level).rev() {
+            entry = self.search_layer(&entry, 1, layer, nodes, &distance, &|_| true);
+        }
+
+        for layer in (0..=level.min(top_level)).rev() {
+            let candidates = self.search_layer(&entry, self.params.ef_construction as usize, layer, nodes, &distance, &|_| true);
+            let max_neighbours = self.max_neighbours(layer);
+            let neighbours = select_neighbours(self.metric, &candidates, max_neighbours, store);
+
+            for &neighbour in &neighbours {
+                let mut other = nodes.node(neighbour).unwrap().into_owned();
+                other.neighbours[layer].push(id);
+                if other.neighbours[layer].len() > max_neighbours {
+                    other.neighbours[layer] = self.shrink(neighbour, &other.neighbours[layer], layer, store);
+                }
+                nodes.set_node(neighbour, other);
+            }
+            node.neighbours[layer] = neighbours;
+            entry = candidates;
+        }
+        nodes.set_node(id, node);
+
+        if level > top_level {
+            self.entry_point = Some(id);
+        }
+    }
+
+    /// Tombstone a point, it stays in the graph until the next rebuild
+    pub fn remove<N: NodeStore + ?Sized>(&mut self, id: u32, nodes: &mut N) {
+        let mut node = match nodes.node(id) {
+            Some(node) if !node.deleted => node.into_owned(),
+            _ => return,
+        };
+        node.deleted = true;
+        nodes.set_node(id, node);
+        self.tombstones += 1;
+    }
+
+    /// Approximate `k` nearest live points to `query`, closest first.
+    /// `query` must already be prepared for the metric, see `Metric::prepare`.
+    #[cfg(test)]
+    pub fn search<S: PointStore + ?Sized, N: NodeStore + ?Sized>(&self, query: &S::Point, k: usize, ef: usize, store: &S, nodes: &N) -> Vec<(f32, u32)> {
+        self.search_filtered(query, k, ef, store, nodes, &|_| true)
+    }
+
+    /// Like `search`, restricted to the points passing `accept`. The filter is
+    /// applied while traversing, every point still routes the search, so the
+    /// result is not cut short by non matching neighbours.
+    pub fn search_filtered<S: PointStore + ?Sized, N: NodeStore + ?Sized>(
+        &self,
+        query: &S::Point,
+        k: usize,
+        ef: usize,
+        store: &S,
+        nodes: &N,
+        accept: &dyn Fn(u32) -> bool,
+    ) -> Vec<(f32, u32)> {
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => return vec![],
+        };
+
+        let distance = |other: u32| query.distance(self.metric, &store.point(other));
+        let mut entry = vec![Scored(distance(entry_point), entry_point)];
+        for layer in (1..=self.level(entry_point, nodes)).rev() {
+            entry = self.search_layer(&entry, 1, layer, nodes, &distance, &|_| true);
+        }
+
+        // Nodes are only read for their tombstone while the graph has any
+        let is_live = |id: u32| accept(id) && (self.tombstones == 0 || nodes.node(id).is_some_and(|node| !node.deleted));
+        let mut found = self.search_layer(&entry, ef.max(k), 0, nodes, &distance, &is_live);
+        found.truncate(k);
+        found.into_iter().map(|Scored(distance, id)| (distance, id)).collect()
+    }
+
+    fn level<N: NodeStore + ?Sized>(&self, id: u32, nodes: &N) -> usize {
+        nodes.node(id).map_or(0, |node| node.neighbours.len() - 1)
+    }
+
+    /// Best-first search on one layer. Every reachable node is used for routing
+    /// but only the ones passing `accept` end up in the result, closest first.
+    /// `distance` measures a point from the query.
+    fn search_layer<N: NodeStore + ?Sized>(
+        &self,
+        entry: &[Scored],
+        ef: usize,
+        layer: usize,
+        nodes: &N,
+        distance: &dyn Fn(u32) -> f32,
+        accept: &dyn Fn(u32) -> bool,
+    ) -> Vec<Scored> {
+        let mut visited: HashSet<u32> = entry.iter().map(|s| s.1).collect();
+        let mut candidates: BinaryHeap<Reverse<Scored>> = entry.iter().map(|s| Reverse(*s)).collect();
+        let mut results: BinaryHeap<Scored> = entry.iter().filter(|s| accept(s.1)).copied().collect();
+        while results.len() > ef {
+            results.pop();
+        }
+
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            if results.len() >= ef && candidate.0 > results.peek().unwrap().0 {
+                break;
+            }
+
+            let node = match nodes.node(candidate.1) {
+                Some(node) => node,
+                None => continue,
+            };
+            let neighbours = match node.neighbours.get(layer) {
+                Some(neighbours) => neighbours,
+                None => continue,
+            };
+            for &neighbour in neighbours.iter() {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let distance = distance(neighbour);
+                if results.len() < ef || distance < results.peek().unwrap().0 {
+                    candidates.push(Reverse(Scored(distance, neighbour)));
+                    if accept(neighbour) {
+                        results.push(Scored(distance, neighbour));
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+
+        results.into_sorted_vec()
+    }
+
+    /// Trim an overflowing neighbour list of `id` back to its capacity
+    fn shrink<S: PointStore + ?Sized>(&self, id: u32, neighbours: &[u32], layer: usize, store: &S) -> Vec<u32> {
+        let point = store.point(id);
+        let mut candidates: Vec<Scored> = neighbours
+            .iter()
+            .map(|&neighbour| Scored(point.distance(self.metric, &store.point(neighbour)), neighbour))
+            .collect();
+        candidates.sort();
+
+        select_neighbours(self.metric, &candidates, self.max_neighbours(layer), store)
+    }
+
+    fn random_level(&mut self) -> usize {
+        // splitmix64, canisters have no thread rng and the graph must be reproducible
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        let uniform = ((z >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let ml = 1.0 / (self.params.m as f64).ln();
+        ((-uniform.ln() * ml).floor() as usize).min(MAX_LEVEL)
+    }
+
+    fn max_neighbours(&self, layer: usize) -> usize {
+        let m = self.params.m as usize;
+        if layer == 0 {
+            m * 2
+        } else {
+            m
+        }
+    }
+}
+
+/// Neighbour selection heuristic from the HNSW paper: prefer candidates that are
+/// closer to the base point than to any neighbour picked so far, then top up
+/// with the closest of the skipped ones.
+fn select_neighbours<S: PointStore + ?Sized>(metric: Metric, candidates: &[Scored], max_neighbours: usize, store: &S) -> Vec<u32> {
+    let mut selected: Vec<(u32, Cow<'_, S::Point>)> = Vec::with_capacity(max_neighbours);
+    let mut skipped: Vec<u32> = vec![];
+
+    for &Scored(distance, id) in candidates {
+        if selected.len() >= max_neighbours {
+            break;
+        }
+        let point = store.point(id);
+        if selected.iter().all(|(_, other)| point.distance(metric, other) > distance) {
+            selected.push((id, point));
+        } else {
+            skipped.push(id);
+        }
+    }
+
+    let mut neighbours: Vec<u32> = selected.into_iter().map(|(id, _)| id).collect();
+    for id in skipped {
+        if neighbours.len() >= max_neighbours {
+            break;
+        }
+        neighbours.push(id);
+    }
+    neighbours
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hnsw, HnswParams, Index, IndexConfig, IndexKind, Metric, Node, NodeStore, PointStore, SearchBudget, Vector, EF_SEARCH};
+    use crate::vdb::flat::IdStore;
+    use crate::vdb::ivf::{List, ListStore};
+    use ic_stable_structures::Storable;
+    use std::borrow::Cow;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    const METRICS: [Metric; 3] = [Metric::Cosine, Metric::DotProduct, Metric::L2];
+
+    // Graph nodes, lists and flat index ids kept apart, the way a collection keeps them
+    #[derive(Default)]
+    struct Entries {
+        nodes: BTreeMap<u32, Node>,
+        lists: BTreeMap<u32, List>,
+        ids: BTreeSet<u32>,
+    }
+
+    impl NodeStore for Entries {
+        fn node(&self, id: u32) -> Option<Cow<'_, Node>> {
+            self.nodes.node(id)
+        }
+
+        fn set_node(&mut self, id: u32, node: Node) {
+            self.nodes.set_node(id, node);
+        }
+    }
+
+    impl ListStore for Entries {
+        fn list(&self, list: u32) -> Option<Cow<'_, List>> {
+            self.lists.list(list)
+        }
+
+        fn set_list(&mut self, list: u32, entries: List) {
+            self.lists.set_list(list, entries);
+        }
+    }
+
+    impl IdStore for Entries {
+        fn ids(&self) -> Vec<u32> {
+            self.ids.ids()
+        }
+
+        fn insert_id(&mut self, id: u32) -> bool {
+            self.ids.insert_id(id)
+        }
+
+        fn remove_id(&mut self, id: u32) -> bool {
+            self.ids.remove_id(id)
+        }
+    }
+
+    fn random_points(count: u32, dimension: usize) -> BTreeMap<u32, Vector> {
+        let mut state: u64 = 42;
+        let mut next = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((state >> 33) as f32) / (1u64 << 31) as f32 - 0.5
+        };
+        (0..count)
+            .map(|id| (id, Vector::from((0..dimension).map(|_| next()).collect::<Vec<f32>>())))
+            .collect()
+    }
+
+    fn prepared(metric: Metric, points: &BTreeMap<u32, Vector>) -> BTreeMap<u32, Vector> {
+        points.iter().map(|(id, p)| (*id, metric.prepare(p.clone()))).collect()
+    }
+
+    fn brute_force(metric: Metric, points: &BTreeMap<u32, Vector>, query: &Vector, k: usize) -> Vec<u32> {
+        let mut all: Vec<(f32, u32)> = points.iter().map(|(id, p)| (metric.distance(query, p), *id)).collect();
+        all.sort_by(|a, b| a.0.total_cmp(&b.0));
+        all.into_iter().take(k).map(|(_, id)| id).collect()
+    }
+
+    #[test]
+    fn incremental_inserts_match_brute_force() {
+        for metric in METRICS {
+            let points = prepared(metric, &random_points(500, 8));
+            let mut hnsw = Hnsw::new(metric, HnswParams::default());
+            let mut nodes = BTreeMap::new();
+            for id in points.keys() {
+                hnsw.insert(*id, &points, &mut nodes);
+            }
+            assert_eq!(hnsw.len(), 500);
+
+            let queries = prepared(metric, &random_points(20, 8));
+            let mut hits = 0;
+            for query in queries.values() {
+                let expected = brute_force(metric, &points, query, 10);
+                let found: Vec<u32> = hnsw.search(query, 10, EF_SEARCH, &points, &nodes).into_iter().map(|(_, id)| id).collect();
+                hits += found.iter().filter(|id| expected.contains(id)).count();
+            }
+            // Recall@10 over 20 queries
+            assert!(hits as f32 / 200.0 > 0.95, "{:?} recall {}", metric, hits as f32 / 200.0);
+        }
+    }
+
+    #[test]
+    fn scores_follow_the_metric() {
+        let a = Vector::from(vec![3.0, 4.0]);
+        let b = Vector::from(vec![4.0, 3.0]);
+
+        // Cosine scores are the cosine similarity of the original vectors
+        let cosine = Metric::Cosine;
+        let score = cosine.score(cosine.distance(&cosine.prepare(a.clone()), &cosine.prepare(b.clone())));
+        assert!((score - 24.0 / 25.0).abs() < 1e-6);
+
+        assert_eq!(Metric::DotProduct.score(Metric::DotProduct.distance(&a, &b)), 24.0);
+        assert!((Metric::L2.score(Metric::L2.distance(&a, &b)) + 2f32.sqrt()).abs() < 1e-6);
+
+        // A closer point always scores higher
+        let c = Vector::from(vec![3.0, 4.5]);
+        for metric in METRICS {
+            let (a, b, c) = (metric.prepare(a.clone()), metric.prepare(b.clone()), metric.prepare(c.clone()));
+            let (near, far) = (metric.distance(&a, &c), metric.distance(&a, &b));
+            assert!(near < far);
+            assert!(metric.score(near) > metric.score(far));
+        }
+    }
+
+    #[test]
+    fn removed_points_are_not_returned() {
+        let points = random_points(100, 4);
+        let mut nodes = BTreeMap::new();
+        let mut hnsw = Hnsw::build(Metric::L2, HnswParams::default(), points.keys().copied(), &points, &mut nodes);
+
+        let query = points.point(7).into_owned();
+        assert_eq!(hnsw.search(&query, 1, EF_SEARCH, &points, &nodes)[0].1, 7);
+
+        hnsw.remove(7, &mut nodes);
+        assert_eq!(hnsw.tombstones(), 1);
+        assert_eq!(hnsw.len(), 99);
+        assert!(nodes[&7].is_deleted());
+        let found = hnsw.search(&query, 100, EF_SEARCH, &points, &nodes);
+        assert_eq!(found.len(), 99);
+        assert!(found.iter().all(|(_, id)| *id != 7));
+    }
+
+    #[test]
+    fn filtered_search_matches_brute_force() {
+        let points = random_points(500, 8);
+        let mut nodes = BTreeMap::new();
+        let hnsw = Hnsw::build(Metric::L2, HnswParams::default(), points.keys().copied(), &points, &mut nodes);
+        let allowed: BTreeMap<u32, Vector> = points.iter().filter(|(id, _)| *id % 10 == 0).map(|(id, p)| (*id, p.clone())).collect();
+
+        let queries = random_points(20, 8);
+        let mut hits = 0;
+        for query in queries.values() {
+            let expected = brute_force(Metric::L2, &allowed, query, 10);
+            let found = hnsw.search_filtered(query, 10, EF_SEARCH, &points, &nodes, &|id| id % 10 == 0);
+            assert_eq!(found.len(), 10);
+            assert!(found.iter().all(|(_, id)| id % 10 == 0));
+            hits += found.iter().filter(|(_, id)| expected.contains(id)).count();
+        }
+        assert!(hits as f32 / 200.0 > 0.95);
+    }
+
+    #[test]
+    fn empty_index_returns_nothing() {
+        let points = random_points(1, 4);
+        let hnsw = Hnsw::default();
+        assert!(hnsw.search(&points[&0], 5, EF_SEARCH, &points, &BTreeMap::new()).is_empty());
+    }
+
+    #[test]
+    fn flat_index_matches_brute_force() {
+        for metric in METRICS {
+            let points = prepared(metric, &random_points(300, 8));
+            let mut entries = Entries::default();
+            let index = Index::build(metric, &IndexConfig::default(), points.keys().copied().collect(), &points, &mut entries);
+            assert!(matches!(index, Index::Flat(_)));
+
+            for query in prepared(metric, &random_points(5, 8)).values() {
+                let found: Vec<u32> = index.search(query, 10, &SearchBudget::default(), &points, &entries).into_iter().map(|(_, id)| id).collect();
+                assert_eq!(found, brute_force(metric, &points, query, 10));
+            }
+        }
+    }
+
+    #[test]
+    fn promotes_past_the_threshold() {
+        let points = random_points(50, 4);
+        let config = IndexConfig { kind: IndexKind::Flat, promote_threshold: 40, ..Default::default() };
+        let mut entries = Entries::default();
+        let mut index = Index::new(Metric::L2, &config);
+        for id in 0..50 {
+            index.insert(id, &points, &mut entries);
+        }
+        // Inserts never promote, the flat index keeps growing
+        assert!(matches!(index, Index::Flat(_)));
+        assert_eq!(index.len(), 50);
+        assert_eq!(entries.ids.len(), 50);
+        assert!(entries.nodes.is_empty());
+
+        let index = Index::build(Metric::L2, &config, (0..40).collect(), &points, &mut entries);
+        assert!(matches!(index, Index::Flat(_)));
+        assert!(entries.nodes.is_empty());
+
+        let index = Index::build(Metric::L2, &config, (0..50).collect(), &points, &mut entries);
+        assert!(matches!(index, Index::Hnsw(_)));
+        assert_eq!(index.len(), 50);
+        assert_eq!(entries.nodes.len(), 50);
+        assert_eq!(index.search(&points[&42], 1, &SearchBudget::default(), &points, &entries)[0].1, 42);
+    }
+
+    #[test]
+    fn stored_graphs_decode_as_hnsw_indexes() {
+        let points = random_points(20, 4);
+        let mut nodes = BTreeMap::new();
+        let hnsw = Hnsw::build(Metric::L2, HnswParams::default(), points.keys().copied(), &points, &mut nodes);
+
+        let index = Index::from_bytes(hnsw.to_bytes());
+        assert!(matches!(index, Index::Hnsw(_)));
+        assert_eq!(index.len(), 20);
+
+        // Graphs stored with their nodes give them up to the node map
+        let legacy = Hnsw { nodes: nodes.clone(), count: 0, ..hnsw };
+        let mut index = Index::from_bytes(legacy.to_bytes());
+        assert_eq!(index.take_nodes(), nodes);
+        assert_eq!(index.len(), 20);
+        assert!(index.take_nodes().is_empty());
+        let entries = Entries { nodes, ..Default::default() };
+        assert_eq!(index.search(&points[&3], 1, &SearchBudget::default(), &points, &entries)[0].1, 3);
+
+        let index = Index::from_bytes(Index::new(Metric::DotProduct, &IndexConfig::default()).to_bytes());
+        assert!(matches!(index, Index::Flat(_)));
+        assert_eq!(index.metric(), Metric::DotProduct);
+    }
+
+    #[test]
+    fn promotes_to_the_configured_kind() {
+        let points = random_points(60, 8);
+        let config = IndexConfig { kind: IndexKind::IvfPq, promote_threshold: 50, ..Default::default() };
+        let mut entries = Entries::default();
+        let mut index = Index::build(Metric::L2, &config, (0..60).collect(), &points, &mut entries);
+        assert!(matches!(index, Index::IvfPq(_)));
+        assert_eq!(index.len(), 60);
+        assert!(entries.nodes.is_empty());
+        assert_eq!(entries.lists.values().map(|list| list.len()).sum::<usize>(), 60);
+        assert!(index.is_approximate(config.quantization));
+
+        // Every list is scanned with a large enough budget
+        let budget = SearchBudget { nprobe: 60, ..Default::default() };
+        assert_eq!(index.search(&points[&5], 60, &budget, &points, &entries).len(), 60);
+        index.remove(5, &points, &mut entries);
+        assert!(index.search(&points[&5], 60, &budget, &points, &entries).iter().all(|(_, id)| *id != 5));
+    }
+
+    #[test]
+    fn params_shape_the_graph() {
+        let points = random_points(200, 8);
+        let sparse = HnswParams { m: 4, ef_construction: 20, ..Default::default() };
+        let mut nodes: BTreeMap<u32, Node> = BTreeMap::new();
+        Hnsw::build(Metric::L2, sparse, points.keys().copied(), &points, &mut nodes);
+        assert!(nodes.values().all(|node| node.neighbours[0].len() <= 8));
+
+        // The seed alone decides the levels, so it decides the graph
+        let mut again = BTreeMap::new();
+        Hnsw::build(Metric::L2, sparse, points.keys().copied(), &points, &mut again);
+        assert_eq!(nodes, again);
+        let reseeded = HnswParams { seed: 7, ..sparse };
+        let mut other = BTreeMap::new();
+        Hnsw::build(Metric::L2, reseeded, points.keys().copied(), &points, &mut other);
+        assert_ne!(nodes, other);
+
+        assert!(IndexConfig { hnsw: HnswParams { m: 1, ..Default::default() }, ..Default::default() }.validate().is_err());
+        assert!(IndexConfig { hnsw: HnswParams { ef_search: 0, ..Default::default() }, ..Default::default() }.validate().is_err());
+        assert!(IndexConfig { hnsw: sparse, ..Default::default() }.validate().is_ok());
+    }
+}
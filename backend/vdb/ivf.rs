@@ -0,0 +1,584 @@
+use super::index::{Metric, PointStore, Vector};
+use ciborium::de;
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+/// Dimensions per PQ subvector, a 1536 dimension vector becomes 192 code bytes
+const SUBVECTOR_DIMENSION: usize = 8;
+/// Entries per PQ codebook, so every code byte indexes one
+const CODEBOOK_SIZE: usize = 256;
+/// Upper bound on the number of coarse centroids
+const MAX_LISTS: usize = 1024;
+/// Points k-means is trained on at most
+const TRAINING_SAMPLE: usize = 8192;
+/// Coarse centroids are trained on this many sample points each at least
+const SAMPLES_PER_LIST: u64 = 16;
+const KMEANS_ITERATIONS: usize = 10;
+/// Multiply-adds a training may spend on k-means, a fraction of an update
+/// call's instruction limit. Fewer lists and sample points keep it within.
+const TRAINING_BUDGET: u64 = 1 << 32;
+/// Multiply-adds spent filing points per training or insert, the points left
+/// over wait in the pending list for the next inserts
+const FILING_BUDGET: u64 = 1 << 29;
+/// Key of the list of points not filed yet, searches measure them exactly
+const PENDING: u32 = u32::MAX;
+const DEFAULT_SEED: u64 = 0x1f5e_ed5e_ed00_c0de;
+/// Lists scanned by a search unless the query asks for another number
+pub const DEFAULT_NPROBE: usize = 8;
+
+/// Inverted file index over product quantized residuals (IVFADC).
+///
+/// k-means splits the space into cells around coarse centroids and every point
+/// is filed in the list of its nearest centroid. Its residual to that centroid
+/// is cut into subvectors, each stored as the index of the closest entry of
+/// that subvector's codebook, one byte per subvector. A search only scans the
+/// `nprobe` lists closest to the query and reads distances from per list
+/// lookup tables. They are approximate, the best candidates should be rescored.
+///
+/// The index only keeps what it was trained to, the lists are in a `ListStore`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IvfPq {
+    metric: Metric,
+    dimension: usize,
+    // `dimension` floats per coarse centroid
+    #[serde(with = "le_f32")]
+    centroids: Vec<f32>,
+    codebooks: Vec<Codebook>,
+    // One list per centroid
+    lists: u32,
+    // Points filed or pending
+    count: usize,
+}
+
+/// Quantizer of the residual components `start..end`
+#[derive(Clone, Serialize, Deserialize)]
+struct Codebook {
+    start: usize,
+    end: usize,
+    // `end - start` floats per entry
+    #[serde(with = "le_f32")]
+    entries: Vec<f32>,
+}
+
+/// The points filed under one centroid, stored under the list's own key
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct List {
+    #[serde(with = "le_u32")]
+    ids: Vec<u32>,
+    // One byte per codebook for every id, none in the pending list
+    #[serde(with = "serde_bytes")]
+    codes: Vec<u8>,
+}
+
+impl List {
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+impl Storable for List {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        de::from_reader(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Where an inverted file keeps its lists, one entry per list, so that an
+/// insert only reads and writes the lists it files points in
+pub trait ListStore {
+    fn list(&self, list: u32) -> Option<Cow<'_, List>>;
+    fn set_list(&mut self, list: u32, entries: List);
+}
+
+impl ListStore for BTreeMap<u32, List> {
+    fn list(&self, list: u32) -> Option<Cow<'_, List>> {
+        self.get(&list).map(Cow::Borrowed)
+    }
+
+    fn set_list(&mut self, list: u32, entries: List) {
+        self.insert(list, entries);
+    }
+}
+
+// Number of lists and of sample points to train `count` points on, so that
+// k-means over the centroids and the codebooks stays within the budget
+fn training_size(count: usize, dimension: usize) -> (usize, usize) {
+    let cost = |lists: usize| ((lists + CODEBOOK_SIZE) * dimension * KMEANS_ITERATIONS) as u64;
+    let mut lists = ((count as f64).sqrt().round() as usize).clamp(1, MAX_LISTS);
+    while lists > 1 && lists as u64 * SAMPLES_PER_LIST * cost(lists) > TRAINING_BUDGET {
+        lists -= 1;
+    }
+    let sample = ((TRAINING_BUDGET / cost(lists)) as usize).clamp(1, TRAINING_SAMPLE).min(count);
+    (lists, sample)
+}
+
+impl IvfPq {
+    /// Train the centroids and codebooks on a sample of the points, then file
+    /// a batch of them. The others are filed by the next inserts. `lists`
+    /// must not hold any list yet.
+    pub fn train<S: PointStore + ?Sized, L: ListStore + ?Sized>(metric: Metric, ids: &[u32], store: &S, lists: &mut L) -> Self {
+        let dimension = ids.first().map(|id| store.vector(*id).dimension()).unwrap_or(0);
+        let mut index = IvfPq {
+            metric,
+            dimension,
+            centroids: vec![],
+            codebooks: vec![],
+            lists: 0,
+            count: 0,
+        };
+        if dimension == 0 {
+            return index;
+        }
+
+        let mut seed = DEFAULT_SEED;
+        let (list_count, sample_len) = training_size(ids.len(), dimension);
+        let sample: Vec<f32> = ids
+            .iter()
+            .step_by(ids.len().div_ceil(sample_len))
+            .flat_map(|id| store.vector(*id).to_vec())
+            .collect();
+        let sample_len = sample.len() / dimension;
+
+        let list_count = list_count.min(sample_len);
+        index.centroids = kmeans(&sample, dimension, list_count, &mut seed);
+        index.lists = list_count as u32;
+        index.count = ids.len();
+
+        // The codebooks quantize what is left once the centroid is subtracted
+        let mut residuals = sample;
+        for point in residuals.chunks_exact_mut(dimension) {
+            let centroid = index.centroid(nearest(&index.centroids, dimension, point));
+            for (value, center) in point.iter_mut().zip(centroid) {
+                *value -= center;
+            }
+        }
+
+        let subvectors = dimension.div_ceil(SUBVECTOR_DIMENSION);
+        for i in 0..subvectors {
+            let (start, end) = (i * dimension / subvectors, (i + 1) * dimension / subvectors);
+            let part: Vec<f32> = residuals.chunks_exact(dimension).flat_map(|point| point[start..end].to_vec()).collect();
+            let entries = kmeans(&part, end - start, CODEBOOK_SIZE.min(sample_len), &mut seed);
+            index.codebooks.push(Codebook { start, end, entries });
+        }
+
+        let (filed, pending) = ids.split_at(ids.len().min(index.batch()));
+        if !pending.is_empty() {
+            lists.set_list(PENDING, List { ids: pending.to_vec(), codes: vec![] });
+        }
+        index.file(filed.iter().map(|id| (*id, store.vector(*id))), lists);
+        index
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    #[cfg(test)]
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    fn centroid(&self, list: usize) -> &[f32] {
+        &self.centroids[list * self.dimension..(list + 1) * self.dimension]
+    }
+
+    // Points filed per call, within the filing budget
+    fn batch(&self) -> usize {
+        (FILING_BUDGET / ((self.lists as usize + CODEBOOK_SIZE) * self.dimension) as u64).max(1) as usize
+    }
+
+    /// File a point under its nearest centroid, along with a batch of the
+    /// pending points. Its vector must already be in `store`. The centroids
+    /// and codebooks stay as trained.
+    pub fn insert<S: PointStore + ?Sized, L: ListStore + ?Sized>(&mut self, id: u32, store: &S, lists: &mut L) {
+        let vector = store.vector(id);
+        if self.lists == 0 || vector.dimension() != self.dimension {
+            return;
+        }
+        self.count += 1;
+
+        let mut pending = vec![];
+        if let Some(list) = lists.list(PENDING).filter(|list| !list.ids.is_empty()) {
+            let mut list = list.into_owned();
+            pending = list.ids.split_off(list.ids.len().saturating_sub(self.batch()));
+            lists.set_list(PENDING, list);
+        }
+        let points = pending.into_iter().map(|id| (id, store.vector(id)));
+        self.file(points.chain([(id, vector)]), lists);
+    }
+
+    // File points under their nearest centroids, every list they go to is
+    // read and written once
+    fn file<'v, L: ListStore + ?Sized>(&self, points: impl Iterator<Item = (u32, Cow<'v, Vector>)>, lists: &mut L) {
+        let mut changed: BTreeMap<u32, List> = BTreeMap::new();
+        for (id, vector) in points {
+            let vector = vector.as_slice();
+            let list = nearest(&self.centroids, self.dimension, vector);
+            let residual: Vec<f32> = vector.iter().zip(self.centroid(list)).map(|(value, center)| value - center).collect();
+            let entries = changed
+                .entry(list as u32)
+                .or_insert_with(|| lists.list(list as u32).map(Cow::into_owned).unwrap_or_default());
+            entries.ids.push(id);
+            entries.codes.extend(
+                self.codebooks
+                    .iter()
+                    .map(|codebook| nearest(&codebook.entries, codebook.end - codebook.start, &residual[codebook.start..codebook.end]) as u8),
+            );
+        }
+        for (list, entries) in changed {
+            lists.set_list(list, entries);
+        }
+    }
+
+    /// Take a point out of the list its vector is filed in, or out of the pending ones
+    pub fn remove<L: ListStore + ?Sized>(&mut self, id: u32, vector: &Vector, lists: &mut L) {
+        if self.lists == 0 || vector.dimension() != self.dimension {
+            return;
+        }
+        let filed = nearest(&self.centroids, self.dimension, vector.as_slice()) as u32;
+        for (list, code_len) in [(filed, self.codebooks.len()), (PENDING, 0)] {
+            let Some(entries) = lists.list(list) else {
+                continue;
+            };
+            if let Some(position) = entries.ids.iter().position(|&other| other == id) {
+                // Move the last entry into the gap
+                let mut entries = entries.into_owned();
+                let last = entries.ids.len() - 1;
+                entries.ids.swap_remove(position);
+                entries.codes.copy_within(last * code_len..(last + 1) * code_len, position * code_len);
+                entries.codes.truncate(last * code_len);
+                lists.set_list(list, entries);
+                self.count -= 1;
+                return;
+            }
+        }
+    }
+
+    /// Approximate `k` nearest points passing `accept` among the `nprobe`
+    /// lists closest to `query` and the pending points, closest first
+    pub fn search<S: PointStore + ?Sized, L: ListStore + ?Sized>(
+        &self,
+        query: &Vector,
+        k: usize,
+        nprobe: usize,
+        store: &S,
+        lists: &L,
+        accept: &dyn Fn(u32) -> bool,
+    ) -> Vec<(f32, u32)> {
+        if self.lists == 0 || query.dimension() != self.dimension {
+            return vec![];
+        }
+        let vector = query;
+        let query = query.as_slice();
+
+        let mut cells: Vec<(f32, usize)> = self
+            .centroids
+            .chunks_exact(self.dimension)
+            .enumerate()
+            .map(|(list, centroid)| (squared_l2(query, centroid), list))
+            .collect();
+        cells.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        cells.truncate(nprobe.max(1));
+
+        // Inner products with the codebook entries are the same for every list,
+        // squared distances depend on the query's residual to the list's centroid.
+        // Unit vectors are ranked on the squared distance, it is 2 - 2 cos, and
+        // its quantization error shrinks for the close points that matter.
+        let inner = match self.metric {
+            Metric::DotProduct => self.table(query, dot),
+            _ => vec![],
+        };
+
+        let mut found = vec![];
+        for (_, list) in cells {
+            let centroid = self.centroid(list);
+            let distances;
+            let (base, table) = match self.metric {
+                Metric::DotProduct => (dot(query, centroid), &inner),
+                _ => {
+                    let residual: Vec<f32> = query.iter().zip(centroid).map(|(value, center)| value - center).collect();
+                    distances = self.table(&residual, squared_l2);
+                    (0.0, &distances)
+                }
+            };
+
+            let Some(entries) = lists.list(list as u32) else {
+                continue;
+            };
+            for (id, code) in entries.ids.iter().zip(entries.codes.chunks_exact(self.codebooks.len())) {
+                if !accept(*id) {
+                    continue;
+                }
+                let sum: f32 = base + code.iter().enumerate().map(|(i, entry)| table[i * CODEBOOK_SIZE + *entry as usize]).sum::<f32>();
+                let distance = match self.metric {
+                    // Both sides are unit length, see `Metric::prepare`
+                    Metric::Cosine => sum / 2.0,
+                    Metric::DotProduct => -sum,
+                    Metric::L2 => sum,
+                };
+                found.push((distance, *id));
+            }
+        }
+        if let Some(pending) = lists.list(PENDING) {
+            for id in pending.ids.iter().filter(|id| accept(**id)) {
+                found.push((self.metric.distance(vector, &store.vector(*id)), *id));
+            }
+        }
+        found.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        found.truncate(k);
+        found
+    }
+
+    // `f` of every codebook entry and the matching part of `vector`, at
+    // `codebook * CODEBOOK_SIZE + entry`
+    fn table(&self, vector: &[f32], f: fn(&[f32], &[f32]) -> f32) -> Vec<f32> {
+        let mut table = vec![0.0; self.codebooks.len() * CODEBOOK_SIZE];
+        for (i, codebook) in self.codebooks.iter().enumerate() {
+            let part = &vector[codebook.start..codebook.end];
+            for (j, entry) in codebook.entries.chunks_exact(part.len()).enumerate() {
+                table[i * CODEBOOK_SIZE + j] = f(part, entry);
+            }
+        }
+        table
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+// Position of the centroid closest to `point`
+fn nearest(centroids: &[f32], dimension: usize, point: &[f32]) -> usize {
+    let mut best = (f32::INFINITY, 0);
+    for (i, centroid) in centroids.chunks_exact(dimension).enumerate() {
+        let distance = squared_l2(point, centroid);
+        if distance < best.0 {
+            best = (distance, i);
+        }
+    }
+    best.1
+}
+
+/// Lloyd's k-means over `points`, `dimension` floats each, seeded with `k`
+/// distinct points. Returns the centroids the same way.
+fn kmeans(points: &[f32], dimension: usize, k: usize, seed: &mut u64) -> Vec<f32> {
+    let count = points.len() / dimension;
+    let k = k.min(count);
+
+    // Partial Fisher-Yates shuffle for the starting centroids
+    let mut order: Vec<usize> = (0..count).collect();
+    for i in 0..k {
+        let j = i + (next_random(seed) % (count - i) as u64) as usize;
+        order.swap(i, j);
+    }
+    let mut centroids: Vec<f32> = order[..k].iter().flat_map(|&i| points[i * dimension..(i + 1) * dimension].to_vec()).collect();
+
+    let mut assignment = vec![usize::MAX; count];
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut changed = false;
+        for (i, point) in points.chunks_exact(dimension).enumerate() {
+            let cell = nearest(&centroids, dimension, point);
+            changed |= assignment[i] != cell;
+            assignment[i] = cell;
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![0.0; k * dimension];
+        let mut sizes = vec![0usize; k];
+        for (point, &cell) in points.chunks_exact(dimension).zip(&assignment) {
+            sizes[cell] += 1;
+            for (sum, value) in sums[cell * dimension..(cell + 1) * dimension].iter_mut().zip(point) {
+                *sum += value;
+            }
+        }
+        // An empty cell keeps its previous centroid
+        for cell in (0..k).filter(|cell| sizes[*cell] > 0) {
+            for d in 0..dimension {
+                centroids[cell * dimension + d] = sums[cell * dimension + d] / sizes[cell] as f32;
+            }
+        }
+    }
+    centroids
+}
+
+// splitmix64, the index must come out the same every time it is trained
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Stored as raw little endian bytes, CBOR would tag every number
+mod le_f32 {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::ByteBuf;
+
+    pub fn serialize<S: Serializer>(values: &[f32], serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f32>, D::Error> {
+        let bytes = ByteBuf::deserialize(deserializer)?;
+        Ok(bytes.as_chunks::<4>().0.iter().map(|b| f32::from_le_bytes(*b)).collect())
+    }
+}
+
+mod le_u32 {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::ByteBuf;
+
+    pub fn serialize<S: Serializer>(values: &[u32], serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+        let bytes = ByteBuf::deserialize(deserializer)?;
+        Ok(bytes.as_chunks::<4>().0.iter().map(|b| u32::from_le_bytes(*b)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{training_size, IvfPq, List, CODEBOOK_SIZE, DEFAULT_NPROBE, KMEANS_ITERATIONS, PENDING, TRAINING_BUDGET};
+    use crate::vdb::index::{Metric, Vector};
+    use std::collections::BTreeMap;
+
+    // Points around a few well separated centers, like topics in a corpus
+    fn clustered_points(count: u32, dimension: usize) -> BTreeMap<u32, Vector> {
+        let mut state: u64 = 42;
+        let mut next = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((state >> 33) as f32) / (1u64 << 31) as f32 - 0.5
+        };
+        let centers: Vec<Vec<f32>> = (0..8).map(|_| (0..dimension).map(|_| next() * 4.0).collect()).collect();
+        (0..count)
+            .map(|id| {
+                let center = &centers[id as usize % centers.len()];
+                (id, Vector::from(center.iter().map(|c| c + next()).collect::<Vec<f32>>()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recall_after_rescoring() {
+        for metric in [Metric::Cosine, Metric::DotProduct, Metric::L2] {
+            let points: BTreeMap<u32, Vector> = clustered_points(2000, 16).into_iter().map(|(id, p)| (id, metric.prepare(p))).collect();
+            let ids: Vec<u32> = points.keys().copied().collect();
+            let mut lists = BTreeMap::new();
+            let index = IvfPq::train(metric, &ids, &points, &mut lists);
+            assert_eq!(index.len(), 2000);
+            assert_eq!(index.lists, 45);
+            assert_eq!(lists.len(), 45);
+
+            let mut hits = 0;
+            for query in clustered_points(20, 16).values() {
+                let query = metric.prepare(query.clone());
+                let mut expected: Vec<(f32, u32)> = points.iter().map(|(id, p)| (metric.distance(&query, p), *id)).collect();
+                expected.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let expected: Vec<u32> = expected.into_iter().take(10).map(|(_, id)| id).collect();
+
+                // Shortlist on the codes, then rank on the full vectors
+                let mut found: Vec<(f32, u32)> = index
+                    .search(&query, 100, DEFAULT_NPROBE, &points, &lists, &|_| true)
+                    .into_iter()
+                    .map(|(_, id)| (metric.distance(&query, &points[&id]), id))
+                    .collect();
+                found.sort_by(|a, b| a.0.total_cmp(&b.0));
+                hits += found.iter().take(10).filter(|(_, id)| expected.contains(id)).count();
+            }
+            let recall = hits as f32 / 200.0;
+            assert!(recall > 0.9, "{:?} recall {}", metric, recall);
+        }
+    }
+
+    #[test]
+    fn nprobe_trades_speed_for_recall() {
+        let points = clustered_points(1000, 8);
+        let ids: Vec<u32> = points.keys().copied().collect();
+        let mut lists = BTreeMap::new();
+        let index = IvfPq::train(Metric::L2, &ids, &points, &mut lists);
+
+        let query = &points[&3];
+        let one = index.search(query, 1000, 1, &points, &lists, &|_| true);
+        let all = index.search(query, 1000, index.lists as usize, &points, &lists, &|_| true);
+        assert!(one.len() < all.len());
+        assert_eq!(all.len(), 1000);
+        assert!(one.iter().any(|(_, id)| *id == 3));
+    }
+
+    #[test]
+    fn removed_and_filtered_points_are_not_returned() {
+        let points = clustered_points(300, 8);
+        let ids: Vec<u32> = points.keys().copied().collect();
+        let mut lists = BTreeMap::new();
+        let mut index = IvfPq::train(Metric::L2, &ids, &points, &mut lists);
+
+        index.remove(7, &points[&7], &mut lists);
+        index.remove(7, &points[&7], &mut lists);
+        assert_eq!(index.len(), 299);
+        assert_eq!(lists.values().map(List::len).sum::<usize>(), 299);
+        let found = index.search(&points[&7], 300, 100, &points, &lists, &|id| id % 2 == 1);
+        assert_eq!(found.len(), 149);
+        assert!(found.iter().all(|(_, id)| *id != 7 && id % 2 == 1));
+
+        // Serialized codes decode to the same index
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&index, &mut bytes).unwrap();
+        let decoded: IvfPq = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(decoded.search(&points[&9], 5, 4, &points, &lists, &|_| true), index.search(&points[&9], 5, 4, &points, &lists, &|_| true));
+    }
+
+    #[test]
+    fn pending_points_are_searched_and_filed_by_inserts() {
+        let points = clustered_points(300, 8);
+        let ids: Vec<u32> = points.keys().copied().collect();
+        let mut lists = BTreeMap::new();
+        let mut index = IvfPq::train(Metric::L2, &ids[..250], &points, &mut lists);
+
+        // Points left over from a training wait in the pending list
+        lists.insert(PENDING, List { ids: ids[250..299].to_vec(), codes: vec![] });
+        index.count += 49;
+        let found = index.search(&points[&260], 1, 1, &points, &lists, &|_| true);
+        assert_eq!(found, vec![(0.0, 260)]);
+        index.remove(270, &points[&270], &mut lists);
+        assert_eq!(lists[&PENDING].len(), 48);
+
+        index.insert(299, &points, &mut lists);
+        assert!(lists[&PENDING].ids.is_empty());
+        assert_eq!(index.len(), 299);
+        assert_eq!(lists.values().map(List::len).sum::<usize>(), 299);
+        let all = index.search(&points[&260], 300, index.lists as usize, &points, &lists, &|_| true);
+        assert_eq!(all.len(), 299);
+    }
+
+    #[test]
+    fn training_stays_within_its_budget() {
+        assert_eq!(training_size(2000, 16), (45, 2000));
+        for (count, dimension) in [(1000, 384), (100_000, 768), (1_000_000, 1536), (1_000_000, 3072)] {
+            let (lists, sample) = training_size(count, dimension);
+            assert!(lists > 1 && lists <= sample, "{} {}", lists, sample);
+            assert!(((lists + CODEBOOK_SIZE) * dimension * KMEANS_ITERATIONS * sample) as u64 <= TRAINING_BUDGET);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use super::collection::DocMetadata;
+use super::db::Database;
+use super::index::{IndexConfig, Metric, Vector};
+use super::memory::get_upgrades_memory;
+use crate::chunker::TextChunk;
+use ic_stable_structures::Memory as _;
+use serde::Deserialize;
+#[cfg(test)]
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Shape of the heap state that `pre_upgrade` used to serialize before the
+// collections moved to stable memory. The old index is skipped, it is rebuilt.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct LegacyDatabase {
+    collections: HashMap<String, LegacyCollection>,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct LegacyMetadata {
+    docs: HashMap<String, DocMetadata>,
+    created_at: u64,
+}
+
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct LegacyCollection {
+    dimension: usize,
+    metadata: LegacyMetadata,
+    keys: Vec<Vector>,
+    values: Vec<String>,
+}
+
+/// Import the state written by the heap based `pre_upgrade`, if any, into the
+/// stable collections. The blob is cleared afterwards so this only runs once.
+pub fn migrate_legacy_state(db: &mut Database) {
+    let memory = get_upgrades_memory();
+    if memory.size() == 0 {
+        return;
+    }
+
+    // Read the length of the state bytes.
+    let mut state_len_bytes = [0; 4];
+    memory.read(0, &mut state_len_bytes);
+    let state_len = u32::from_le_bytes(state_len_bytes) as usize;
+    if state_len == 0 {
+        return;
+    }
+
+    // Read the bytes
+    let mut state_bytes = vec![0; state_len];
+    memory.read(4, &mut state_bytes);
+
+    let state: LegacyDatabase = ciborium::de::from_reader(&*state_bytes).expect("failed to decode state");
+    for (name, collection) in state.collections {
+        import_collection(db, name, collection);
+    }
+
+    memory.write(0, &0u32.to_le_bytes());
+}
+
+fn import_collection(db: &mut Database, name: String, legacy: LegacyCollection) {
+    // The recorded dimension was never checked against the vectors, trust the vectors
+    let dimension = legacy.keys.first().map(|key| key.dimension()).unwrap_or(legacy.dimension);
+    if db.create_collection(name.clone(), dimension, Metric::Cosine, IndexConfig::default(), legacy.metadata.created_at).is_err() {
+        return;
+    }
+
+    // Old uploads stored one vector per document in upload order, but deleting
+    // a document only dropped its metadata. The vectors can only be matched
+    // back to their documents when nothing was ever deleted or replaced.
+    let mut docs: Vec<DocMetadata> = legacy.metadata.docs.into_values().collect();
+    docs.sort_by_key(|doc| doc.created_at);
+    let linked = docs.len() == legacy.keys.len() && docs.len() == legacy.values.len();
+    if !linked {
+        ic_cdk::println!("collection {}: vectors can't be matched to documents, re-upload needed", name);
+    }
+
+    for (i, doc) in docs.into_iter().enumerate() {
+        let (keys, values) = match linked {
+            true => {
+                let text = legacy.values[i].clone();
+                let chunk = TextChunk {
+                    start: 0,
+                    end: text.chars().count(),
+                    text,
+                    page: None,
+                    section: vec![],
+                    sheet: None,
+                    rows: None,
+                    language: None,
+                    symbol: None,
+                    lines: None,
+                    fields: vec![],
+                    date: None,
+                };
+                (vec![legacy.keys[i].to_vec()], vec![chunk])
+            }
+            false => (vec![], vec![]),
+        };
+        let _ = db.insert_into_collection(&name, keys, values, doc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate_legacy_state, LegacyCollection, LegacyDatabase, LegacyMetadata};
+    use crate::vdb::collection::DocMetadata;
+    use crate::vdb::db::Database;
+    use crate::vdb::index::Vector;
+    use crate::vdb::memory::get_upgrades_memory;
+    use ic_stable_structures::writer::Writer;
+    use ic_stable_structures::Memory as _;
+    use std::collections::HashMap;
+
+    fn doc(file_name: &str, created_at: u64) -> DocMetadata {
+        DocMetadata {
+            title: file_name.to_string(),
+            file_name: file_name.to_string(),
+            file_type: Some("text".to_string()),
+            file_size: 1024,
+            created_at,
+            author: None,
+            creation_date: None,
+        }
+    }
+
+    fn write_legacy_state(state: &LegacyDatabase) {
+        let mut state_bytes = vec![];
+        ciborium::ser::into_writer(state, &mut state_bytes).unwrap();
+        let len = state_bytes.len() as u32;
+        let mut memory = get_upgrades_memory();
+        let mut writer = Writer::new(&mut memory, 0);
+        writer.write(&len.to_le_bytes()).unwrap();
+        writer.write(&state_bytes).unwrap()
+    }
+
+    #[test]
+    fn migrate_heap_state_into_stable_collections() {
+        let docs = HashMap::from([
+            ("second.txt".to_string(), doc("second.txt", 2000)),
+            ("first.txt".to_string(), doc("first.txt", 1000)),
+        ]);
+        let collection = LegacyCollection {
+            dimension: 3,
+            metadata: LegacyMetadata { docs, created_at: 0 },
+            keys: vec![Vector::from(vec![1.0, 0.0, 0.0]), Vector::from(vec![0.0, 1.0, 0.0])],
+            values: vec!["first text".to_string(), "second text".to_string()],
+        };
+        write_legacy_state(&LegacyDatabase {
+            collections: HashMap::from([("user".to_string(), collection)]),
+        });
+
+        let mut db = Database::new();
+        migrate_legacy_state(&mut db);
+
+        assert_eq!(db.get_docs(&"user".to_string()).unwrap().len(), 2);
+        let results = db.query(&"user".to_string(), vec![0.0, 1.0, 0.0], 1, None, None).unwrap();
+        assert_eq!(results[0].text, "second text");
+        assert_eq!(results[0].metadata.file_name, "second.txt");
+
+        // The blob is cleared, running the migration again is a no-op
+        let mut len_bytes = [0; 4];
+        get_upgrades_memory().read(0, &mut len_bytes);
+        assert_eq!(u32::from_le_bytes(len_bytes), 0);
+        migrate_legacy_state(&mut db);
+        assert_eq!(db.get_docs(&"user".to_string()).unwrap().len(), 2);
+    }
+}
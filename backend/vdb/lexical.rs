@@ -0,0 +1,251 @@
+use super::memory::Memory;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+/// BM25 term frequency saturation
+const K1: f32 = 1.2;
+/// BM25 document length normalisation
+const B: f32 = 0.75;
+/// Reciprocal rank fusion constant, damps the weight of the very first ranks
+pub const RRF_K: f32 = 60.0;
+
+/// Corpus statistics BM25 needs, kept on the collection
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct LexicalStats {
+    // Indexed chunks and the sum of their token counts
+    pub chunks: u64,
+    pub tokens: u64,
+}
+
+/// One entry of the inverted index: `term` occurs in chunk `id` of `collection`.
+/// Keeping one entry per chunk makes inserts and removals cheap, a term's
+/// postings are read back with a range scan.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct TermKey {
+    pub collection: String,
+    pub term: String,
+    pub id: u32,
+}
+
+impl TermKey {
+    pub fn new(collection: &str, term: &str, id: u32) -> Self {
+        Self {
+            collection: collection.to_string(),
+            term: term.to_string(),
+            id,
+        }
+    }
+}
+
+impl Storable for TermKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&(self.collection.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.collection.as_bytes());
+        bytes.extend_from_slice(self.term.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let id = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let len = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+        let collection = String::from_utf8(bytes[6..6 + len].to_vec()).unwrap();
+        let term = String::from_utf8(bytes[6 + len..].to_vec()).unwrap();
+        TermKey { collection, term, id }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Occurrences of a term in a chunk, along with the chunk's length in tokens
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Posting {
+    pub tf: u32,
+    pub len: u32,
+}
+
+impl Storable for Posting {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.tf.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.len.to_le_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Posting {
+            tf: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            len: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 8,
+        is_fixed_size: true,
+    };
+}
+
+/// Lowercased alphanumeric words. Identifiers such as `AB-1234` or `v2.3.1` are
+/// kept whole as well as split into their parts, so both forms match.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    for word in text.split(|c: char| !(c.is_alphanumeric() || "-_./".contains(c))) {
+        let word = word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+        if word.is_empty() {
+            continue;
+        }
+
+        let parts: Vec<&str> = word.split(|c: char| !c.is_alphanumeric()).filter(|p| !p.is_empty()).collect();
+        if parts.len() > 1 {
+            tokens.push(word.clone());
+        }
+        tokens.extend(parts.into_iter().map(|p| p.to_string()));
+    }
+    tokens
+}
+
+fn term_counts(text: &str) -> (HashMap<String, u32>, u32) {
+    let tokens = tokenize(text);
+    let len = tokens.len() as u32;
+    let mut counts = HashMap::new();
+    for token in tokens {
+        *counts.entry(token).or_insert(0) += 1;
+    }
+    (counts, len)
+}
+
+/// Add a chunk's terms to the inverted index
+pub fn index_chunk(
+    postings: &mut StableBTreeMap<TermKey, Posting, Memory>,
+    stats: &mut LexicalStats,
+    collection: &str,
+    id: u32,
+    text: &str,
+) {
+    let (counts, len) = term_counts(text);
+    for (term, tf) in counts {
+        postings.insert(TermKey::new(collection, &term, id), Posting { tf, len });
+    }
+    stats.chunks += 1;
+    stats.tokens += len as u64;
+}
+
+/// Take a chunk's terms out of the inverted index, `text` must be the indexed one
+pub fn remove_chunk(
+    postings: &mut StableBTreeMap<TermKey, Posting, Memory>,
+    stats: &mut LexicalStats,
+    collection: &str,
+    id: u32,
+    text: &str,
+) {
+    let (counts, len) = term_counts(text);
+    for term in counts.keys() {
+        postings.remove(&TermKey::new(collection, term, id));
+    }
+    stats.chunks = stats.chunks.saturating_sub(1);
+    stats.tokens = stats.tokens.saturating_sub(len as u64);
+}
+
+/// Every chunk containing at least one query term, scored with BM25, best first
+pub fn search(
+    postings: &StableBTreeMap<TermKey, Posting, Memory>,
+    stats: &LexicalStats,
+    collection: &str,
+    query: &str,
+) -> Vec<(f32, u32)> {
+    if stats.chunks == 0 {
+        return vec![];
+    }
+    let n = stats.chunks as f32;
+    let avg_len = (stats.tokens as f32 / n).max(1.0);
+
+    let terms: HashSet<String> = tokenize(query).into_iter().collect();
+    let mut scores: HashMap<u32, f32> = HashMap::new();
+    for term in terms {
+        let range = TermKey::new(collection, &term, 0)..=TermKey::new(collection, &term, u32::MAX);
+        let hits: Vec<(TermKey, Posting)> = postings.range(range).collect();
+        if hits.is_empty() {
+            continue;
+        }
+
+        let df = hits.len() as f32;
+        let idf = (1.0 + (n - df + 0.5) / (df + 0.5)).ln();
+        for (key, posting) in hits {
+            let tf = posting.tf as f32;
+            let norm = K1 * (1.0 - B + B * posting.len as f32 / avg_len);
+            *scores.entry(key.id).or_insert(0.0) += idf * tf * (K1 + 1.0) / (tf + norm);
+        }
+    }
+
+    let mut ranked: Vec<(f32, u32)> = scores.into_iter().map(|(id, score)| (score, id)).collect();
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    ranked
+}
+
+/// Reciprocal rank fusion of two rankings, best first. `keyword_weight` in
+/// `[0, 1]` shifts the balance from the vector ranking to the keyword one.
+pub fn fuse(vector: &[u32], keyword: &[u32], keyword_weight: f32) -> Vec<(f32, u32)> {
+    let keyword_weight = keyword_weight.clamp(0.0, 1.0);
+    let mut scores: HashMap<u32, f32> = HashMap::new();
+    for (rank, id) in vector.iter().enumerate() {
+        *scores.entry(*id).or_insert(0.0) += (1.0 - keyword_weight) / (RRF_K + rank as f32 + 1.0);
+    }
+    for (rank, id) in keyword.iter().enumerate() {
+        *scores.entry(*id).or_insert(0.0) += keyword_weight / (RRF_K + rank as f32 + 1.0);
+    }
+
+    let mut fused: Vec<(f32, u32)> = scores.into_iter().filter(|(_, score)| *score > 0.0).map(|(id, score)| (score, id)).collect();
+    fused.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    fused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fuse, index_chunk, remove_chunk, search, tokenize, LexicalStats};
+    use crate::vdb::memory::get_lexical_memory;
+    use ic_stable_structures::StableBTreeMap;
+
+    #[test]
+    fn tokenize_keeps_identifiers() {
+        assert_eq!(tokenize("Hello, World!"), vec!["hello", "world"]);
+        assert_eq!(tokenize("Part AB-1234 (v2.3.1)."), vec!["part", "ab-1234", "ab", "1234", "v2.3.1", "v2", "3", "1"]);
+        assert!(tokenize(" -- ... ").is_empty());
+    }
+
+    #[test]
+    fn bm25_ranks_rare_terms_higher() {
+        let mut postings = StableBTreeMap::init(get_lexical_memory());
+        let mut stats = LexicalStats::default();
+        index_chunk(&mut postings, &mut stats, "c", 0, "the pump model XK-200 overheats");
+        index_chunk(&mut postings, &mut stats, "c", 1, "the pump is quiet and the pump is cheap");
+        index_chunk(&mut postings, &mut stats, "c", 2, "the valve is made of steel");
+        // Another collection never leaks into the results
+        index_chunk(&mut postings, &mut stats, "other", 0, "xk-200 xk-200 xk-200");
+
+        let results = search(&postings, &stats, "c", "pump XK-200");
+        assert_eq!(results.iter().map(|(_, id)| *id).collect::<Vec<_>>(), vec![0, 1]);
+        assert!(results[0].0 > results[1].0);
+
+        assert!(search(&postings, &stats, "c", "missing").is_empty());
+
+        remove_chunk(&mut postings, &mut stats, "c", 0, "the pump model XK-200 overheats");
+        let results = search(&postings, &stats, "c", "XK-200");
+        assert!(results.is_empty());
+        assert_eq!(stats.chunks, 3);
+    }
+
+    #[test]
+    fn fuse_weights_rankings() {
+        let vector = [1, 2, 3];
+        let keyword = [3, 4];
+
+        // Items ranked by both lists come first when the weights are balanced
+        let fused = fuse(&vector, &keyword, 0.5);
+        assert_eq!(fused[0].1, 3);
+
+        let ids = |fused: Vec<(f32, u32)>| fused.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+        assert_eq!(ids(fuse(&vector, &keyword, 0.0)), vec![1, 2, 3]);
+        assert_eq!(ids(fuse(&vector, &keyword, 1.0)), vec![3, 4]);
+    }
+}
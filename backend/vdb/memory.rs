@@ -10,6 +10,15 @@ const UPGRADES: MemoryId = MemoryId::new(0);
 // every additional stable structure.
 const STABLE_BTREE: MemoryId = MemoryId::new(1);
 const CONFIG_MEMORY: MemoryId = MemoryId::new(2);
+const INDEX_MEMORY: MemoryId = MemoryId::new(3);
+const VECTOR_MEMORY: MemoryId = MemoryId::new(4);
+const CHUNK_MEMORY: MemoryId = MemoryId::new(5);
+const LEXICAL_MEMORY: MemoryId = MemoryId::new(6);
+const CODE_MEMORY: MemoryId = MemoryId::new(7);
+const GRAPH_MEMORY: MemoryId = MemoryId::new(8);
+const LIST_MEMORY: MemoryId = MemoryId::new(9);
+const DOC_CHUNK_MEMORY: MemoryId = MemoryId::new(10);
+const FLAT_ID_MEMORY: MemoryId = MemoryId::new(11);
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -21,10 +30,11 @@ thread_local! {
     static CONFIG_MAP: RefCell<StableBTreeMap<String, String, Memory>> =
         RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with_borrow(|m| m.get(CONFIG_MEMORY))));
 
-    static OWNER: RefCell<Option<Principal>> = RefCell::new(None);
+    static OWNER: RefCell<Option<Principal>> = const { RefCell::new(None) };
 
 }
 
+#[allow(dead_code)]
 pub fn set_owner(owner: Principal) {
     OWNER.with(|owner_ref| {
         *owner_ref.borrow_mut() = Some(owner);
@@ -53,4 +63,40 @@ pub fn get_upgrades_memory() -> Memory {
 
 pub fn get_stable_btree_memory() -> Memory {
     MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_BTREE))
-}
\ No newline at end of file
+}
+
+pub fn get_index_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(INDEX_MEMORY))
+}
+
+pub fn get_vector_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(VECTOR_MEMORY))
+}
+
+pub fn get_chunk_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CHUNK_MEMORY))
+}
+
+pub fn get_lexical_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(LEXICAL_MEMORY))
+}
+
+pub fn get_code_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CODE_MEMORY))
+}
+
+pub fn get_graph_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(GRAPH_MEMORY))
+}
+
+pub fn get_list_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(LIST_MEMORY))
+}
+
+pub fn get_doc_chunk_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(DOC_CHUNK_MEMORY))
+}
+
+pub fn get_flat_id_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(FLAT_ID_MEMORY))
+}
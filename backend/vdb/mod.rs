@@ -1,5 +1,10 @@
 pub mod collection;
 pub mod db;
 pub mod error;
+pub mod flat;
 pub mod index;
-pub mod memory;
\ No newline at end of file
+pub mod ivf;
+pub mod legacy;
+pub mod lexical;
+pub mod memory;
+pub mod quantization;
\ No newline at end of file
@@ -0,0 +1,248 @@
+use super::index::{Metric, Point, Vector};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// How a collection stores the vectors its index searches, chosen per
+/// collection. Quantized collections still keep the full precision vectors in
+/// stable memory, the candidates found on the codes are rescored with them.
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq, Eq)]
+pub enum Quantization {
+    /// Plain `f32` components
+    #[default]
+    None,
+    /// One signed byte per component plus a scale per vector, 4x smaller
+    Int8,
+    /// One sign bit per component plus the vector's norm, 32x smaller
+    Binary,
+}
+
+impl Quantization {
+    /// Bytes taken by the code of a `dimension` long vector
+    pub fn code_len(&self, dimension: usize) -> usize {
+        match self {
+            Quantization::None => dimension * 4,
+            Quantization::Int8 => 4 + dimension,
+            Quantization::Binary => 4 + dimension.div_ceil(8),
+        }
+    }
+
+    pub fn encode(&self, vector: &[f32]) -> Vec<u8> {
+        match self {
+            Quantization::None => vector.iter().flat_map(|value| value.to_le_bytes()).collect(),
+            Quantization::Int8 => {
+                // Symmetric range, the largest component maps to +-127
+                let max = vector.iter().fold(0.0f32, |max, value| max.max(value.abs()));
+                let scale = max / 127.0;
+                let mut code = scale.to_le_bytes().to_vec();
+                code.extend(vector.iter().map(|value| match scale {
+                    0.0 => 0,
+                    _ => (value / scale).round().clamp(-127.0, 127.0) as i8 as u8,
+                }));
+                code
+            }
+            Quantization::Binary => {
+                let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+                let mut code = norm.to_le_bytes().to_vec();
+                code.resize(self.code_len(vector.len()), 0);
+                for (i, value) in vector.iter().enumerate() {
+                    if *value > 0.0 {
+                        code[4 + i / 8] |= 1 << (i % 8);
+                    }
+                }
+                code
+            }
+        }
+    }
+
+    /// A stored code in the form distances are computed on
+    pub fn code(&self, bytes: Vec<u8>, dimension: usize) -> Code {
+        match self {
+            Quantization::None => Code::Float(Vector::from(self.decode(&bytes, dimension))),
+            Quantization::Int8 => Code::Int8 { scale: f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]), bytes },
+            Quantization::Binary => Code::Binary {
+                norm: f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                dimension,
+                bytes,
+            },
+        }
+    }
+
+    /// Approximate components of a code, written to `out` which sets the dimension
+    pub fn decode_into(&self, code: &[u8], out: &mut [f32]) {
+        match self {
+            Quantization::None => {
+                for (value, bytes) in out.iter_mut().zip(code.as_chunks::<4>().0) {
+                    *value = f32::from_le_bytes(*bytes);
+                }
+            }
+            Quantization::Int8 => {
+                let scale = f32::from_le_bytes([code[0], code[1], code[2], code[3]]);
+                for (value, byte) in out.iter_mut().zip(&code[4..]) {
+                    *value = *byte as i8 as f32 * scale;
+                }
+            }
+            Quantization::Binary => {
+                // Every component gets the same magnitude, which keeps the norm
+                let norm = f32::from_le_bytes([code[0], code[1], code[2], code[3]]);
+                let magnitude = norm / (out.len() as f32).sqrt();
+                for (i, value) in out.iter_mut().enumerate() {
+                    *value = match code[4 + i / 8] & (1 << (i % 8)) {
+                        0 => -magnitude,
+                        _ => magnitude,
+                    };
+                }
+            }
+        }
+    }
+
+    pub fn decode(&self, code: &[u8], dimension: usize) -> Vec<f32> {
+        let mut out = vec![0.0; dimension];
+        self.decode_into(code, &mut out);
+        out
+    }
+}
+
+/// A point as an index compares it. Distances between codes, or between a
+/// code and a full precision query, are computed on the code bytes directly.
+#[derive(Clone)]
+pub enum Code {
+    /// Full precision, for collections that aren't quantized and for queries
+    Float(Vector),
+    /// Signed bytes after the scale, see `Quantization::encode`
+    Int8 { scale: f32, bytes: Vec<u8> },
+    /// Sign bits after the norm, every component has the same magnitude
+    Binary { norm: f32, dimension: usize, bytes: Vec<u8> },
+}
+
+impl Code {
+    fn dot(&self, other: &Code) -> f32 {
+        match (self, other) {
+            (Code::Float(query), code) | (code, Code::Float(query)) => code.dot_floats(query.as_slice()),
+            (Code::Int8 { scale: a, bytes: x }, Code::Int8 { scale: b, bytes: y }) => {
+                let sum: i32 = x[4..].iter().zip(&y[4..]).map(|(x, y)| *x as i8 as i32 * *y as i8 as i32).sum();
+                a * b * sum as f32
+            }
+            // Agreeing signs add the product of the magnitudes, the others take it away
+            (Code::Binary { norm: a, dimension, bytes: x }, Code::Binary { norm: b, bytes: y, .. }) => {
+                let differing: u32 = x[4..].iter().zip(&y[4..]).map(|(x, y)| (x ^ y).count_ones()).sum();
+                a * b * (*dimension as f32 - 2.0 * differing as f32) / *dimension as f32
+            }
+            // Codes of different forms never meet in an index
+            (a, b) => a.to_vector().dot(&b.to_vector()),
+        }
+    }
+
+    fn dot_floats(&self, query: &[f32]) -> f32 {
+        match self {
+            Code::Float(vector) => vector.as_slice().iter().zip(query).map(|(x, y)| x * y).sum(),
+            Code::Int8 { scale, bytes } => scale * bytes[4..].iter().zip(query).map(|(byte, value)| *byte as i8 as f32 * value).sum::<f32>(),
+            Code::Binary { norm, dimension, bytes } => {
+                let sum: f32 = query
+                    .iter()
+                    .enumerate()
+                    .map(|(i, value)| match bytes[4 + i / 8] & (1 << (i % 8)) {
+                        0 => -value,
+                        _ => *value,
+                    })
+                    .sum();
+                norm / (*dimension as f32).sqrt() * sum
+            }
+        }
+    }
+
+    fn squared_norm(&self) -> f32 {
+        match self {
+            Code::Float(vector) => vector.dot(vector),
+            Code::Int8 { scale, bytes } => scale * scale * bytes[4..].iter().map(|byte| (*byte as i8 as i32).pow(2)).sum::<i32>() as f32,
+            Code::Binary { norm, .. } => norm * norm,
+        }
+    }
+
+    fn to_vector(&self) -> Vector {
+        match self {
+            Code::Float(vector) => vector.clone(),
+            Code::Int8 { bytes, .. } => Vector::from(Quantization::Int8.decode(bytes, bytes.len() - 4)),
+            Code::Binary { dimension, bytes, .. } => Vector::from(Quantization::Binary.decode(bytes, *dimension)),
+        }
+    }
+}
+
+impl Point for Code {
+    fn distance(&self, metric: Metric, other: &Self) -> f32 {
+        if let (Code::Float(a), Code::Float(b)) = (self, other) {
+            return metric.distance(a, b);
+        }
+        let dot = self.dot(other);
+        match metric {
+            Metric::Cosine => 1.0 - dot,
+            Metric::DotProduct => -dot,
+            Metric::L2 => (self.squared_norm() + other.squared_norm() - 2.0 * dot).max(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Code, Quantization};
+    use crate::vdb::index::{Metric, Point, Vector};
+
+    #[test]
+    fn codes_roundtrip_approximately() {
+        let vector = vec![0.5, -1.0, 0.25, 0.0, 0.75, -0.1, 0.3, -0.6, 0.9];
+
+        assert_eq!(Quantization::None.decode(&Quantization::None.encode(&vector), 9), vector);
+
+        let code = Quantization::Int8.encode(&vector);
+        assert_eq!(code.len(), Quantization::Int8.code_len(9));
+        let decoded = Quantization::Int8.decode(&code, 9);
+        assert!(vector.iter().zip(&decoded).all(|(a, b)| (a - b).abs() <= 0.5 / 127.0 + 1e-6));
+
+        // Signs survive and the norm is kept
+        let code = Quantization::Binary.encode(&vector);
+        assert_eq!(code.len(), 6);
+        let decoded = Quantization::Binary.decode(&code, 9);
+        assert!(vector.iter().zip(&decoded).all(|(a, b)| (*a > 0.0) == (*b > 0.0)));
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!((norm(&vector) - norm(&decoded)).abs() < 1e-5);
+
+        // Encoding a decoded vector gives back the same code
+        assert_eq!(Quantization::Int8.encode(&Quantization::Int8.decode(&Quantization::Int8.encode(&vector), 9)), Quantization::Int8.encode(&vector));
+        assert_eq!(Quantization::Binary.encode(&decoded), code);
+    }
+
+    #[test]
+    fn codes_are_smaller() {
+        // A text-embedding-3-small vector
+        let full = Quantization::None.code_len(1536);
+        assert_eq!(full, 6144);
+        assert!(full as f32 / Quantization::Int8.code_len(1536) as f32 > 3.9);
+        assert!(full as f32 / Quantization::Binary.code_len(1536) as f32 > 31.0);
+    }
+
+    #[test]
+    fn zero_vectors_encode() {
+        for quantization in [Quantization::Int8, Quantization::Binary] {
+            let decoded = quantization.decode(&quantization.encode(&[0.0; 4]), 4);
+            assert_eq!(decoded.iter().map(|x| x.abs()).sum::<f32>(), 0.0);
+        }
+    }
+
+    #[test]
+    fn codes_are_compared_without_decoding() {
+        let a = vec![0.5, -1.0, 0.25, 0.0, 0.75, -0.1, 0.3, -0.6, 0.9];
+        let b = vec![0.4, -0.8, 0.5, 0.1, 0.7, 0.2, -0.3, -0.5, 1.0];
+        for quantization in [Quantization::None, Quantization::Int8, Quantization::Binary] {
+            let code = |v: &[f32]| quantization.code(quantization.encode(v), 9);
+            let decoded = |v: &[f32]| Vector::from(quantization.decode(&quantization.encode(v), 9));
+            for metric in [Metric::Cosine, Metric::DotProduct, Metric::L2] {
+                // The same distances as on the decoded vectors, between codes
+                // and between a full precision query and a code
+                let expected = metric.distance(&decoded(&a), &decoded(&b));
+                assert!((code(&a).distance(metric, &code(&b)) - expected).abs() < 1e-4, "{:?} {:?}", quantization, metric);
+                let expected = metric.distance(&Vector::from(a.clone()), &decoded(&b));
+                let query = Code::Float(Vector::from(a.clone()));
+                assert!((query.distance(metric, &code(&b)) - expected).abs() < 1e-4, "{:?} {:?}", quantization, metric);
+            }
+        }
+    }
+}